@@ -0,0 +1,26 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Captures build information for the /meta/version endpoint
+fn main() {
+    // Builds from a source tarball (or without git installed) just report "unknown"
+    let git_commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map_or_else(|| "unknown".to_string(), |commit| commit.trim().to_string());
+
+    let build_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0);
+
+    println!("cargo:rustc-env=V9_GIT_COMMIT={}", git_commit);
+    println!("cargo:rustc-env=V9_BUILD_TIMESTAMP={}", build_timestamp);
+
+    // Rebuild when the checked out commit changes
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
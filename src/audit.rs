@@ -0,0 +1,60 @@
+use std::convert::TryInto;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+use crate::error::WorkerError;
+use crate::model::{AuditEvent, AuditOperation, AuditResult, ComponentId};
+
+lazy_static! {
+    // Only opened if V9_AUDIT_LOG is set, otherwise nothing is audited
+    static ref AUDIT_LOG: Option<Mutex<File>> = env::var_os("V9_AUDIT_LOG").map(|path| {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap_or_else(|e| panic!("Could not open V9_AUDIT_LOG ({:?}): {}", path, e));
+        Mutex::new(file)
+    });
+}
+
+// Opens the audit log at startup, so a bad V9_AUDIT_LOG panics right away instead of on the first activation
+pub fn init() {
+    lazy_static::initialize(&AUDIT_LOG);
+}
+
+// Appends one JSON line to the audit log, `id` is `None` for requests that couldn't be parsed
+// A failed write is only logged, the operation itself has already happened
+pub fn record(operation: AuditOperation, id: Option<&ComponentId>, result: AuditResult) {
+    if let Some(audit_log) = &*AUDIT_LOG {
+        let event = AuditEvent {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| {
+                    since_epoch.as_millis().try_into().unwrap_or(u64::MAX)
+                }),
+            operation,
+            user: id.map(|id| id.path.user.clone()),
+            repo: id.map(|id| id.path.repo.clone()),
+            hash: id.map(|id| id.hash.clone()),
+            result,
+        };
+
+        if let Err(e) = append(&mut audit_log.lock(), &event) {
+            warn!("Could not write {:?} to the audit log, err {}", event, e);
+        }
+    }
+}
+
+// The line is written in one go and synced, so it survives the worker (or the machine) crashing right after
+fn append(file: &mut File, event: &AuditEvent) -> Result<(), WorkerError> {
+    let mut line = serde_json::to_string(event)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    file.sync_data()?;
+    Ok(())
+}
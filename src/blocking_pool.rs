@@ -0,0 +1,101 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::task::{spawn_blocking, JoinHandle};
+
+use crate::model::BlockingPoolMetrics;
+
+// tokio doesn't report on its blocking pool, so we count our own blocking tasks as they go through it
+static QUEUED_TASKS: AtomicU64 = AtomicU64::new(0);
+static RUNNING_TASKS: AtomicU64 = AtomicU64::new(0);
+
+// Like `spawn_blocking`, but the task shows up in `blocking_pool_metrics`
+pub fn spawn_tracked<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    QUEUED_TASKS.fetch_add(1, Ordering::SeqCst);
+    spawn_blocking(move || {
+        QUEUED_TASKS.fetch_sub(1, Ordering::SeqCst);
+        let _running = RunningTask::start();
+        f()
+    })
+}
+
+// Decrements the running count even if the task panics
+struct RunningTask;
+
+impl RunningTask {
+    fn start() -> Self {
+        RUNNING_TASKS.fetch_add(1, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for RunningTask {
+    fn drop(&mut self) {
+        RUNNING_TASKS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn blocking_pool_metrics() -> BlockingPoolMetrics {
+    BlockingPoolMetrics {
+        running_tasks: RUNNING_TASKS.load(Ordering::SeqCst),
+        queued_tasks: QUEUED_TASKS.load(Ordering::SeqCst),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use tokio::runtime::Builder;
+
+    use super::*;
+
+    fn wait_for_running(running_tasks: u64) {
+        for _ in 0..500 {
+            if blocking_pool_metrics().running_tasks == running_tasks {
+                return;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        panic!(
+            "Expected {} running tasks, got {:?}",
+            running_tasks,
+            blocking_pool_metrics()
+        );
+    }
+
+    // The counters are global, so this is the only test that spawns tracked tasks
+    #[test]
+    fn tasks_are_counted_until_they_finish_or_panic() {
+        // A single blocking thread, so the second task has to wait for the first
+        let mut runtime = Builder::new().basic_scheduler().max_threads(1).build().unwrap();
+
+        runtime.block_on(async {
+            let (release_first, first_released) = channel::<()>();
+            let (release_second, second_released) = channel::<()>();
+            let first = spawn_tracked(move || first_released.recv().unwrap());
+            let second = spawn_tracked(move || {
+                second_released.recv().unwrap();
+                panic!("the task failed");
+            });
+
+            wait_for_running(1);
+            assert_eq!(blocking_pool_metrics().queued_tasks, 1);
+
+            release_first.send(()).unwrap();
+            first.await.unwrap();
+            wait_for_running(1);
+            assert_eq!(blocking_pool_metrics().queued_tasks, 0);
+
+            release_second.send(()).unwrap();
+            assert!(second.await.is_err());
+            let metrics = blocking_pool_metrics();
+            assert_eq!((metrics.running_tasks, metrics.queued_tasks), (0, 0));
+        });
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::error::{WorkerError, WorkerErrorKind};
+
+// Caps how many calls to a component can be in flight at once, counting both the running call and the ones
+// waiting for the component's lock. Unlike rate limiting this is about load right now, so a slow component
+// sheds excess calls quickly instead of building up a queue.
+#[derive(Debug)]
+pub struct CallLimiter {
+    // `None` means unlimited
+    max_concurrent: Option<u32>,
+    in_flight: AtomicU32,
+}
+
+impl CallLimiter {
+    pub fn new(max_concurrent: Option<u32>) -> Self {
+        Self {
+            max_concurrent,
+            in_flight: AtomicU32::new(0),
+        }
+    }
+
+    // Fails with `ComponentBusy` if the component is already at its limit
+    pub fn try_enter(&self) -> Result<CallSlot<'_>, WorkerError> {
+        let previous = self.in_flight.fetch_add(1, Ordering::SeqCst);
+        if self.max_concurrent.map_or(false, |max| previous >= max) {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return Err(WorkerErrorKind::ComponentBusy.into());
+        }
+
+        Ok(CallSlot { limiter: self })
+    }
+}
+
+// Holds one of the component's call slots until it's dropped
+#[derive(Debug)]
+pub struct CallSlot<'a> {
+    limiter: &'a CallLimiter,
+}
+
+impl Drop for CallSlot<'_> {
+    fn drop(&mut self) {
+        self.limiter.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_over_the_limit_are_shed_until_a_slot_frees_up() {
+        let limiter = CallLimiter::new(Some(2));
+        let first = limiter.try_enter().unwrap();
+        let _second = limiter.try_enter().unwrap();
+
+        let e = limiter.try_enter().unwrap_err();
+        assert!(matches!(e.kind(), WorkerErrorKind::ComponentBusy), "{:?}", e);
+        // A shed call doesn't keep a slot
+        assert_eq!(limiter.in_flight.load(Ordering::SeqCst), 2);
+
+        drop(first);
+        assert!(limiter.try_enter().is_ok());
+    }
+
+    #[test]
+    fn no_limit_never_sheds() {
+        let limiter = CallLimiter::new(None);
+        let slots: Vec<CallSlot<'_>> = (0..100).map(|_| limiter.try_enter().unwrap()).collect();
+        assert_eq!(limiter.in_flight.load(Ordering::SeqCst), 100);
+
+        drop(slots);
+        assert_eq!(limiter.in_flight.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,175 @@
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::env_utils::env_or;
+use crate::model::BreakerState;
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_FAILURE_WINDOW_SECS: u64 = 60;
+const DEFAULT_COOLDOWN_SECS: u64 = 30;
+
+lazy_static! {
+    // A threshold of 0 turns the breaker off
+    static ref FAILURE_THRESHOLD: u32 = env_or("V9_BREAKER_FAILURE_THRESHOLD", DEFAULT_FAILURE_THRESHOLD);
+    static ref FAILURE_WINDOW: Duration =
+        Duration::from_secs(env_or("V9_BREAKER_WINDOW_SECS", DEFAULT_FAILURE_WINDOW_SECS));
+    static ref COOLDOWN: Duration = Duration::from_secs(env_or("V9_BREAKER_COOLDOWN_SECS", DEFAULT_COOLDOWN_SECS));
+}
+
+// Stops sending calls to a component that keeps failing, so a broken process isn't hammered (and callers
+// fail fast). After enough consecutive failures the breaker opens, and calls are rejected until the cooldown
+// is over. Then a single call is let through as a probe: if it works the breaker closes again, otherwise it
+// goes straight back to open.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: State,
+    consecutive_failures: u32,
+    first_failure: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen,
+}
+
+impl CircuitBreaker {
+    pub fn new() -> Self {
+        Self {
+            state: State::Closed,
+            consecutive_failures: 0,
+            first_failure: None,
+        }
+    }
+
+    // Returns `Err` with how long is left on the cooldown if the call should be rejected, otherwise whether the
+    // call is the probe
+    // NOTE: Calls to a component are serialized, so every allowed call is followed by `record`
+    pub fn try_pass(&mut self) -> Result<bool, Duration> {
+        if let State::Open { until } = self.state {
+            let now = Instant::now();
+            if now < until {
+                return Err(until - now);
+            }
+            self.state = State::HalfOpen;
+        }
+
+        Ok(matches!(self.state, State::HalfOpen))
+    }
+
+    // Failures are calls that errored, or got a server error response from the component
+    pub fn record(&mut self, succeeded: bool) {
+        if *FAILURE_THRESHOLD == 0 {
+            return;
+        }
+
+        if succeeded {
+            if let State::HalfOpen = self.state {
+                info!("Probe call succeeded, closing circuit breaker");
+            }
+            self.state = State::Closed;
+            self.consecutive_failures = 0;
+            self.first_failure = None;
+            return;
+        }
+
+        // Failures spread out over a long time don't count as a streak
+        let now = Instant::now();
+        let streak_expired = self
+            .first_failure
+            .map_or(true, |first| now.duration_since(first) > *FAILURE_WINDOW);
+        if streak_expired {
+            self.consecutive_failures = 0;
+            self.first_failure = Some(now);
+        }
+        self.consecutive_failures += 1;
+
+        let should_open = match self.state {
+            State::HalfOpen => true,
+            State::Closed => self.consecutive_failures >= *FAILURE_THRESHOLD,
+            State::Open { .. } => false,
+        };
+        if should_open {
+            warn!(
+                "Opening circuit breaker after {} consecutive failures, for {:?}",
+                self.consecutive_failures, *COOLDOWN
+            );
+            self.state = State::Open {
+                until: now + *COOLDOWN,
+            };
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match self.state {
+            State::Closed => BreakerState::Closed,
+            State::Open { until } if Instant::now() < until => BreakerState::Open,
+            // The cooldown is over, so the next call will be a probe
+            State::Open { .. } | State::HalfOpen => BreakerState::HalfOpen,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fail(breaker: &mut CircuitBreaker, times: u32) {
+        for _ in 0..times {
+            assert!(breaker.try_pass().is_ok());
+            breaker.record(false);
+        }
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let mut breaker = CircuitBreaker::new();
+        fail(&mut breaker, *FAILURE_THRESHOLD - 1);
+        // A success resets the streak
+        breaker.record(true);
+        fail(&mut breaker, *FAILURE_THRESHOLD - 1);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        fail(&mut breaker, 1);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        let cooldown_left = breaker.try_pass().unwrap_err();
+        assert!(cooldown_left <= *COOLDOWN);
+    }
+
+    #[test]
+    fn probes_once_the_cooldown_is_over() {
+        let mut breaker = CircuitBreaker::new();
+        fail(&mut breaker, *FAILURE_THRESHOLD);
+        breaker.state = State::Open {
+            until: Instant::now(),
+        };
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        // A failed probe opens the breaker again right away
+        assert_eq!(breaker.try_pass(), Ok(true));
+        breaker.record(false);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // And a successful one closes it
+        breaker.state = State::Open {
+            until: Instant::now(),
+        };
+        assert_eq!(breaker.try_pass(), Ok(true));
+        breaker.record(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.try_pass(), Ok(false));
+    }
+
+    #[test]
+    fn failures_spread_out_dont_open_the_breaker() {
+        let mut breaker = CircuitBreaker::new();
+        fail(&mut breaker, *FAILURE_THRESHOLD - 1);
+        breaker.first_failure = Instant::now().checked_sub(*FAILURE_WINDOW + Duration::from_secs(1));
+
+        fail(&mut breaker, 1);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.consecutive_failures, 1);
+    }
+}
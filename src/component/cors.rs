@@ -0,0 +1,62 @@
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, VARY,
+};
+use hyper::{Body, Response, StatusCode};
+
+use crate::error::WorkerError;
+use crate::model::CorsConfig;
+
+// Preflight requests are answered by the worker itself, they never reach the component
+pub fn preflight_response(
+    cors: &CorsConfig,
+    origin: Option<&str>,
+) -> Result<Response<Body>, WorkerError> {
+    let mut resp = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap();
+
+    // If the origin isn't allowed we still answer, but without any of the allow headers
+    // (The browser will then refuse to make the actual request)
+    if add_allow_origin(cors, origin, &mut resp)? {
+        let headers = resp.headers_mut();
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_str(&cors.methods.join(", "))?,
+        );
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            HeaderValue::from_str(&cors.headers.join(", "))?,
+        );
+    }
+
+    Ok(resp)
+}
+
+// Returns whether the origin was allowed (and thus whether the header was added)
+pub fn add_allow_origin(
+    cors: &CorsConfig,
+    origin: Option<&str>,
+    resp: &mut Response<Body>,
+) -> Result<bool, WorkerError> {
+    let allowed_origin = if cors.origins.iter().any(|o| o == "*") {
+        Some("*")
+    } else {
+        origin.filter(|origin| cors.origins.iter().any(|o| o == origin))
+    };
+
+    let headers = resp.headers_mut();
+    // The response depends on the origin, so caches need to know to key on it
+    headers.insert(VARY, HeaderValue::from_static("Origin"));
+
+    if let Some(allowed_origin) = allowed_origin {
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_str(allowed_origin)?,
+        );
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
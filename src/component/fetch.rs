@@ -0,0 +1,139 @@
+// Components can be activated from an http(s) URL instead of a local path, in which case the worker downloads the
+// artifact itself (this needs the "fetch" feature). The component's hash must be the SHA-256 of the artifact, which
+// the download is checked against like any other component file.
+
+#[cfg(feature = "fetch")]
+use std::fs::File;
+#[cfg(feature = "fetch")]
+use std::io::Write;
+#[cfg(feature = "fetch")]
+use std::thread;
+#[cfg(feature = "fetch")]
+use std::time::Duration;
+
+#[cfg(feature = "fetch")]
+use hyper::body::HttpBody;
+#[cfg(feature = "fetch")]
+use hyper::{Client, Uri};
+#[cfg(feature = "fetch")]
+use hyper_rustls::HttpsConnector;
+#[cfg(feature = "fetch")]
+use lazy_static::lazy_static;
+use tempfile::TempPath;
+#[cfg(feature = "fetch")]
+use tokio::runtime::Builder as RuntimeBuilder;
+#[cfg(feature = "fetch")]
+use tokio::time::timeout;
+
+#[cfg(feature = "fetch")]
+use crate::env_utils::env_or;
+use crate::error::{WorkerError, WorkerErrorKind};
+use crate::fs_utils::is_sha256;
+#[cfg(feature = "fetch")]
+use crate::fs_utils::named_temp_file;
+use crate::model::{ActivateRequest, ExecutionMethod};
+
+// Covers the whole download, however steadily the bytes are coming in
+#[cfg(feature = "fetch")]
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[cfg(feature = "fetch")]
+lazy_static! {
+    static ref MAX_ARTIFACT_BYTES: u64 = env_or("V9_MAX_ARTIFACT_BYTES", 1024 * 1024 * 1024);
+}
+
+pub fn is_remote(executable_file: &str) -> bool {
+    executable_file.starts_with("http://") || executable_file.starts_with("https://")
+}
+
+// Checks that a remote artifact could be used, without downloading it
+pub fn check_remote(ar: &ActivateRequest) -> Result<(), WorkerError> {
+    let problem = |problem: &str| {
+        Err(WorkerErrorKind::ArtifactDownload(ar.executable_file.clone(), problem.to_string()).into())
+    };
+
+    if cfg!(not(feature = "fetch")) {
+        return problem("this worker was built without the \"fetch\" feature");
+    }
+    if ar.execution_method == ExecutionMethod::ContainerizedScript {
+        return problem("containerized scripts are directories, so they can't be downloaded");
+    }
+    if !is_sha256(&ar.id.hash) {
+        return problem("the component's hash must be the artifact's SHA-256, in hex");
+    }
+
+    Ok(())
+}
+
+// If the artifact is remote, downloads it and points `executable_file` at the download
+// The download is deleted when the returned path is dropped, so it has to be kept for as long as the component
+pub fn fetch_remote(ar: &mut ActivateRequest) -> Result<Option<TempPath>, WorkerError> {
+    if !is_remote(&ar.executable_file) {
+        return Ok(None);
+    }
+    check_remote(ar)?;
+
+    let artifact = download(&ar.executable_file)?;
+    ar.executable_file = artifact.to_string_lossy().into_owned();
+    Ok(Some(artifact))
+}
+
+#[cfg(not(feature = "fetch"))]
+fn download(_url: &str) -> Result<TempPath, WorkerError> {
+    unreachable!("`check_remote` rejects every remote artifact without the \"fetch\" feature")
+}
+
+// Activations happen both before the server's runtime exists (when restoring them at startup) and on its blocking
+// threads (where starting another runtime panics), so downloads get a thread and runtime of their own
+#[cfg(feature = "fetch")]
+fn download(url: &str) -> Result<TempPath, WorkerError> {
+    let problem =
+        |problem: String| WorkerError::from(WorkerErrorKind::ArtifactDownload(url.to_string(), problem));
+
+    let uri: Uri = url.parse().map_err(|e| problem(format!("invalid URL ({})", e)))?;
+    let temp_file = named_temp_file()?;
+    let mut file = temp_file.reopen()?;
+
+    let downloader = thread::spawn(move || -> Result<Result<(), String>, WorkerError> {
+        let mut runtime = RuntimeBuilder::new().basic_scheduler().enable_all().build()?;
+        runtime.block_on(async {
+            timeout(DOWNLOAD_TIMEOUT, download_to(uri, &mut file))
+                .await
+                .map_err(|_| WorkerErrorKind::OperationTimedOut("artifact download"))?
+        })
+    });
+    downloader
+        .join()
+        .map_err(|_| WorkerErrorKind::Panic("artifact download thread panicked".to_string()))??
+        .map_err(problem)?;
+
+    debug!("Downloaded component artifact {} to {:?}", url, temp_file.path());
+    Ok(temp_file.into_temp_path())
+}
+
+// Returns what was wrong with the response, if anything
+#[cfg(feature = "fetch")]
+async fn download_to(uri: Uri, file: &mut File) -> Result<Result<(), String>, WorkerError> {
+    let client = Client::builder().build::<_, hyper::Body>(HttpsConnector::new());
+    let resp = client.get(uri).await?;
+    if !resp.status().is_success() {
+        return Ok(Err(format!("the server answered with {}", resp.status())));
+    }
+
+    let mut body = resp.into_body();
+    let mut downloaded_bytes = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        downloaded_bytes += chunk.len() as u64;
+        if downloaded_bytes > *MAX_ARTIFACT_BYTES {
+            return Ok(Err(format!(
+                "it's larger than the {} byte limit",
+                *MAX_ARTIFACT_BYTES
+            )));
+        }
+
+        file.write_all(&chunk)?;
+    }
+
+    Ok(Ok(()))
+}
@@ -1,41 +1,135 @@
+use std::convert::TryInto;
+use std::ffi::OsString;
 use std::fmt::Debug;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
 use std::time::{Duration, Instant};
 
+use lazy_static::lazy_static;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{sysconf, Pid, SysconfVar};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
 use subprocess::Popen;
+use tempfile::TempPath;
 
+use crate::component::fetch;
 use crate::component::logs::{LogPolicy, LogTracker};
-use crate::docker::idle_container_creator::{get_idle_container, CODE_FOLDER};
-use crate::docker::{load_docker_image, V9Container};
+use crate::docker::idle_container_creator::{get_container, idle_container_config, warm_idle_pool};
+use crate::docker::{
+    build_docker_image, check_image_platform, check_linux_containers, docker_image_exists,
+    kill_container, load_docker_image, release_docker_image, retain_docker_image, validate_image_tag,
+    ContainerOptions, V9Container,
+};
+use crate::env_utils::env_or;
 use crate::error::{WorkerError, WorkerErrorKind};
-use crate::fs_utils::canonicalize;
-use crate::model::{ActivateRequest, ExecutionMethod};
+use crate::events;
+use crate::fs_utils::{
+    absolute_path, canonicalize, check_component_file, check_component_hash, find_in_path,
+    hash_contents, hash_field, ExpectedFileType,
+};
+use crate::model::{
+    ActivateRequest, ComponentId, ComponentPath, ExecutionMethod, LifecycleEventKind, PipeFraming,
+    ResourceUsage,
+};
 use crate::named_pipe::NamedPipe;
+use crate::settings;
+use crate::telemetry;
 
-// Shutdown an unused component after 10 minutes
-const EXPIRY_DURATION: Duration = Duration::from_secs(60 * 10);
+lazy_static! {
+    // How long a component gets to answer a ping (see `IsolatedProcessWrapper::ping`)
+    pub static ref PING_TIMEOUT: Duration = Duration::from_millis(env_or("V9_PING_TIMEOUT_MS", 1000));
+}
+
+// Booting can fail transiently (e.g. the docker daemon being momentarily busy), so we retry a few times
+// Delays double after each failure, so with these values we wait 100ms, 200ms, then give up
+const BOOT_ATTEMPTS: u32 = 3;
+const BOOT_RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+
+// How much of a crashed component's output is included in the error
+const OUTPUT_TAIL_LINES: usize = 20;
+
+// Containerized scripts are started by running this file (from the component's directory) in the container
+const START_SCRIPT: &str = "start.sh";
+// A start script that fails outright (e.g. a typo in the command) usually exits within this long, and catching
+// that at boot gives a much clearer error than the pipes never being opened
+const START_SCRIPT_GRACE_PERIOD: Duration = Duration::from_millis(50);
+
+// Linux reports cpu times in /proc in clock ticks, which are 100 Hz on every platform we care about
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+// Fallback in case we can't query the real page size
+const DEFAULT_PAGE_SIZE: u64 = 4096;
+
+// What `IsolatedProcessWrapper::ping` found
+#[derive(Debug)]
+pub enum Liveness {
+    NotRunning,
+    // The process is running, but wasn't asked to answer
+    Running,
+    // Carries the process's answer
+    Answered(String),
+}
 
 #[derive(Debug)]
 pub struct IsolatedProcessWrapper {
-    isolation_controller: Box<dyn ProcessIsolationController>,
+    id: ComponentId,
+    // NOTE: Fields are dropped in declaration order, and the process must be shut down before the
+    // controller cleans up after itself (e.g. removing the docker image the process runs in)
     process_handle: Option<Box<dyn IsolatedProcessHandle>>,
+    isolation_controller: Box<dyn ProcessIsolationController>,
+
+    // Only held so a downloaded artifact is deleted once the controller is done with it
+    _downloaded_artifact: Option<TempPath>,
+
+    kill_switch: Arc<KillSwitch>,
+    // Only set for components activated with `control_pipe`
+    control_channel: Option<Arc<ControlChannel>>,
+    pipe_framing: PipeFraming,
 
     last_accessed: Instant,
 }
 
 impl IsolatedProcessWrapper {
-    pub fn new(ar: ActivateRequest) -> Result<Self, WorkerError> {
+    pub fn new(mut ar: ActivateRequest) -> Result<Self, WorkerError> {
+        let pipe_framing = ar.pipe_framing;
+        let control_pipe = ar.control_pipe;
+        check_control_pipe(&ar)?;
+        check_working_dir(&ar)?;
+        check_runtime(&ar)?;
+        check_base_image(&ar)?;
+        let downloaded_artifact = fetch::fetch_remote(&mut ar)?;
+        check_component_hash(&ar.executable_file, &ar.id.hash)?;
+
         // We do not validate whether "ar.executable_file" is a valid path here
-        // It's better for each isolation controller to deal with it individually, since they need
-        // to account for the edge case (it becoming invalid) anyway
+        // It's better for each isolation controller to deal with it individually, since each expects
+        // something different (and they need to account for the edge case of it becoming invalid anyway)
         let isolation_controller: Box<dyn ProcessIsolationController> = match ar.execution_method {
-            ExecutionMethod::ContainerizedScript => {
-                Box::new(ContainerizedScriptController::new(ar.executable_file)?)
-            }
-            ExecutionMethod::DockerArchive => {
-                Box::new(DockerArchiveController::new(&ar.executable_file)?)
-            }
-            ExecutionMethod::PythonUnsafe => Box::new(PythonUnsafeController::new(ar.executable_file)?),
+            ExecutionMethod::ContainerizedScript => Box::new(ContainerizedScriptController::new(
+                ar.executable_file.clone(),
+                ar.base_image.clone(),
+                ar.working_dir.clone(),
+                ContainerOptions::from_activate_request(&ar)?,
+            )?),
+            ExecutionMethod::DockerArchive => Box::new(DockerArchiveController::new(
+                &ar.executable_file,
+                ContainerOptions::from_activate_request(&ar)?,
+            )?),
+            ExecutionMethod::DockerBuild => Box::new(DockerBuildController::new(
+                ar.executable_file.clone(),
+                &ar.id.path,
+                ContainerOptions::from_activate_request(&ar)?,
+            )?),
+            ExecutionMethod::NodeUnsafe => Box::new(NodeUnsafeController::new(
+                &ar.executable_file,
+                ar.working_dir.clone(),
+            )?),
+            ExecutionMethod::PythonUnsafe => Box::new(PythonUnsafeController::new(
+                &ar.executable_file,
+                ar.working_dir.clone(),
+            )?),
         };
 
         // If we want to start the process automatically, we can use this code. But it makes testing cold starts hard
@@ -46,71 +140,524 @@ impl IsolatedProcessWrapper {
         // }
 
         Ok(Self {
-            isolation_controller,
+            id: ar.id.clone(),
             process_handle: None,
+            isolation_controller,
+            _downloaded_artifact: downloaded_artifact,
+
+            kill_switch: Arc::new(KillSwitch::default()),
+            control_channel: if control_pipe {
+                Some(Arc::new(ControlChannel::new(pipe_framing)))
+            } else {
+                None
+            },
+            pipe_framing,
 
             last_accessed: Instant::now(),
         })
     }
 
+    // Runs the checks `new` would, without loading, building, or starting anything
+    // Checks that need the image itself (like a docker archive's platform) can only happen on a real activation
+    pub fn validate(ar: &ActivateRequest) -> Result<(), WorkerError> {
+        check_control_pipe(ar)?;
+        check_working_dir(ar)?;
+        check_runtime(ar)?;
+        check_base_image(ar)?;
+
+        // Remote artifacts are only downloaded by a real activation
+        if fetch::is_remote(&ar.executable_file) {
+            return fetch::check_remote(ar);
+        }
+        check_component_hash(&ar.executable_file, &ar.id.hash)?;
+
+        match ar.execution_method {
+            ExecutionMethod::ContainerizedScript => ContainerizedScriptController::validate(
+                &ar.executable_file,
+                &ContainerOptions::from_activate_request(ar)?,
+            ),
+            ExecutionMethod::DockerArchive => {
+                ContainerOptions::from_activate_request(ar)?;
+                DockerArchiveController::validate(&ar.executable_file)
+            }
+            ExecutionMethod::DockerBuild => {
+                ContainerOptions::from_activate_request(ar)?;
+                DockerBuildController::validate(&ar.executable_file)
+            }
+            ExecutionMethod::NodeUnsafe => {
+                NodeUnsafeController::validate(&ar.executable_file, ar.working_dir.as_deref())
+            }
+            ExecutionMethod::PythonUnsafe => {
+                PythonUnsafeController::validate(&ar.executable_file, ar.working_dir.as_deref())
+            }
+        }
+    }
+
+    // The kill switch can be triggered without access to this wrapper (see `KillSwitch`)
+    pub fn kill_switch(&self) -> Arc<KillSwitch> {
+        self.kill_switch.clone()
+    }
+
+    // Control messages don't go through this wrapper either (see `ControlChannel`)
+    pub fn control_channel(&self) -> Option<Arc<ControlChannel>> {
+        self.control_channel.clone()
+    }
+
+    // Also returns whether the process had to be booted for this query (i.e. whether it was a cold start)
     pub fn query_process(
         &mut self,
         req: &str,
         log_tracker: &mut LogTracker,
-    ) -> Result<String, WorkerError> {
+    ) -> Result<(String, bool), WorkerError> {
         self.last_accessed = Instant::now();
 
-        if self.process_handle.is_none() {
-            let log_policy = log_tracker.create_associated_policy()?;
-            self.process_handle = Some(self.isolation_controller.boot_process(log_policy)?)
-        }
+        let cold = self.ensure_booted(log_tracker)?;
 
         // This is a safe unwrap, since we just ensured we have a booted proccess
         let handle = self.process_handle.as_mut().unwrap();
 
-        let resp = handle.query_process(req);
+        let resp = handle
+            .query_process(req, self.pipe_framing, None)
+            .map_err(|e| e.with_output_tail(|| self.output_tail(log_tracker)));
         trace!("attempted to query some process and got {:?}", resp);
 
         // If querying the process fails, then we need to restart it
-        if resp.is_err() {
-            self.process_handle = None;
+        if let Err(e) = &resp {
+            self.stop_failed_process(e);
+        }
+
+        resp.map(|resp| (resp, cold))
+    }
+
+    // Checks the process is responsive, without booting it. With a `req` (see `ActivateRequest::ping`) it has to
+    // answer within PING_TIMEOUT, otherwise it only has to still be running.
+    // Unlike calls, pings don't count as accesses, so they don't keep a component from expiring
+    pub fn ping(
+        &mut self,
+        req: Option<&str>,
+        log_tracker: &mut LogTracker,
+    ) -> Result<Liveness, WorkerError> {
+        if self.process_handle.is_none() {
+            return Ok(Liveness::NotRunning);
+        }
+
+        // This is a safe unwrap, since we just checked there is a process
+        let handle = self.process_handle.as_mut().unwrap();
+
+        let resp = match req {
+            Some(req) => handle
+                .query_process(req, self.pipe_framing, Some(*PING_TIMEOUT))
+                .map(Liveness::Answered),
+            None => handle.check_alive().map(|()| Liveness::Running),
+        }
+        .map_err(|e| e.with_output_tail(|| self.output_tail(log_tracker)));
+
+        // Same as with `query_process`, a process that didn't answer is in an unknown state
+        if let Err(e) = &resp {
+            self.stop_failed_process(e);
+        }
+
+        resp
+    }
+
+    // Reads another message from the process, after `query_process` already got the first
+    pub fn read_next_message(&mut self, log_tracker: &mut LogTracker) -> Result<String, WorkerError> {
+        self.last_accessed = Instant::now();
+
+        let handle = self
+            .process_handle
+            .as_mut()
+            .ok_or(WorkerErrorKind::PipeDisconnected)?;
+
+        let resp = handle
+            .read_next(self.pipe_framing)
+            .map_err(|e| e.with_output_tail(|| self.output_tail(log_tracker)));
+
+        // Same as with `query_process`, a failed read leaves the process in an unknown state
+        if let Err(e) = &resp {
+            self.stop_failed_process(e);
         }
 
         resp
     }
 
+    // Boots the process ahead of time, so the first real request doesn't pay for the cold start
+    // Returns whether the process actually needed to be booted
+    pub fn warm(&mut self, log_tracker: &mut LogTracker) -> Result<bool, WorkerError> {
+        self.last_accessed = Instant::now();
+
+        self.ensure_booted(log_tracker)
+    }
+
+    // Returns whether the process actually needed to be booted
+    fn ensure_booted(&mut self, log_tracker: &mut LogTracker) -> Result<bool, WorkerError> {
+        if self.process_handle.is_some() {
+            return Ok(false);
+        }
+
+        let log_policy = log_tracker.create_associated_policy()?;
+        let (handle, control_pipe) =
+            telemetry::in_span("boot process", || self.boot_process_with_retries(&log_policy))
+                .map_err(|e| e.with_output_tail(|| self.output_tail(log_tracker)))?;
+        self.kill_switch.arm(handle.terminator());
+        if let Some(control_channel) = &self.control_channel {
+            control_channel.connect(control_pipe);
+        }
+        self.process_handle = Some(handle);
+        events::emit(LifecycleEventKind::ProcessBooted, &self.id, None);
+
+        Ok(true)
+    }
+
+    fn stop_failed_process(&mut self, e: &WorkerError) {
+        // Asking docker costs a call, so it's only worth it if someone is listening
+        let oom_killed = events::enabled()
+            && self
+                .process_handle
+                .as_ref()
+                .map_or(false, |handle| handle.oom_killed());
+        let event = if oom_killed {
+            LifecycleEventKind::ContainerOomKilled
+        } else {
+            LifecycleEventKind::ProcessCrashed
+        };
+        events::emit(event, &self.id, Some(e.to_string()));
+
+        self.stop_process();
+    }
+
+    fn stop_process(&mut self) {
+        self.kill_switch.disarm();
+        if let Some(control_channel) = &self.control_channel {
+            control_channel.disconnect();
+        }
+        self.process_handle = None;
+    }
+
+    fn boot_process_with_retries(
+        &self,
+        log_policy: &Arc<LogPolicy>,
+    ) -> Result<(Box<dyn IsolatedProcessHandle>, Option<NamedPipe>), WorkerError> {
+        let mut delay = BOOT_RETRY_BASE_DELAY;
+        let mut attempt = 1;
+
+        loop {
+            // Each attempt gets a fresh control pipe, a process that failed to boot may have left the last one open
+            let control_pipe = self
+                .control_channel
+                .as_ref()
+                .map(|_| NamedPipe::new())
+                .transpose()?;
+            match self
+                .isolation_controller
+                .boot_process(log_policy.clone(), control_pipe.as_ref())
+            {
+                Ok(handle) => return Ok((handle, control_pipe)),
+                Err(e) if attempt < BOOT_ATTEMPTS => {
+                    warn!(
+                        "Boot attempt {}/{} failed for {:?}, retrying in {:?}. err: {}",
+                        attempt, BOOT_ATTEMPTS, self.isolation_controller, delay, e
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!(
+                        "Boot attempt {}/{} failed for {:?}, giving up. err: {}",
+                        attempt, BOOT_ATTEMPTS, self.isolation_controller, e
+                    );
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // Returns `None` if there is no running process to measure
+    pub fn resource_usage(&mut self) -> Option<ResourceUsage> {
+        let handle = self.process_handle.as_mut()?;
+
+        match handle.resource_usage() {
+            Ok(usage) => Some(usage),
+            Err(e) => {
+                warn!("Could not get resource usage for {:?}, err {}", handle, e);
+                None
+            }
+        }
+    }
+
+    // `captured` is what the log policy collected, which is all we have if the process isn't running
+    pub fn logs(&self, captured: Option<String>) -> Result<Option<String>, WorkerError> {
+        if let Some(handle) = &self.process_handle {
+            handle.logs(captured)
+        } else {
+            Ok(captured)
+        }
+    }
+
+    // The last few lines of the process's output, best effort since we're already handling a failure
+    fn output_tail(&self, log_tracker: &mut LogTracker) -> Option<String> {
+        let (_, captured) = log_tracker.get_contents();
+        let logs = captured.and_then(|captured| self.logs(captured)).ok()??;
+
+        let lines: Vec<&str> = logs.lines().collect();
+        let tail = &lines[lines.len().saturating_sub(OUTPUT_TAIL_LINES)..];
+        Some(tail.join("\n")).filter(|tail| !tail.is_empty())
+    }
+
     // The `heartbeat` function is called periodically
     pub fn heartbeat(&mut self) {
         if self.process_handle.is_none() {
             return;
         }
 
-        if Instant::now() - self.last_accessed > EXPIRY_DURATION {
+        if Instant::now() - self.last_accessed > settings::idle_expiry() {
             debug!("Shutting down unused function {:?}", self.process_handle);
-            self.process_handle = None
+            events::emit(
+                LifecycleEventKind::ProcessTerminated,
+                &self.id,
+                Some("it was idle for longer than the idle expiry".to_string()),
+            );
+            self.stop_process();
+        }
+    }
+}
+
+impl Drop for IsolatedProcessWrapper {
+    fn drop(&mut self) {
+        if self.process_handle.is_some() {
+            events::emit(
+                LifecycleEventKind::ProcessTerminated,
+                &self.id,
+                Some("its component was stopped".to_string()),
+            );
         }
     }
 }
 
+// Someone calling a component holds its lock for the whole call, so to interrupt a stuck call (e.g.
+// when deactivating) we need a way to kill the process that doesn't go through the lock
+// Killing the process makes the in-flight call fail promptly with a pipe/subprocess error
+#[derive(Debug, Default)]
+pub struct KillSwitch {
+    terminator: Mutex<Option<ProcessTerminator>>,
+}
+
+impl KillSwitch {
+    fn arm(&self, terminator: Option<ProcessTerminator>) {
+        *self.terminator.lock() = terminator;
+    }
+
+    // Must be called once the process is gone, so we never signal a reused pid
+    fn disarm(&self) {
+        *self.terminator.lock() = None;
+    }
+
+    pub fn trigger(&self) {
+        if let Some(terminator) = self.terminator.lock().take() {
+            info!("Forcibly terminating process {:?}", terminator);
+            terminator.terminate();
+        }
+    }
+}
+
+// Control messages are mostly useful while the component is busy with a call, when its lock is held, so like the
+// kill switch the control pipe is kept outside of the lock. It's connected whenever the process is running.
+#[derive(Debug)]
+pub struct ControlChannel {
+    framing: PipeFraming,
+    pipe: Mutex<Option<NamedPipe>>,
+}
+
+impl ControlChannel {
+    fn new(framing: PipeFraming) -> Self {
+        Self {
+            framing,
+            pipe: Mutex::new(None),
+        }
+    }
+
+    fn connect(&self, pipe: Option<NamedPipe>) {
+        *self.pipe.lock() = pipe;
+    }
+
+    fn disconnect(&self) {
+        *self.pipe.lock() = None;
+    }
+
+    // Returns the component's reply, or `None` if its process isn't running (and so there's nothing to control)
+    pub fn send(&self, message: &str) -> Result<Option<String>, WorkerError> {
+        let mut pipe = self.pipe.lock();
+        let reply = match pipe.as_mut() {
+            Some(control_pipe) => control_pipe.query(message, self.framing, None),
+            None => return Ok(None),
+        };
+
+        // A failed exchange leaves the pipe out of step, so it isn't used again until the process is restarted
+        if reply.is_err() {
+            *pipe = None;
+        }
+        reply.map(|reply| match self.framing {
+            PipeFraming::Newline => Some(reply.trim_end_matches('\n').to_string()),
+            PipeFraming::LengthPrefixed => Some(reply),
+        })
+    }
+}
+
+// Containerized scripts run in pooled containers, which are started (and have their pipes mounted) before we know
+// what they'll run, so they can't be given a control pipe
+fn check_control_pipe(ar: &ActivateRequest) -> Result<(), WorkerError> {
+    if ar.control_pipe && ar.execution_method == ExecutionMethod::ContainerizedScript {
+        return Err(WorkerErrorKind::IncompatibleContainerOptions(
+            "containerized-script components can't have a control_pipe",
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// Docker images are started with `docker run`, which uses the image's own WORKDIR
+fn check_working_dir(ar: &ActivateRequest) -> Result<(), WorkerError> {
+    let from_image = matches!(
+        ar.execution_method,
+        ExecutionMethod::DockerArchive | ExecutionMethod::DockerBuild
+    );
+    if ar.working_dir.is_some() && from_image {
+        return Err(WorkerErrorKind::IncompatibleContainerOptions(
+            "docker-archive and docker-build components run in their image's WORKDIR, so can't set working_dir",
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// A runtime is asked for to isolate the component, so it mustn't be quietly ignored for one that runs on the host
+fn check_runtime(ar: &ActivateRequest) -> Result<(), WorkerError> {
+    let on_host = matches!(
+        ar.execution_method,
+        ExecutionMethod::NodeUnsafe | ExecutionMethod::PythonUnsafe
+    );
+    if ar.runtime.is_some() && on_host {
+        return Err(WorkerErrorKind::IncompatibleContainerOptions(
+            "node-unsafe and python-unsafe components don't run in a container, so can't set runtime",
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// Every other execution method has its image (or lack of one) decided by its artifact
+fn check_base_image(ar: &ActivateRequest) -> Result<(), WorkerError> {
+    match &ar.base_image {
+        Some(_) if ar.execution_method != ExecutionMethod::ContainerizedScript => {
+            Err(WorkerErrorKind::IncompatibleContainerOptions(
+                "only containerized-script components can set base_image",
+            )
+            .into())
+        }
+        Some(base_image) => validate_image_tag(base_image),
+        None => Ok(()),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ProcessTerminator {
+    Process(u32),
+    Container { name: String, helper_pid: Option<u32> },
+}
+
+impl ProcessTerminator {
+    fn terminate(&self) {
+        match self {
+            Self::Process(pid) => terminate_pid(*pid),
+            Self::Container { name, helper_pid } => {
+                if let Err(e) = kill_container(name) {
+                    warn!("Failed to kill container {}, err {}", name, e);
+                }
+                if let Some(pid) = helper_pid {
+                    terminate_pid(*pid);
+                }
+            }
+        }
+    }
+}
+
+fn terminate_pid(pid: u32) {
+    let res = pid
+        .try_into()
+        .map_err(WorkerError::from)
+        .and_then(|pid| Ok(kill(Pid::from_raw(pid), Signal::SIGTERM)?));
+    if let Err(e) = res {
+        warn!("Failed to terminate process {}, err {}", pid, e);
+    }
+}
+
 pub trait ProcessIsolationController: Debug + Send {
+    // The process's stdout and stderr must be set up from `log_policy` (see `LogPolicy::get_popen_config`),
+    // otherwise its output never reaches the component's logs
+    // If there's a `control_pipe`, its paths are passed to the process after the data pipe's
     fn boot_process(
         &self,
         log_policy: Arc<LogPolicy>,
+        control_pipe: Option<&NamedPipe>,
     ) -> Result<Box<dyn IsolatedProcessHandle>, WorkerError>;
 }
 
 pub trait IsolatedProcessHandle: Debug + Send {
-    fn query_process(&mut self, req: &str) -> Result<String, WorkerError>;
+    // `timeout` replaces V9_CALL_TIMEOUT_MS for this query, if it's set
+    fn query_process(
+        &mut self,
+        req: &str,
+        framing: PipeFraming,
+        timeout: Option<Duration>,
+    ) -> Result<String, WorkerError>;
+
+    // Fails with `SubprocessTerminated` if the process has exited
+    fn check_alive(&mut self) -> Result<(), WorkerError>;
+
+    // Reads another message, for components that answer a query with several (see `ActivateRequest::streaming`)
+    fn read_next(&mut self, framing: PipeFraming) -> Result<String, WorkerError>;
+
+    fn resource_usage(&mut self) -> Result<ResourceUsage, WorkerError>;
+
+    // Takes the output captured by the log policy, and returns the process's full logs
+    fn logs(&self, captured: Option<String>) -> Result<Option<String>, WorkerError> {
+        Ok(captured)
+    }
+
+    // Returns `None` if the process has already exited
+    fn terminator(&self) -> Option<ProcessTerminator>;
+
+    // Whether the process was killed for running out of memory, which only docker keeps track of
+    fn oom_killed(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug)]
 pub struct PythonUnsafeController {
+    // Absolute, see `host_process_paths`
     executable_file: String,
+    working_dir: String,
 }
 
 impl PythonUnsafeController {
-    pub fn new(executable_file: String) -> Result<Self, WorkerError> {
-        Ok(Self { executable_file })
+    pub fn new(executable_file: &str, working_dir: Option<String>) -> Result<Self, WorkerError> {
+        Self::validate(executable_file, working_dir.as_deref())?;
+
+        let (executable_file, working_dir) = host_process_paths(executable_file, working_dir)?;
+        Ok(Self {
+            executable_file,
+            working_dir,
+        })
+    }
+
+    fn validate(executable_file: &str, working_dir: Option<&str>) -> Result<(), WorkerError> {
+        check_host_working_dir(working_dir)?;
+        check_component_file(executable_file, ExpectedFileType::File)
     }
 }
 
@@ -118,69 +665,334 @@ impl ProcessIsolationController for PythonUnsafeController {
     fn boot_process(
         &self,
         log_policy: Arc<LogPolicy>,
+        control_pipe: Option<&NamedPipe>,
     ) -> Result<Box<dyn IsolatedProcessHandle>, WorkerError> {
-        let pipe = NamedPipe::new()?;
+        boot_piped_process(
+            &["python3", "-u", &self.executable_file],
+            &self.working_dir,
+            control_pipe,
+            &log_policy,
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct NodeUnsafeController {
+    // Absolute, see `host_process_paths`
+    executable_file: String,
+    working_dir: String,
+}
 
-        let c_in = canonicalize(pipe.component_input_file())?;
-        let c_out = canonicalize(pipe.component_output_file())?;
+impl NodeUnsafeController {
+    pub fn new(executable_file: &str, working_dir: Option<String>) -> Result<Self, WorkerError> {
+        Self::validate(executable_file, working_dir.as_deref())?;
 
-        let subprocess = Popen::create(
-            &["python3", "-u", &self.executable_file, &c_in, &c_out],
-            log_policy.get_popen_config()?,
-        )?;
+        let (executable_file, working_dir) = host_process_paths(executable_file, working_dir)?;
+        Ok(Self {
+            executable_file,
+            working_dir,
+        })
+    }
+
+    fn validate(executable_file: &str, working_dir: Option<&str>) -> Result<(), WorkerError> {
+        check_host_working_dir(working_dir)?;
+        check_component_file(executable_file, ExpectedFileType::File)?;
+
+        // Fail at activation time, rather than on the first call
+        if find_in_path("node").is_none() {
+            return Err(WorkerErrorKind::MissingExecutable("node").into());
+        }
+
+        Ok(())
+    }
+}
+
+impl ProcessIsolationController for NodeUnsafeController {
+    fn boot_process(
+        &self,
+        log_policy: Arc<LogPolicy>,
+        control_pipe: Option<&NamedPipe>,
+    ) -> Result<Box<dyn IsolatedProcessHandle>, WorkerError> {
+        boot_piped_process(
+            &["node", &self.executable_file],
+            &self.working_dir,
+            control_pipe,
+            &log_policy,
+        )
+    }
+}
+
+// Components run on the host start in the directory their file is in, unless they ask for another one, so
+// relative paths are resolved next to their code. The file itself is passed as an absolute path, since a relative
+// one would be resolved against the new working directory too.
+fn host_process_paths(
+    executable_file: &str,
+    working_dir: Option<String>,
+) -> Result<(String, String), WorkerError> {
+    let executable_file = absolute_path(Path::new(executable_file))?;
+    let working_dir = match working_dir {
+        Some(working_dir) => working_dir,
+        None => Path::new(&executable_file)
+            .parent()
+            .and_then(Path::to_str)
+            .unwrap_or("/")
+            .to_string(),
+    };
+
+    Ok((executable_file, working_dir))
+}
 
-        Ok(Box::new(PipedProcessHandle { subprocess, pipe }))
+fn check_host_working_dir(working_dir: Option<&str>) -> Result<(), WorkerError> {
+    match working_dir {
+        Some(working_dir) => check_component_file(working_dir, ExpectedFileType::Directory),
+        None => Ok(()),
     }
 }
 
+// Runs `command` directly on the host, with the pipe paths appended as the last arguments
+fn boot_piped_process(
+    command: &[&str],
+    working_dir: &str,
+    control_pipe: Option<&NamedPipe>,
+    log_policy: &Arc<LogPolicy>,
+) -> Result<Box<dyn IsolatedProcessHandle>, WorkerError> {
+    let pipe = NamedPipe::new()?;
+
+    let mut argv = command.to_vec();
+    let pipe_paths = pipe_arguments(&pipe, control_pipe)?;
+    argv.extend(pipe_paths.iter().map(String::as_str));
+
+    let mut popen_config = log_policy.get_popen_config()?;
+    popen_config.cwd = Some(OsString::from(working_dir));
+    let subprocess = Popen::create(&argv, popen_config)?;
+
+    Ok(Box::new(PipedProcessHandle { subprocess, pipe }))
+}
+
 #[derive(Debug)]
 struct DockerArchiveController {
     docker_image_tag: String,
+    container_options: ContainerOptions,
 }
 
 impl DockerArchiveController {
-    pub fn new(docker_tar_file_path: &str) -> Result<Self, WorkerError> {
-        if !cfg!(target_os = "linux") {
-            return Err(WorkerErrorKind::UnsupportedPlatform("must be linux!").into());
+    pub fn new(
+        docker_tar_file_path: &str,
+        container_options: ContainerOptions,
+    ) -> Result<Self, WorkerError> {
+        Self::validate(docker_tar_file_path)?;
+
+        let docker_image_tag = load_docker_image(docker_tar_file_path)?;
+        if let Some(platform) = container_options.platform() {
+            if let Err(e) = check_image_platform(&docker_image_tag, platform) {
+                release_docker_image(&docker_image_tag);
+                return Err(e);
+            }
         }
 
         Ok(Self {
-            docker_image_tag: load_docker_image(docker_tar_file_path)?,
+            docker_image_tag,
+            container_options,
         })
     }
+
+    fn validate(docker_tar_file_path: &str) -> Result<(), WorkerError> {
+        check_linux_containers()?;
+        check_component_file(docker_tar_file_path, ExpectedFileType::File)
+    }
 }
 
 impl ProcessIsolationController for DockerArchiveController {
     fn boot_process(
         &self,
         log_policy: Arc<LogPolicy>,
+        control_pipe: Option<&NamedPipe>,
     ) -> Result<Box<dyn IsolatedProcessHandle>, WorkerError> {
-        let pipe = NamedPipe::new()?;
+        boot_image_container(
+            &self.docker_image_tag,
+            &self.container_options,
+            control_pipe,
+            &log_policy,
+        )
+    }
+}
 
-        let c_in = canonicalize(pipe.component_input_file())?;
-        let c_out = canonicalize(pipe.component_output_file())?;
+impl Drop for DockerArchiveController {
+    fn drop(&mut self) {
+        release_docker_image(&self.docker_image_tag);
+    }
+}
 
-        let container = V9Container::start(pipe, &self.docker_image_tag, &[&c_in, &c_out], &log_policy)?;
+#[derive(Debug)]
+struct DockerBuildController {
+    build_context: String,
+    // Derived from the context's contents, so an unchanged context never has to be rebuilt
+    docker_image_tag: String,
+    container_options: ContainerOptions,
+    // Set once we've built (or found) the image, and so hold a reference to it
+    image_ready: AtomicBool,
+}
 
-        Ok(Box::new(ContainerizedProcessHandle {
-            container,
-            helper_subproccess: None,
-        }))
+impl DockerBuildController {
+    pub fn new(
+        build_context: String,
+        path: &ComponentPath,
+        container_options: ContainerOptions,
+    ) -> Result<Self, WorkerError> {
+        Self::validate(&build_context)?;
+
+        // The component path is part of the hash, so components can never end up sharing an image by accident
+        // SHA-256 keeps the tag the same across worker versions (std's hashers make no such promise), so an
+        // upgrade doesn't rebuild every image
+        let mut hasher = Sha256::new();
+        hash_field(&mut hasher, path.user.as_bytes());
+        hash_field(&mut hasher, path.repo.as_bytes());
+        // An empty platform isn't valid, so it can't be mistaken for an unset one
+        hash_field(&mut hasher, container_options.platform().unwrap_or("").as_bytes());
+        hash_contents(Path::new(&build_context), &mut hasher)?;
+        let docker_image_tag = format!("v9-build:{:x}", hasher.finalize());
+
+        Ok(Self {
+            build_context,
+            docker_image_tag,
+            container_options,
+            image_ready: AtomicBool::new(false),
+        })
+    }
+
+    fn validate(build_context: &str) -> Result<(), WorkerError> {
+        check_linux_containers()?;
+        // The context can either be a directory or a tar archive
+        check_component_file(build_context, ExpectedFileType::Either)
+    }
+}
+
+impl ProcessIsolationController for DockerBuildController {
+    fn boot_process(
+        &self,
+        log_policy: Arc<LogPolicy>,
+        control_pipe: Option<&NamedPipe>,
+    ) -> Result<Box<dyn IsolatedProcessHandle>, WorkerError> {
+        // We build on the first boot rather than at activation, so that the build output ends up in the logs
+        if !self.image_ready.load(Ordering::SeqCst) {
+            if docker_image_exists(&self.docker_image_tag) {
+                debug!("Reusing previously built image {}", self.docker_image_tag);
+            } else {
+                build_docker_image(
+                    &self.build_context,
+                    &self.docker_image_tag,
+                    &self.container_options,
+                    &log_policy,
+                )?;
+            }
+            retain_docker_image(&self.docker_image_tag);
+            self.image_ready.store(true, Ordering::SeqCst);
+        }
+
+        boot_image_container(
+            &self.docker_image_tag,
+            &self.container_options,
+            control_pipe,
+            &log_policy,
+        )
     }
 }
 
+impl Drop for DockerBuildController {
+    fn drop(&mut self) {
+        if self.image_ready.load(Ordering::SeqCst) {
+            release_docker_image(&self.docker_image_tag);
+        }
+    }
+}
+
+// Runs a container of `image`, with the pipe paths as its arguments
+fn boot_image_container(
+    image: &str,
+    container_options: &ContainerOptions,
+    control_pipe: Option<&NamedPipe>,
+    log_policy: &Arc<LogPolicy>,
+) -> Result<Box<dyn IsolatedProcessHandle>, WorkerError> {
+    let pipe = NamedPipe::new()?;
+
+    let pipe_paths = pipe_arguments(&pipe, control_pipe)?;
+    let arguments: Vec<&str> = pipe_paths.iter().map(String::as_str).collect();
+    let container = V9Container::start(
+        pipe,
+        control_pipe,
+        image,
+        &arguments,
+        container_options,
+        log_policy,
+    )?;
+
+    Ok(Box::new(ContainerizedProcessHandle {
+        container,
+        helper_subproccess: None,
+    }))
+}
+
+// The data pipe's input and output, then the control pipe's (if there is one)
+fn pipe_arguments(
+    pipe: &NamedPipe,
+    control_pipe: Option<&NamedPipe>,
+) -> Result<Vec<String>, WorkerError> {
+    let mut arguments = Vec::new();
+    for pipe in Some(pipe).into_iter().chain(control_pipe) {
+        arguments.push(canonicalize(pipe.component_input_file())?);
+        arguments.push(canonicalize(pipe.component_output_file())?);
+    }
+
+    Ok(arguments)
+}
+
 #[derive(Debug)]
 pub struct ContainerizedScriptController {
     executable_file: String,
+    image_tag: String,
+    // Inside the container, relative to the code folder
+    working_dir: Option<String>,
+    container_options: ContainerOptions,
 }
 
 impl ContainerizedScriptController {
-    pub fn new(executable_file: String) -> Result<Self, WorkerError> {
-        if !cfg!(target_os = "linux") {
-            return Err(WorkerErrorKind::UnsupportedPlatform("must be linux!").into());
+    // `base_image` defaults to the idle pool's image
+    pub fn new(
+        executable_file: String,
+        base_image: Option<String>,
+        working_dir: Option<String>,
+        container_options: ContainerOptions,
+    ) -> Result<Self, WorkerError> {
+        Self::validate(&executable_file, &container_options)?;
+
+        let image_tag = base_image.unwrap_or_else(|| idle_container_config().image_tag.clone());
+        warm_idle_pool(&image_tag);
+
+        Ok(Self {
+            executable_file,
+            image_tag,
+            working_dir,
+            container_options,
+        })
+    }
+
+    fn validate(executable_file: &str, container_options: &ContainerOptions) -> Result<(), WorkerError> {
+        check_linux_containers()?;
+        // The whole directory is copied into the container
+        check_component_file(executable_file, ExpectedFileType::Directory)?;
+        check_component_file(
+            &format!("{}/{}", executable_file, START_SCRIPT),
+            ExpectedFileType::File,
+        )?;
+        // Setting up the code folder needs root and a writable root filesystem
+        if container_options.is_restricted() {
+            return Err(WorkerErrorKind::IncompatibleContainerOptions(
+                "containerized-script components can't set run_as_user or read_only_rootfs",
+            )
+            .into());
         }
 
-        Ok(Self { executable_file })
+        Ok(())
     }
 }
 
@@ -188,12 +1000,15 @@ impl ProcessIsolationController for ContainerizedScriptController {
     fn boot_process(
         &self,
         log_policy: Arc<LogPolicy>,
+        // Never set, see `check_control_pipe`
+        _control_pipe: Option<&NamedPipe>,
     ) -> Result<Box<dyn IsolatedProcessHandle>, WorkerError> {
-        let mut container = get_idle_container()?;
+        let mut container = get_container(&self.image_tag, &self.container_options)?;
+        let code_folder = &idle_container_config().code_folder;
 
         // Copy over the files
         let pre_copy = Instant::now();
-        container.copy_directory_in(&self.executable_file, CODE_FOLDER)?;
+        container.copy_directory_in(&self.executable_file, code_folder)?;
         debug!(
             "Copying directory took {} milliseconds",
             pre_copy.elapsed().as_millis()
@@ -202,10 +1017,39 @@ impl ProcessIsolationController for ContainerizedScriptController {
         let c_in = canonicalize(container.pipe().component_input_file())?;
         let c_out = canonicalize(container.pipe().component_output_file())?;
 
-        let subprocess = container.exec_async(
-            &["sh", &format!("{}/{}", CODE_FOLDER, "start.sh"), &c_in, &c_out],
-            &log_policy,
-        )?;
+        // The script could have been removed from the component's directory since it was activated
+        let start_script = format!("{}/{}", code_folder, START_SCRIPT);
+        match container.exec_sync(&["test", "-f", &start_script]) {
+            Ok(_) => {}
+            // `test` failing is reported like any other failed docker command
+            Err(e) if matches!(e.kind(), WorkerErrorKind::Docker(..)) => {
+                return Err(WorkerErrorKind::StartScriptFailed(
+                    format!("{} is missing from the component's directory", START_SCRIPT),
+                    None,
+                )
+                .into())
+            }
+            Err(e) => return Err(e),
+        }
+
+        // A working directory that doesn't exist in the container makes `docker exec` fail right away, which the
+        // check below reports
+        let working_dir = match &self.working_dir {
+            Some(working_dir) => Path::new(code_folder)
+                .join(working_dir)
+                .to_string_lossy()
+                .into_owned(),
+            None => code_folder.clone(),
+        };
+        let mut subprocess =
+            container.exec_async(&["sh", &start_script, &c_in, &c_out], &working_dir, &log_policy)?;
+        if let Some(exit_status) = subprocess.wait_timeout(START_SCRIPT_GRACE_PERIOD)? {
+            return Err(WorkerErrorKind::StartScriptFailed(
+                format!("{} exited right away, with code {:?}", START_SCRIPT, exit_status),
+                None,
+            )
+            .into());
+        }
 
         Ok(Box::new(ContainerizedProcessHandle {
             container,
@@ -221,18 +1065,89 @@ pub struct PipedProcessHandle {
 }
 
 impl IsolatedProcessHandle for PipedProcessHandle {
-    fn query_process(&mut self, req: &str) -> Result<String, WorkerError> {
-        // Check if the subprocess has terminated
-        if let Some(exit_status) = self.subprocess.poll() {
-            return Err(WorkerErrorKind::SubprocessTerminated(exit_status).into());
-        }
+    fn query_process(
+        &mut self,
+        req: &str,
+        framing: PipeFraming,
+        timeout: Option<Duration>,
+    ) -> Result<String, WorkerError> {
+        self.check_alive()?;
 
         trace!("Writing {:?} to piped process", req);
-        let resp = self.pipe.query(req)?;
+        let resp = self.pipe.query(req, framing, timeout)?;
         trace!("Got back {:?} from piped process", resp);
 
         Ok(resp)
     }
+
+    fn read_next(&mut self, framing: PipeFraming) -> Result<String, WorkerError> {
+        let resp = self.pipe.read_string(framing)?;
+        trace!("Got back {:?} from piped process", resp);
+
+        Ok(resp)
+    }
+
+    fn check_alive(&mut self) -> Result<(), WorkerError> {
+        match self.subprocess.poll() {
+            Some(exit_status) => Err(WorkerErrorKind::SubprocessTerminated(exit_status, None).into()),
+            None => Ok(()),
+        }
+    }
+
+    fn resource_usage(&mut self) -> Result<ResourceUsage, WorkerError> {
+        self.check_alive()?;
+
+        // This is a safe unwrap, since we just checked the subprocess is still running
+        proc_resource_usage(self.subprocess.pid().unwrap())
+    }
+
+    fn terminator(&self) -> Option<ProcessTerminator> {
+        self.subprocess.pid().map(ProcessTerminator::Process)
+    }
+}
+
+fn proc_resource_usage(pid: u32) -> Result<ResourceUsage, WorkerError> {
+    let stat = read_to_string(format!("/proc/{}/stat", pid))?;
+    let statm = read_to_string(format!("/proc/{}/statm", pid))?;
+    let uptime = read_to_string("/proc/uptime")?;
+
+    let parse_field = |field: Option<&str>, source: &str| {
+        field.and_then(|f| f.parse::<f64>().ok()).ok_or_else(|| {
+            WorkerError::from(WorkerErrorKind::InvalidSerialization(
+                "malformed /proc entry",
+                source.as_bytes().to_vec(),
+            ))
+        })
+    };
+
+    // The command name in `stat` may contain spaces, so only look at fields after its closing paren
+    // (After that paren, utime/stime/starttime are at indices 11/12/19)
+    let stat_fields: Vec<&str> = stat.rsplit(')').next().unwrap_or("").split_whitespace().collect();
+    let user_ticks = parse_field(stat_fields.get(11).copied(), &stat)?;
+    let system_ticks = parse_field(stat_fields.get(12).copied(), &stat)?;
+    let start_ticks = parse_field(stat_fields.get(19).copied(), &stat)?;
+    let system_uptime = parse_field(uptime.split_whitespace().next(), &uptime)?;
+
+    // This is the average cpu usage over the lifetime of the process
+    let cpu_seconds = (user_ticks + system_ticks) / CLOCK_TICKS_PER_SECOND;
+    let process_seconds = system_uptime - start_ticks / CLOCK_TICKS_PER_SECOND;
+    let cpu_percent = if process_seconds > 0.0 {
+        100.0 * cpu_seconds / process_seconds
+    } else {
+        0.0
+    };
+
+    // The second field of `statm` is the resident set size in pages
+    let resident_pages = parse_field(statm.split_whitespace().nth(1), &statm)?;
+    let page_size: u64 = match sysconf(SysconfVar::PAGE_SIZE)? {
+        Some(size) => size.try_into()?,
+        None => DEFAULT_PAGE_SIZE,
+    };
+
+    Ok(ResourceUsage {
+        memory_bytes: resident_pages * page_size as f64,
+        cpu_percent,
+    })
 }
 
 impl Drop for PipedProcessHandle {
@@ -254,23 +1169,78 @@ pub struct ContainerizedProcessHandle {
 }
 
 impl IsolatedProcessHandle for ContainerizedProcessHandle {
-    fn query_process(&mut self, req: &str) -> Result<String, WorkerError> {
-        // Check if the subprocess has terminated
-        if let Some(exit_status) = self.container.process().poll() {
-            return Err(WorkerErrorKind::SubprocessTerminated(exit_status).into());
-        }
+    fn query_process(
+        &mut self,
+        req: &str,
+        framing: PipeFraming,
+        timeout: Option<Duration>,
+    ) -> Result<String, WorkerError> {
+        self.check_alive()?;
 
         trace!("Writing {:?} to piped process", req);
-        let resp = self.container.pipe().query(req)?;
+        let resp = self.container.pipe().query(req, framing, timeout)?;
+        trace!("Got back {:?} from piped process", resp);
+
+        Ok(resp)
+    }
+
+    // A containerized script runs through `docker exec`, so it's only alive if the helper is too
+    fn check_alive(&mut self) -> Result<(), WorkerError> {
+        let helper_exit = self.helper_subproccess.as_mut().and_then(Popen::poll);
+        match self.container.process().poll().or(helper_exit) {
+            Some(exit_status) => Err(WorkerErrorKind::SubprocessTerminated(exit_status, None).into()),
+            None => Ok(()),
+        }
+    }
+
+    fn read_next(&mut self, framing: PipeFraming) -> Result<String, WorkerError> {
+        let resp = self.container.pipe().read_string(framing)?;
         trace!("Got back {:?} from piped process", resp);
 
         Ok(resp)
     }
+
+    fn resource_usage(&mut self) -> Result<ResourceUsage, WorkerError> {
+        self.container.resource_usage()
+    }
+
+    // The log policy only sees our local docker client processes, so ask the daemon for the container output
+    fn logs(&self, captured: Option<String>) -> Result<Option<String>, WorkerError> {
+        let container_logs = self.container.logs()?;
+
+        Ok(Some(match (&self.helper_subproccess, captured) {
+            // The script runs through `docker exec`, so its output only shows up in the helper's logs
+            (Some(_), Some(helper_logs)) => container_logs + &helper_logs,
+            _ => container_logs,
+        }))
+    }
+
+    fn terminator(&self) -> Option<ProcessTerminator> {
+        Some(ProcessTerminator::Container {
+            name: self.container.name().to_string(),
+            helper_pid: self.helper_subproccess.as_ref().and_then(Popen::pid),
+        })
+    }
+
+    fn oom_killed(&self) -> bool {
+        self.container.oom_killed().unwrap_or_else(|e| {
+            warn!(
+                "Could not check if container {} ran out of memory, err {}",
+                self.container.name(),
+                e
+            );
+            false
+        })
+    }
 }
 
 impl Drop for ContainerizedProcessHandle {
     fn drop(&mut self) {
         if let Some(p) = &mut self.helper_subproccess {
+            // The script runs through `docker exec`, so the container itself doesn't see it fail
+            if p.poll().map_or(false, |status| !status.success()) {
+                self.container.mark_component_failed();
+            }
             if let Err(e) = p.terminate() {
                 // Detach so we don't hang waiting for it
                 p.detach();
@@ -1,4 +1,4 @@
-use std::fs::read_to_string;
+use std::fs::{read_to_string, OpenOptions};
 use std::mem::replace;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
@@ -6,6 +6,7 @@ use std::sync::Arc;
 use tempfile::NamedTempFile;
 
 use crate::error::WorkerError;
+use crate::fs_utils::named_temp_file;
 use subprocess::{PopenConfig, Redirection};
 
 static DEDUP_COUNTER: AtomicU64 = AtomicU64::new(0);
@@ -15,19 +16,25 @@ pub struct LogTracker {
     // Tracks when a different log tracker is switched to
     dedup_number: u64,
     policy_handle: Arc<LogPolicy>,
+    // If not set, every policy we hand out ignores the output
+    capture_logs: bool,
 }
 
 impl LogTracker {
-    pub fn new() -> Self {
+    pub fn new(capture_logs: bool) -> Self {
         Self {
             dedup_number: DEDUP_COUNTER.fetch_add(1, Ordering::SeqCst),
             policy_handle: LogPolicy::new_ignore_policy(),
+            capture_logs,
         }
     }
 
     pub fn create_associated_policy(&mut self) -> Result<Arc<LogPolicy>, WorkerError> {
-        let backing_file = NamedTempFile::new()?;
-        let associated_policy = Arc::new(LogPolicy::ToFile(backing_file));
+        let associated_policy = if self.capture_logs {
+            Arc::new(LogPolicy::ToFile(named_temp_file()?))
+        } else {
+            LogPolicy::new_ignore_policy()
+        };
 
         let old_policy = replace(&mut self.policy_handle, associated_policy.clone());
         // Check if the old policy is still in use (this is mostly just for debugging/testing)
@@ -84,12 +91,16 @@ impl LogPolicy {
                 stderr: Redirection::File(temp_file.as_file().try_clone()?),
                 ..PopenConfig::default()
             },
-            Self::Ignore => PopenConfig {
-                detached: true,
-                stdout: Redirection::Pipe,
-                stderr: Redirection::Pipe,
-                ..PopenConfig::default()
-            },
+            // Nobody would read a pipe, so a chatty process would eventually block writing to it
+            Self::Ignore => {
+                let null = OpenOptions::new().write(true).open("/dev/null")?;
+                PopenConfig {
+                    detached: true,
+                    stdout: Redirection::File(null.try_clone()?),
+                    stderr: Redirection::File(null),
+                    ..PopenConfig::default()
+                }
+            }
         })
     }
 }
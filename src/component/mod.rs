@@ -1,50 +1,167 @@
+mod call_limit;
+mod circuit_breaker;
+mod cors;
+mod fetch;
 mod isolation;
 mod logs;
+mod network;
+mod persistence;
+mod rate_limit;
+mod response_cache;
+mod spill;
 mod stats;
+mod status;
+mod streaming;
 
-use std::collections::HashMap;
-use std::convert::TryInto;
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
 use std::fmt::{self, Debug, Formatter};
-use std::time::Instant;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use hyper::{Body, Method, Response};
-use parking_lot::Mutex;
+use hyper::body::Bytes;
+use hyper::header::{
+    HeaderName, HeaderValue, ALLOW, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, ORIGIN,
+};
+use hyper::{Body, HeaderMap, Method, Response, StatusCode};
+use parking_lot::{Mutex, RwLock};
 use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
 use systemstat::{Platform, System};
 
-use crate::component::isolation::IsolatedProcessWrapper;
+use crate::audit;
+use crate::component::call_limit::{CallLimiter, CallSlot};
+use crate::component::isolation::{
+    ControlChannel, IsolatedProcessWrapper, KillSwitch, Liveness, PING_TIMEOUT,
+};
 use crate::component::logs::LogTracker;
+use crate::component::network::NetworkTracker;
+use crate::component::rate_limit::RateLimiter;
+use crate::component::response_cache::{is_cacheable_request, CacheKey, ResponseCache};
 use crate::component::stats::StatTracker;
-use crate::error::WorkerError;
+use crate::component::status::StatusSnapshot;
+use crate::component::streaming::EventSink;
+use crate::docker::idle_container_creator::{idle_pool_status, idle_pools_status};
+use crate::error::{WorkerError, WorkerErrorKind, WORKER_ERROR_STATUS};
+use crate::events;
 use crate::model::{
-    ActivateRequest, ActivateResponse, ActivationStatus, ComponentId, ComponentLog, ComponentPath,
-    ComponentRequest, ComponentResponse, ComponentStatus, DeactivateRequest, DeactivateResponse,
-    DeactivationStatus, LogResponse, StatusColor, StatusResponse,
+    ActivateRequest, ActivateResponse, ActivationStatus, AuditOperation, AuditResult,
+    ComponentActivationResponse, ComponentId, ComponentLog, ComponentPath, ComponentRequest,
+    ComponentResponse, ComponentStreamMessage, ControlMessage, ControlRequest, ControlResponse,
+    ControlStatus, CorsConfig, DeactivateAllResponse, DeactivateRequest, DeactivateResponse,
+    DeactivationStatus, LifecycleEventKind, LogResponse, PingResponse, PingStatus, RawStatsResponse,
+    StatusColor, StatusResponse, WarmRequest, WarmResponse, WarmStatus, PING_FUNCTION,
 };
+use crate::request_handler::{client_ip, request_id, traceparent};
 
 pub use crate::component::logs::LogPolicy;
+pub use crate::component::stats::response_color;
+pub use crate::component::streaming::EarlyResponse;
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+const PLAIN_TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+// Where a component's own reason phrase goes, see `set_status_reason`
+const X_STATUS_REASON: &str = "x-status-reason";
+
+// How much of a malformed component response is echoed back in the error, to help debug the component
+const MAX_MALFORMED_RESPONSE_EXCERPT_CHARS: usize = 512;
+// Every verb we pass on to components (OPTIONS and HEAD may be answered by the worker instead)
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS";
+
+// Bumped whenever `StatusResponse` changes incompatibly
+// Version 2: system usage is `null` when unavailable, instead of -1
+const STATUS_RESPONSE_VERSION: u32 = 2;
 
 pub struct ComponentManager {
     system: System,
+    network_tracker: Mutex<NetworkTracker>,
     // Invariant: No method without exclusive access (&mut self) can lock multiple components at a time
     // (Otherwise deadlock is possible)
     active_components: HashMap<ComponentPath, Mutex<ComponentHandle>>,
+    // These are kept outside the component locks, so in-flight calls can be interrupted
+    kill_switches: HashMap<ComponentPath, Arc<KillSwitch>>,
+    // Same for control channels, which only components activated with `control_pipe` have
+    control_channels: HashMap<ComponentPath, Arc<ControlChannel>>,
+    // Also kept outside the component locks, since they limit how many calls can wait on those locks
+    call_limiters: HashMap<ComponentPath, CallLimiter>,
+    // And the status snapshots, so status requests don't wait for calls to finish
+    statuses: HashMap<ComponentPath, Arc<Mutex<StatusSnapshot>>>,
+    // What we need to re-activate everything after a restart, plus whether it changed since the last save
+    activations: HashMap<ComponentPath, ActivateRequest>,
+    activations_changed: AtomicBool,
+    // Activations that passed their checks but are still building their isolation controller, keyed by the
+    // ticket of the `ActivationReservation` holding them
+    reservations: HashMap<ComponentPath, (u64, ComponentId)>,
+    next_reservation_ticket: u64,
+}
+
+// Holds a component's slot while its isolation controller is built, which can take a while (e.g. loading a docker
+// image), so the manager doesn't have to stay locked in the meantime
+#[derive(Debug)]
+struct ActivationReservation {
+    activate_request: ActivateRequest,
+    stat_tracker: StatTracker,
+    ticket: u64,
 }
 
 impl Debug for ComponentManager {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("ComponentManager")
             .field("system", &"[unable to format this]")
+            .field("network_tracker", &self.network_tracker)
             .field("active_components", &self.active_components)
+            .field("kill_switches", &self.kill_switches)
+            .field("control_channels", &self.control_channels)
+            .field("call_limiters", &self.call_limiters)
+            .field("statuses", &self.statuses)
+            .field("activations", &self.activations)
+            .field("activations_changed", &self.activations_changed)
+            .field("reservations", &self.reservations)
+            .field("next_reservation_ticket", &self.next_reservation_ticket)
             .finish()
     }
 }
 
+// Finds a call limit set to zero, which would reject every call and so is never what the caller meant
+fn zero_limit(activate_request: &ActivateRequest) -> Option<&'static str> {
+    if activate_request.max_requests_per_second == Some(0) {
+        Some("max_requests_per_second")
+    } else if activate_request.max_concurrent == Some(0) {
+        Some("max_concurrent")
+    } else {
+        None
+    }
+}
+
+fn failed_activation(e: &WorkerError) -> ActivateResponse {
+    let result = match e.kind() {
+        WorkerErrorKind::ArtifactDownload(_, _)
+        | WorkerErrorKind::HashMismatch(_, _, _)
+        | WorkerErrorKind::MissingComponentFile(_, _) => ActivationStatus::FailedToFindExecutable,
+        _ => ActivationStatus::FailedToStart,
+    };
+    ActivateResponse {
+        result,
+        dbg_message: e.to_string(),
+        id: None,
+    }
+}
+
 impl ComponentManager {
     pub fn new() -> Self {
         Self {
             system: System::new(),
+            network_tracker: Mutex::new(NetworkTracker::default()),
             active_components: HashMap::new(),
+            kill_switches: HashMap::new(),
+            control_channels: HashMap::new(),
+            call_limiters: HashMap::new(),
+            statuses: HashMap::new(),
+            activations: HashMap::new(),
+            activations_changed: AtomicBool::new(false),
+            reservations: HashMap::new(),
+            next_reservation_ticket: 0,
         }
     }
 
@@ -52,64 +169,292 @@ impl ComponentManager {
         self.active_components.get(path)
     }
 
-    // TODO: Activate and Deactivate should respect the hash passsed in, instead of blindly deactivating anything
+    // Claims one of the component's call slots, which must be held for the whole call (including waiting for
+    // the component's lock). Fails immediately if the component already has as many calls as it allows.
+    pub fn enter_call(&self, path: &ComponentPath) -> Result<CallSlot<'_>, WorkerError> {
+        let limiter = self
+            .call_limiters
+            .get(path)
+            .ok_or_else(|| WorkerErrorKind::ComponentNotFound(path.clone()))?;
+
+        let slot = limiter.try_enter();
+        if slot.is_err() {
+            warn!(
+                "Shedding a call to {:?}, since it has too many calls in flight",
+                path
+            );
+        }
+        slot
+    }
+
+    // TODO: Deactivate should respect the hash passsed in, instead of blindly deactivating anything
 
     pub fn activate(
         &mut self,
         activate_request: Result<ActivateRequest, serde_json::Error>,
     ) -> ActivateResponse {
-        if let Err(e) = activate_request {
-            return ActivateResponse {
-                result: ActivationStatus::InvalidRequest,
-                dbg_message: e.to_string(),
-            };
-        }
+        let id = activate_request.as_ref().ok().map(|ar| ar.id.clone());
+        let resp = match self.reserve_activation(activate_request) {
+            Ok(reservation) => {
+                let isolated_process_wrapper =
+                    IsolatedProcessWrapper::new(reservation.activate_request.clone());
+                self.finish_activation(reservation, isolated_process_wrapper)
+            }
+            Err(resp) => resp,
+        };
 
-        // This is a safe unwrap, since we just checked if activate_request was in an error state
-        let activate_request = activate_request.unwrap();
+        audit::record(
+            AuditOperation::Activate,
+            id.as_ref(),
+            AuditResult::Activation(resp.result.clone()),
+        );
+        resp
+    }
 
-        if self.active_components.contains_key(&activate_request.id.path) {
-            warn!(
-                "Attempt to activate already activated component ({:?}) was foiled!",
+    // Like `activate`, but the manager is only locked to reserve and then finish the activation
+    // That way routing to every other component carries on while this one's isolation controller is built
+    pub fn activate_shared(
+        manager: &RwLock<Self>,
+        activate_request: Result<ActivateRequest, serde_json::Error>,
+    ) -> ActivateResponse {
+        let id = activate_request.as_ref().ok().map(|ar| ar.id.clone());
+        let reserved = manager.write().reserve_activation(activate_request);
+        let resp = match reserved {
+            Ok(reservation) => {
+                let isolated_process_wrapper =
+                    IsolatedProcessWrapper::new(reservation.activate_request.clone());
+                manager
+                    .write()
+                    .finish_activation(reservation, isolated_process_wrapper)
+            }
+            Err(resp) => resp,
+        };
+
+        audit::record(
+            AuditOperation::Activate,
+            id.as_ref(),
+            AuditResult::Activation(resp.result.clone()),
+        );
+        resp
+    }
+
+    // Runs the activation checks, and if they pass holds the component's slot until `finish_activation`
+    fn reserve_activation(
+        &mut self,
+        activate_request: Result<ActivateRequest, serde_json::Error>,
+    ) -> Result<ActivationReservation, ActivateResponse> {
+        let activate_request = activate_request.map_err(|e| ActivateResponse {
+            result: ActivationStatus::InvalidRequest,
+            dbg_message: e.to_string(),
+            id: None,
+        })?;
+
+        let stat_tracker = self.check_activation(&activate_request)?;
+
+        let ticket = self.next_reservation_ticket;
+        self.next_reservation_ticket += 1;
+        self.reservations.insert(
+            activate_request.id.path.clone(),
+            (ticket, activate_request.id.clone()),
+        );
+
+        Ok(ActivationReservation {
+            activate_request,
+            stat_tracker,
+            ticket,
+        })
+    }
+
+    // Makes a reserved component active, unless it was deactivated while its isolation controller was being built
+    fn finish_activation(
+        &mut self,
+        reservation: ActivationReservation,
+        isolated_process_wrapper: Result<IsolatedProcessWrapper, WorkerError>,
+    ) -> ActivateResponse {
+        let ActivationReservation {
+            activate_request,
+            stat_tracker,
+            ticket,
+        } = reservation;
+
+        let path = &activate_request.id.path;
+        if self.reservations.get(path).map(|(t, _)| *t) != Some(ticket) {
+            info!(
+                "Component ({:?}) was deactivated before its activation finished",
                 activate_request
             );
             return ActivateResponse {
-                result: ActivationStatus::AlreadyRunning,
-                dbg_message: "already running, redundant request!!".to_string(),
+                result: ActivationStatus::FailedToStart,
+                dbg_message: "the component was deactivated while it was being activated".to_string(),
+                id: None,
             };
         }
+        self.reservations.remove(path);
 
-        let isolated_process_wrapper = match IsolatedProcessWrapper::new(activate_request.clone()) {
+        let isolated_process_wrapper = match isolated_process_wrapper {
             Ok(w) => w,
-            Err(e) => {
-                return ActivateResponse {
-                    result: ActivationStatus::FailedToStart,
-                    dbg_message: e.to_string(),
-                }
-            }
+            Err(e) => return failed_activation(&e),
         };
 
+        // Dropping the old component stops its process
+        let replaced = self.remove_active_component(path);
+
+        self.kill_switches.insert(
+            activate_request.id.path.clone(),
+            isolated_process_wrapper.kill_switch(),
+        );
+        if let Some(control_channel) = isolated_process_wrapper.control_channel() {
+            self.control_channels
+                .insert(activate_request.id.path.clone(), control_channel);
+        }
+        self.call_limiters.insert(
+            activate_request.id.path.clone(),
+            CallLimiter::new(activate_request.max_concurrent),
+        );
+        let status = Arc::new(Mutex::new(StatusSnapshot::new(
+            activate_request.id.clone(),
+            stat_tracker,
+        )));
+        self.statuses
+            .insert(activate_request.id.path.clone(), status.clone());
         self.active_components.insert(
             activate_request.id.path.clone(),
             Mutex::new(ComponentHandle {
                 id: activate_request.id.clone(),
                 component_process_wrapper: isolated_process_wrapper,
-                log_tracker: LogTracker::new(),
-                stat_tracker: StatTracker::default(),
+                cors: activate_request.cors.clone(),
+                handle_options: activate_request.handle_options,
+                streaming: activate_request.streaming,
+                ping: activate_request.ping,
+                handlers: activate_request
+                    .handlers
+                    .as_ref()
+                    .map(|handlers| handlers.iter().cloned().collect()),
+                rate_limiter: activate_request.max_requests_per_second.map(RateLimiter::new),
+                response_cache: ResponseCache::default(),
+                log_tracker: LogTracker::new(activate_request.capture_logs),
+                status,
             }),
         );
 
+        self.activations
+            .insert(activate_request.id.path.clone(), activate_request.clone());
+        self.activations_changed.store(true, Ordering::SeqCst);
+        events::emit(LifecycleEventKind::Activated, &activate_request.id, None);
+
         info!("Successfully activated a component ({:?})", activate_request);
 
         ActivateResponse {
             result: ActivationStatus::ActivationSuccessful,
-            dbg_message: "successfully activated".to_string(),
+            dbg_message: match replaced {
+                Some(replaced) => format!("successfully activated, replacing hash {}", replaced.hash),
+                None => "successfully activated".to_string(),
+            },
+            id: Some(activate_request.id),
+        }
+    }
+
+    // Answers like `activate` would, without starting the component or changing what is active
+    pub fn validate_activation(
+        &self,
+        activate_request: Result<ActivateRequest, serde_json::Error>,
+    ) -> ActivateResponse {
+        let activate_request = match activate_request {
+            Ok(ar) => ar,
+            Err(e) => {
+                return ActivateResponse {
+                    result: ActivationStatus::InvalidRequest,
+                    dbg_message: e.to_string(),
+                    id: None,
+                }
+            }
+        };
+
+        if let Err(resp) = self.check_activation(&activate_request) {
+            return resp;
+        }
+        if let Err(e) = IsolatedProcessWrapper::validate(&activate_request) {
+            return failed_activation(&e);
+        }
+
+        ActivateResponse {
+            result: ActivationStatus::ActivationSuccessful,
+            dbg_message: "dry run, the activation would succeed".to_string(),
+            id: Some(activate_request.id),
+        }
+    }
+
+    // The checks an activation has to pass before we look at the component itself
+    // On failure, returns the response the activation should get
+    fn check_activation(
+        &self,
+        activate_request: &ActivateRequest,
+    ) -> Result<StatTracker, ActivateResponse> {
+        if let Some((_, reserved)) = self.reservations.get(&activate_request.id.path) {
+            return Err(ActivateResponse {
+                result: ActivationStatus::AlreadyRunning,
+                dbg_message: "this component is already being activated".to_string(),
+                id: Some(reserved.clone()),
+            });
+        }
+
+        if let Some(running) = self.activations.get(&activate_request.id.path) {
+            // Re-sending the exact component that is already running is how reconciliation loops converge,
+            // so it isn't worth a warning
+            if running.id == activate_request.id {
+                debug!("Component ({:?}) is already up to date", running.id);
+                return Err(ActivateResponse {
+                    result: ActivationStatus::AlreadyUpToDate,
+                    dbg_message: "already running this exact component".to_string(),
+                    id: Some(running.id.clone()),
+                });
+            }
+
+            // A different hash replaces the running component once the new one is ready (see
+            // `finish_activation`), until then calls keep going to the old one
+            info!(
+                "Replacing a running component ({:?}) with {:?}",
+                running.id, activate_request.id
+            );
         }
+
+        if let Some(field) = zero_limit(activate_request) {
+            return Err(ActivateResponse {
+                result: ActivationStatus::InvalidRequest,
+                dbg_message: format!("{} must be positive", field),
+                id: None,
+            });
+        }
+
+        StatTracker::new(
+            activate_request.stat_window_seconds,
+            activate_request.latency_percentiles.clone(),
+        )
+        .map_err(|e| ActivateResponse {
+            result: ActivationStatus::InvalidRequest,
+            dbg_message: e.to_string(),
+            id: None,
+        })
     }
 
     pub fn deactivate(
         &mut self,
         deactivate_request: Result<DeactivateRequest, serde_json::Error>,
+    ) -> DeactivateResponse {
+        let id = deactivate_request.as_ref().ok().map(|dr| dr.id.clone());
+        let resp = self.remove_component(deactivate_request);
+
+        audit::record(
+            AuditOperation::Deactivate,
+            id.as_ref(),
+            AuditResult::Deactivation(resp.result.clone()),
+        );
+        resp
+    }
+
+    fn remove_component(
+        &mut self,
+        deactivate_request: Result<DeactivateRequest, serde_json::Error>,
     ) -> DeactivateResponse {
         if let Err(e) = deactivate_request {
             return DeactivateResponse {
@@ -121,6 +466,18 @@ impl ComponentManager {
         // This is a safe unwrap, since we just checked if deactivate_request was in an error state
         let deactivate_request = deactivate_request.unwrap();
 
+        // The activation will see its reservation is gone when it finishes, and drop the component
+        if self.reservations.remove(&deactivate_request.id.path).is_some() {
+            info!(
+                "Cancelled the activation in progress of a component ({:?})",
+                deactivate_request
+            );
+            return DeactivateResponse {
+                result: DeactivationStatus::DeactivationSuccessful,
+                dbg_message: "cancelled the activation in progress".to_string(),
+            };
+        }
+
         if !self.active_components.contains_key(&deactivate_request.id.path) {
             warn!(
                 "Attempt to deactivate a non-active component ({:?}) was foiled!",
@@ -132,7 +489,8 @@ impl ComponentManager {
             };
         }
 
-        self.active_components.remove(&deactivate_request.id.path);
+        self.remove_active_component(&deactivate_request.id.path);
+        self.activations_changed.store(true, Ordering::SeqCst);
 
         info!("Successfully deactivated a component ({:?})", deactivate_request);
 
@@ -142,92 +500,429 @@ impl ComponentManager {
         }
     }
 
+    // Returns the ID of the component that was removed, if there was one
+    fn remove_active_component(&mut self, path: &ComponentPath) -> Option<ComponentId> {
+        self.kill_switches.remove(path);
+        self.control_channels.remove(path);
+        self.call_limiters.remove(path);
+        self.statuses.remove(path);
+        self.activations.remove(path);
+
+        // The deactivation request's hash isn't checked (see the TODO on `activate`), so the event carries the
+        // running one
+        let id = self.active_components.remove(path)?.into_inner().id;
+        events::emit(LifecycleEventKind::Deactivated, &id, None);
+        Some(id)
+    }
+
+    // Kills the component's process, so any in-flight call to it fails fast instead of hanging
+    // This only needs shared access, so it can be done before waiting for exclusive access to deactivate
+    pub fn interrupt(&self, path: &ComponentPath) {
+        if let Some(kill_switch) = self.kill_switches.get(path) {
+            kill_switch.trigger();
+        }
+    }
+
+    pub fn interrupt_all(&self) {
+        for kill_switch in self.kill_switches.values() {
+            kill_switch.trigger();
+        }
+    }
+
+    // Used to drain a worker -- every component is dropped, which terminates its process/container
+    pub fn deactivate_all(&mut self) -> DeactivateAllResponse {
+        self.kill_switches.clear();
+        self.control_channels.clear();
+        self.call_limiters.clear();
+        self.statuses.clear();
+        self.activations.clear();
+        self.activations_changed.store(true, Ordering::SeqCst);
+
+        let deactivated: Vec<ComponentId> = self
+            .active_components
+            .drain()
+            .map(|(_, component)| component.into_inner().id)
+            .chain(self.reservations.drain().map(|(_, (_, id))| id))
+            .collect();
+
+        for id in &deactivated {
+            audit::record(
+                AuditOperation::DeactivateAll,
+                Some(id),
+                AuditResult::Deactivation(DeactivationStatus::DeactivationSuccessful),
+            );
+            events::emit(LifecycleEventKind::Deactivated, id, None);
+        }
+
+        info!("Successfully deactivated all components ({:?})", deactivated);
+
+        DeactivateAllResponse {
+            deactivated_count: deactivated.len(),
+            deactivated,
+            dbg_message: "deactivation of all components succesful".to_string(),
+        }
+    }
+
+    // Used when the worker shuts down -- like `deactivate_all`, every process/container is stopped, but the
+    // activations are kept, so they're restored when the worker comes back
+    pub fn stop_all(&mut self) {
+        self.interrupt_all();
+        self.kill_switches.clear();
+        self.control_channels.clear();
+        self.call_limiters.clear();
+        self.statuses.clear();
+
+        let stopped = self.active_components.len();
+        self.active_components.clear();
+
+        info!("Stopped all {} active components", stopped);
+    }
+
+    // Saves the activation requests of every active component, if they changed since the last save
+    pub fn persist_activations(&self, path: &Path) {
+        if !self.activations_changed.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let activations: Vec<&ActivateRequest> = self
+            .activations
+            .values()
+            .filter(|ar| persistence::is_restorable(ar))
+            .collect();
+        if let Err(e) = persistence::save_activations(path, &activations) {
+            warn!("Could not persist activations to {:?}, err {}", path, e);
+            // Try again next time
+            self.activations_changed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    // Re-activates everything saved by `persist_activations`
+    // Components that can't be activated anymore (e.g. their executable is gone) are skipped
+    pub fn restore_activations(&mut self, path: &Path) {
+        let activations = match persistence::load_activations(path) {
+            Ok(activations) => activations,
+            Err(e) => {
+                warn!("Could not read persisted activations from {:?}, err {}", path, e);
+                return;
+            }
+        };
+
+        for activate_request in activations {
+            // Snapshots from before these were left out can still have them
+            if !persistence::is_restorable(&activate_request) {
+                warn!(
+                    "Skipping restore of component ({:?}), local docker archives can't be restored",
+                    activate_request
+                );
+                continue;
+            }
+
+            // Most isolation controllers only check the executable when booting, so check it up front
+            // (remote artifacts are downloaded again, so there is nothing to check)
+            if !fetch::is_remote(&activate_request.executable_file)
+                && !Path::new(&activate_request.executable_file).exists()
+            {
+                warn!(
+                    "Skipping restore of component ({:?}), its executable no longer exists",
+                    activate_request
+                );
+                continue;
+            }
+
+            let id = activate_request.id.clone();
+            let resp = self.activate(Ok(activate_request));
+            if resp.result != ActivationStatus::ActivationSuccessful {
+                warn!("Skipping restore of component ({:?}), {:?}", id, resp);
+            }
+        }
+    }
+
+    pub fn warm(&self, warm_request: Result<WarmRequest, serde_json::Error>) -> WarmResponse {
+        if let Err(e) = warm_request {
+            return WarmResponse {
+                result: WarmStatus::InvalidRequest,
+                dbg_message: e.to_string(),
+            };
+        }
+
+        // This is a safe unwrap, since we just checked if warm_request was in an error state
+        let warm_request = warm_request.unwrap();
+
+        if !self.active_components.contains_key(&warm_request.id.path) {
+            warn!(
+                "Attempt to warm a non-active component ({:?}) was foiled!",
+                warm_request
+            );
+            return WarmResponse {
+                result: WarmStatus::ComponentNotFound,
+                dbg_message: "warm failed, since the component was not activated".to_string(),
+            };
+        }
+
+        let component = &self.active_components[&warm_request.id.path];
+        match component.lock().warm() {
+            Ok(true) => {
+                info!("Successfully warmed a component ({:?})", warm_request);
+                WarmResponse {
+                    result: WarmStatus::WarmSuccessful,
+                    dbg_message: "successfully warmed".to_string(),
+                }
+            }
+            Ok(false) => WarmResponse {
+                result: WarmStatus::WarmSuccessful,
+                dbg_message: "already warm, redundant request".to_string(),
+            },
+            Err(e) => {
+                warn!("Failed to warm component ({:?}), err {}", warm_request, e);
+                WarmResponse {
+                    result: WarmStatus::FailedToStart,
+                    dbg_message: e.to_string(),
+                }
+            }
+        }
+    }
+
+    // Doesn't need the component's lock, so it reaches the component even while it's busy with a call
+    pub fn control(
+        &self,
+        control_request: Result<ControlRequest, serde_json::Error>,
+    ) -> ControlResponse {
+        let response = |result, dbg_message: String, reply| ControlResponse {
+            result,
+            dbg_message,
+            reply,
+        };
+
+        let control_request = match control_request {
+            Ok(control_request) => control_request,
+            Err(e) => return response(ControlStatus::InvalidRequest, e.to_string(), None),
+        };
+
+        if !self.active_components.contains_key(&control_request.id.path) {
+            return response(
+                ControlStatus::ComponentNotFound,
+                "control failed, since the component was not activated".to_string(),
+                None,
+            );
+        }
+
+        let message = ControlMessage {
+            control: control_request.control,
+        };
+        let sent = match self.control_channels.get(&control_request.id.path) {
+            Some(control_channel) => send_control_message(control_channel, &message),
+            None => {
+                return response(
+                    ControlStatus::NoControlPipe,
+                    "control failed, since the component was activated without a control_pipe"
+                        .to_string(),
+                    None,
+                )
+            }
+        };
+
+        match sent {
+            Ok(Some(reply)) => {
+                info!("Sent {:?} to a component ({:?})", message, control_request.id);
+                response(
+                    ControlStatus::ControlSuccessful,
+                    "control message delivered".to_string(),
+                    Some(reply),
+                )
+            }
+            Ok(None) => response(
+                ControlStatus::ComponentNotRunning,
+                "the component isn't running, so there's nothing to control".to_string(),
+                None,
+            ),
+            Err(e) => {
+                warn!(
+                    "Failed to send {:?} to a component ({:?}), err {}",
+                    message, control_request.id, e
+                );
+                response(ControlStatus::FailedToDeliver, e.to_string(), None)
+            }
+        }
+    }
+
+    // Pinging doesn't boot the component, and gives up on a component that's busy with a call after PING_TIMEOUT
+    pub fn ping(&self, path: &ComponentPath, headers: &HeaderMap) -> PingResponse {
+        let response = |result, dbg_message: String, latency_ms| PingResponse {
+            result,
+            dbg_message,
+            latency_ms,
+        };
+
+        let start = Instant::now();
+        match self
+            .lookup_component(path)
+            .map(|component| component.try_lock_for(*PING_TIMEOUT))
+        {
+            None => response(
+                PingStatus::ComponentNotFound,
+                "ping failed, since the component was not activated".to_string(),
+                None,
+            ),
+            Some(None) => response(
+                PingStatus::ComponentBusy,
+                "the component is still busy with a call".to_string(),
+                None,
+            ),
+            Some(Some(mut handle)) => match handle.ping(headers) {
+                Ok(Liveness::NotRunning) => response(
+                    PingStatus::ComponentNotRunning,
+                    "the component isn't running, it will be booted by the next call".to_string(),
+                    None,
+                ),
+                Ok(Liveness::Running) => response(
+                    PingStatus::ProcessRunning,
+                    "the component doesn't answer pings, but its process is running".to_string(),
+                    None,
+                ),
+                Ok(Liveness::Answered(_)) => response(
+                    PingStatus::PingSuccessful,
+                    "the component answered".to_string(),
+                    Some(start.elapsed().as_millis().try_into().unwrap_or(u64::MAX)),
+                ),
+                Err(e) => {
+                    warn!("Ping failed for a component ({:?}), err {}", path, e);
+                    response(PingStatus::PingFailed, e.to_string(), None)
+                }
+            },
+        }
+    }
+
+    // Components are locked one at a time, since reading a container's logs can take a while, and that shouldn't
+    // hold up calls to every other component
     pub fn logs(&self) -> LogResponse {
         let logs = self
             .active_components
             .values()
-            .map(|component| {
-                let mut locked_component = component.lock();
-                locked_component.get_component_log()
-            })
+            .map(|component| component.lock().get_component_log())
             .collect();
 
         LogResponse { logs }
     }
 
-    pub fn status(&self) -> StatusResponse {
+    // Only reads the logs of the one component, unlike `logs`
+    pub fn logs_for(&self, path: &ComponentPath) -> Result<ComponentLog, WorkerError> {
+        let component = self
+            .lookup_component(path)
+            .ok_or_else(|| WorkerErrorKind::ComponentNotFound(path.clone()))?;
+
+        Ok(component.lock().get_component_log())
+    }
+
+    pub fn activation(&self, path: &ComponentPath) -> Result<ComponentActivationResponse, WorkerError> {
+        let ar = self
+            .activations
+            .get(path)
+            .ok_or_else(|| WorkerErrorKind::ComponentNotFound(path.clone()))?;
+
+        // Listed field by field, so new activation fields aren't exposed without a thought about what they reveal
+        Ok(ComponentActivationResponse {
+            id: ar.id.clone(),
+            executable_file: ar.executable_file.clone(),
+            execution_method: ar.execution_method.clone(),
+            pipe_framing: ar.pipe_framing,
+            streaming: ar.streaming,
+            control_pipe: ar.control_pipe,
+            ping: ar.ping,
+            handlers: ar.handlers.clone(),
+            handle_options: ar.handle_options,
+            cors: ar.cors.clone(),
+            max_requests_per_second: ar.max_requests_per_second,
+            max_concurrent: ar.max_concurrent,
+            stat_window_seconds: ar.stat_window_seconds,
+            latency_percentiles: ar.latency_percentiles.clone(),
+            capture_logs: ar.capture_logs,
+            volume_container_paths: ar
+                .volumes
+                .iter()
+                .map(|volume| volume.container_path.clone())
+                .collect(),
+            platform: ar.platform.clone(),
+            network_mode: ar.network_mode.clone(),
+            run_as_user: ar.run_as_user.clone(),
+            read_only_rootfs: ar.read_only_rootfs,
+            runtime: ar.runtime.clone(),
+            base_image: ar.base_image.clone(),
+            working_dir: ar.working_dir.clone(),
+        })
+    }
+
+    pub fn raw_stats(
+        &self,
+        path: &ComponentPath,
+        limit: usize,
+    ) -> Result<RawStatsResponse, WorkerError> {
+        let status = self
+            .statuses
+            .get(path)
+            .ok_or_else(|| WorkerErrorKind::ComponentNotFound(path.clone()))?
+            .lock();
+        let (events, truncated) = status.stat_tracker.raw_events(limit);
+
+        Ok(RawStatsResponse {
+            id: status.id.clone(),
+            events,
+            truncated,
+        })
+    }
+
+    // A `detailed` status also includes per-component resource usage (which is much slower to collect)
+    pub fn status(&self, detailed: bool) -> StatusResponse {
         debug!("Processing status request by looking up system averages...");
 
         let cpu_usage = self
             .system
             .load_average()
             .map(|avg| f64::from(avg.one) / 100.0)
-            .map_err(|e| {
-                warn!("Could not get cpu usage {}", e);
-                e
-            })
-            .unwrap_or(-1.0);
+            .map_err(|e| warn!("Could not get cpu usage {}", e))
+            .ok();
 
         let memory_usage = self
             .system
             .memory()
             .map(|mem| 1.0 - mem.free.as_u64() as f64 / mem.total.as_u64() as f64)
-            .map_err(|e| {
-                warn!("Could not get memory usage {}", e);
-                e
-            })
-            .unwrap_or(-1.0);
-
-        // TODO: I believe this returns error rate since bootup. It should only cover the last minute or so
-        let packets_data = self.system.networks().map(|networks| {
-            networks
-                .values()
-                .flat_map(|network| {
-                    let stats = self.system.network_stats(&network.name);
-                    if let Err(e) = &stats {
-                        warn!(
-                            "Could not get network stats for network {}. err: {}",
-                            network.name, e
-                        )
-                    }
-                    stats.ok()
-                })
-                .fold((0, 0), |(total_packets, failed_packets), stats| {
-                    (
-                        total_packets + stats.tx_packets + stats.rx_packets,
-                        failed_packets + stats.tx_errors + stats.rx_errors,
-                    )
-                })
-        });
+            .map_err(|e| warn!("Could not get memory usage {}", e))
+            .ok();
 
-        let network_usage = match packets_data {
-            Ok((0, _)) => {
-                warn!("Zero packet info available!");
-                -1.0
-            }
-            Ok((total_packets, failed_packets)) => failed_packets as f64 / total_packets as f64,
-            Err(e) => {
-                warn!("Could not get network usage {}", e);
-                -1.0
-            }
-        };
+        let network_usage = self.network_tracker.lock().error_rate();
+        if network_usage.is_none() {
+            warn!("No network interval data available yet!");
+        }
 
         let active_components = self
-            .active_components
-            .values()
-            .map(|component_handle| component_handle.lock().get_component_status())
+            .statuses
+            .iter()
+            .map(|(path, status)| {
+                // Measuring needs the process, which a busy component is using, so it gets its last measurement
+                if detailed {
+                    if let Some(mut handle) = self.active_components.get(path).and_then(Mutex::try_lock)
+                    {
+                        handle.measure_resource_usage();
+                    }
+                }
+                status.lock().component_status(detailed)
+            })
             .collect();
 
         StatusResponse {
+            version: STATUS_RESPONSE_VERSION,
             cpu_usage,
             memory_usage,
             network_usage,
             active_components,
+            idle_pool: idle_pool_status(),
+            idle_pools: idle_pools_status(),
         }
     }
 
     // The heartbeat function is called periodically
     pub fn heartbeat(&self) {
+        self.network_tracker.lock().sample(&self.system);
+
         for component in self.active_components.values() {
             // It's okay not to block on the lock -- heartbeats have no guaranteed periodicity
             // (Plus, this is only used for component shutdown, if someone has this lock, the
@@ -245,81 +940,406 @@ pub struct ComponentHandle {
 
     component_process_wrapper: IsolatedProcessWrapper,
 
+    cors: Option<CorsConfig>,
+    handle_options: bool,
+    streaming: bool,
+    ping: bool,
+    // `None` means every function is passed along to the component
+    handlers: Option<HashSet<String>>,
+    rate_limiter: Option<RateLimiter>,
+    response_cache: ResponseCache,
+
     log_tracker: LogTracker,
-    stat_tracker: StatTracker,
+    // Shared with the manager, see `StatusSnapshot`
+    status: Arc<Mutex<StatusSnapshot>>,
 }
 
 impl ComponentHandle {
+    // `early_response` is only used by streaming components (see `stream_component`)
+    #[allow(clippy::too_many_arguments)]
     pub fn handle_component_call(
         &mut self,
         component_method: &str,
         http_verb: &Method,
         additional_path_components: &[&str],
+        headers: &HeaderMap,
         query: String,
         body: String,
+        early_response: EarlyResponse,
     ) -> Result<Response<Body>, WorkerError> {
-        let start = Instant::now();
+        let request_id = request_id(headers).to_string();
+
+        // Checked first, since a call to a function that doesn't exist can't succeed (and shouldn't boot anything)
+        if let Some(handlers) = &self.handlers {
+            if !handlers.contains(component_method) {
+                debug!(
+                    "[{}] {:?} has no handler named {:?}",
+                    request_id, self.id, component_method
+                );
+                return Err(WorkerErrorKind::HandlerNotFound(
+                    self.id.path.clone(),
+                    component_method.to_string(),
+                )
+                .into());
+            }
+        }
+
+        let origin = headers.get(ORIGIN).and_then(|o| o.to_str().ok());
+        if *http_verb == Method::OPTIONS {
+            if let Some(cors) = &self.cors {
+                return cors::preflight_response(cors, origin);
+            }
+            // Most components don't expect OPTIONS at all, so unless they ask for it we answer it here
+            if !self.handle_options {
+                return Ok(options_response());
+            }
+        }
+
+        // Rate limited calls are rejected before they get anywhere near the process
+        // (Whatever the worker answers itself, like CORS preflights, doesn't count towards the limit)
+        if let Some(rate_limiter) = &mut self.rate_limiter {
+            if !rate_limiter.try_acquire() {
+                warn!("[{}] Rate limiting a call to {:?}", request_id, self.id);
+                return Err(WorkerErrorKind::RateLimited.into());
+            }
+        }
+
+        // HEAD is answered by calling the component with GET and then dropping the body
+        // (The headers, including Content-Length, are kept as they would be for the GET)
+        let is_head = *http_verb == Method::HEAD;
+        let component_verb = if is_head { &Method::GET } else { http_verb };
+
+        if self.streaming {
+            if is_head {
+                return Ok(streaming::event_stream_head_response());
+            }
+
+            self.check_circuit_breaker(headers)?;
+            let request = component_request(
+                component_method,
+                http_verb,
+                additional_path_components,
+                headers,
+                query,
+                body,
+            );
+            let stream_resp = self.stream_component(&request, origin, early_response);
+            self.status.lock().circuit_breaker.record(stream_resp.is_ok());
+            return stream_resp;
+        }
+
+        // Cache hits never reach the process, so they aren't in its stats (and can be served while the breaker
+        // is open)
+        let cached_resp = if *component_verb == Method::GET && is_cacheable_request(headers) {
+            let cache_key = CacheKey::new(component_method, additional_path_components, &query);
+            self.response_cache.get(&cache_key)
+        } else {
+            None
+        };
+
+        let mut resp = if let Some(cached_resp) = cached_resp {
+            debug!("[{}] Serving a cached response for {:?}", request_id, self.id);
+            cached_resp
+        } else {
+            self.check_circuit_breaker(headers)?;
+
+            let call_resp = self.call_component(
+                component_method,
+                component_verb,
+                additional_path_components,
+                headers,
+                query,
+                body,
+            );
+            self.status.lock().circuit_breaker.record(match &call_resp {
+                Ok(resp) => !(resp.status().is_server_error() || resp.status() == WORKER_ERROR_STATUS),
+                Err(_) => false,
+            });
+            call_resp?
+        };
+        if let Some(cors) = &self.cors {
+            cors::add_allow_origin(cors, origin, &mut resp)?;
+        }
+        if is_head {
+            *resp.body_mut() = Body::empty();
+        }
+
+        Ok(resp)
+    }
+
+    // Components that answer pings are pinged before a probe call is let through, so a process that's still stuck
+    // fails the probe without the call ever reaching it
+    fn check_circuit_breaker(&mut self, headers: &HeaderMap) -> Result<(), WorkerError> {
+        let request_id = request_id(headers);
+        let open_error = |id: &ComponentId, cooldown_left: Duration| {
+            debug!("[{}] Circuit breaker is open for {:?}", request_id, id);
+            // Rounded up, so clients don't retry right before the cooldown is over
+            let retry_after = cooldown_left.as_secs() + 1;
+            WorkerError::from(WorkerErrorKind::CircuitOpen(retry_after))
+        };
 
-        let request = ComponentRequest {
-            called_function: component_method.to_string(),
+        let probing = self
+            .status
+            .lock()
+            .circuit_breaker
+            .try_pass()
+            .map_err(|cooldown_left| open_error(&self.id, cooldown_left))?;
+        if probing && self.ping {
+            // A component that isn't running is booted by the call, which is then the probe
+            if let Err(e) = self.ping(headers) {
+                warn!("[{}] Probe ping failed for {:?}, err {}", request_id, self.id, e);
+                let mut status = self.status.lock();
+                status.circuit_breaker.record(false);
+                return match status.circuit_breaker.try_pass() {
+                    Ok(_) => Err(e),
+                    Err(cooldown_left) => Err(open_error(&self.id, cooldown_left)),
+                };
+            }
+        }
+
+        Ok(())
+    }
 
-            http_method: http_verb.to_string(),
-            path: additional_path_components.join("/"),
-            request_arguments: query,
-            request_body: body,
+    // Checks the component is responsive, see `IsolatedProcessWrapper::ping`
+    pub fn ping(&mut self, headers: &HeaderMap) -> Result<Liveness, WorkerError> {
+        let request = if self.ping {
+            let request = component_request(
+                PING_FUNCTION,
+                &Method::GET,
+                &[],
+                headers,
+                String::new(),
+                String::new(),
+            );
+            Some(encode_request(&request)?)
+        } else {
+            None
         };
 
-        debug!("Firing component request {:?}", request);
+        let liveness = self
+            .component_process_wrapper
+            .ping(request.as_deref(), &mut self.log_tracker)?;
+        // Anything that isn't a valid response means the component is confused, even if it did answer
+        if let Liveness::Answered(response) = &liveness {
+            decode_response(response.clone(), request_id(headers))?;
+        }
+
+        Ok(liveness)
+    }
+
+    fn call_component(
+        &mut self,
+        component_method: &str,
+        http_verb: &Method,
+        additional_path_components: &[&str],
+        headers: &HeaderMap,
+        query: String,
+        body: String,
+    ) -> Result<Response<Body>, WorkerError> {
+        let start = Instant::now();
+        let cache_key = CacheKey::new(component_method, additional_path_components, &query);
+        let request_bytes = body.len();
+
+        let request = component_request(
+            component_method,
+            http_verb,
+            additional_path_components,
+            headers,
+            query,
+            body,
+        );
 
-        // Our communication with subprocesses has protocol calls for one percent encoded JSON per request/response
-        // We handle this deserialization here to keep it general
-        let serialized_request = serde_json::to_string(&request)?;
-        let encoded_request = utf8_percent_encode(&serialized_request, NON_ALPHANUMERIC);
+        debug!("[{}] Firing component request {:?}", request.request_id, request);
 
-        let encoded_response = self
+        self.record_access();
+        let (encoded_response, cold) = self
             .component_process_wrapper
-            .query_process(&encoded_request.to_string(), &mut self.log_tracker)?;
-        let serialized_response = percent_decode_str(&encoded_response).decode_utf8()?.to_string();
-        let response: ComponentResponse = serde_json::from_str(&serialized_response)?;
+            .query_process(&encode_request(&request)?, &mut self.log_tracker)?;
+        let response = decode_response(encoded_response, &request.request_id)?;
 
-        debug!("Got component response {:?}", response);
+        debug!("[{}] Got component response {:?}", request.request_id, response);
 
+        // Informational (1xx) codes can't be the final response to a request
+        if !(200..=599).contains(&response.http_response_code) {
+            return Err(WorkerErrorKind::InvalidComponentResponse(format!(
+                "http_response_code {} is not in the range 200-599",
+                response.http_response_code
+            ))
+            .into());
+        }
         let resp_code: u16 = response.http_response_code.try_into()?;
+        let bodyless = forbids_body(resp_code);
 
         if let Some(m) = response.error_message {
-            if !m.is_empty() {
-                let resp = Response::builder().status(resp_code).body(Body::from(m)).unwrap();
+            if !m.is_empty() && !bodyless {
+                let mut resp = Response::builder().status(resp_code).body(Body::from(m)).unwrap();
+                set_default_content_type(&mut resp, PLAIN_TEXT_CONTENT_TYPE);
+                set_status_reason(&mut resp, response.status_reason, &request.request_id);
                 return Ok(resp);
             }
         }
 
-        let resp_body = response.response_body;
+        // Only successful GETs are cached, since anything else might not be safe to repeat
+        let cache_ttl = response
+            .cache_ttl_seconds
+            .filter(|ttl| *ttl > 0 && *http_verb == Method::GET && is_cacheable_request(headers))
+            .filter(|_| StatusCode::from_u16(resp_code).map_or(false, |code| code.is_success()));
+
+        let resp_body = if bodyless {
+            if !response.response_body.is_empty() {
+                debug!(
+                    "[{}] Dropping the body of a {} response",
+                    request.request_id, resp_code
+                );
+            }
+            String::new()
+        } else {
+            response.response_body
+        };
         let response_bytes = resp_body.len();
-        let resp = Response::builder()
-            .status(resp_code)
-            .body(Body::from(resp_body))
-            .unwrap();
+        let cached_body = cache_ttl.map(|_| Bytes::from(resp_body.clone()));
+        let mut resp = if bodyless {
+            Response::builder().status(resp_code).body(Body::empty()).unwrap()
+        } else {
+            let content_type = default_content_type(&resp_body);
+            let mut resp = Response::builder()
+                .status(resp_code)
+                .header(CONTENT_LENGTH, response_bytes)
+                .body(spill::response_body(resp_body)?)
+                .unwrap();
+            set_default_content_type(&mut resp, content_type);
+            resp
+        };
+        set_location(&mut resp, response.location, &request.request_id);
+        set_status_reason(&mut resp, response.status_reason, &request.request_id);
+
+        if let (Some(ttl), Some(cached_body)) = (cache_ttl, cached_body) {
+            resp.headers_mut()
+                .insert(CACHE_CONTROL, HeaderValue::try_from(format!("max-age={}", ttl))?);
+            self.response_cache.insert(
+                cache_key,
+                resp.status(),
+                resp.headers().clone(),
+                cached_body,
+                Duration::from_secs(u64::from(ttl)),
+            );
+        }
 
         let processing_duration = start.elapsed();
-        self.stat_tracker.add_stat_event(
+        debug!(
+            "[{}] Component call took {:?} ({} request bytes, {} response bytes)",
+            request.request_id, processing_duration, request_bytes, response_bytes
+        );
+        self.status.lock().stat_tracker.add_stat_event(
             processing_duration.as_millis().try_into()?,
+            request_bytes.try_into()?,
             response_bytes.try_into()?,
+            cold,
+            response_color(resp.status()),
         );
 
         Ok(resp)
     }
 
-    pub fn get_component_status(&mut self) -> ComponentStatus {
-        let component_stats = self.stat_tracker.get_component_stats();
+    // Streaming components answer with a series of messages instead of a single response, which are relayed to
+    // the client as server-sent events while the call is still going
+    // The response is handed to the client through `early_response`, so the one returned here only reflects
+    // how the stream went (for the status color)
+    fn stream_component(
+        &mut self,
+        request: &ComponentRequest,
+        origin: Option<&str>,
+        early_response: EarlyResponse,
+    ) -> Result<Response<Body>, WorkerError> {
+        let start = Instant::now();
+        let request_bytes = request.request_body.len();
+
+        debug!(
+            "[{}] Firing streaming component request {:?}",
+            request.request_id, request
+        );
+
+        // The first message is read before responding, so a component that fails right away still gets an error
+        // status instead of an empty stream
+        self.record_access();
+        let (encoded_message, cold) = self
+            .component_process_wrapper
+            .query_process(&encode_request(request)?, &mut self.log_tracker)?;
+        let first_message = decode_stream_message(&encoded_message)?;
+
+        let (mut resp, events) = streaming::event_stream_response();
+        if let Some(cors) = &self.cors {
+            cors::add_allow_origin(cors, origin, &mut resp)?;
+        }
+        let client_connected = early_response.send(resp);
+
+        let response_bytes = match self.relay_events(first_message, &events, client_connected) {
+            Ok(response_bytes) => response_bytes,
+            Err(e) => {
+                warn!("[{}] Component stream failed, err {}", request.request_id, e);
+                events.abort();
+                return Err(e);
+            }
+        };
+
+        let processing_duration = start.elapsed();
+        debug!(
+            "[{}] Component stream took {:?} ({} request bytes, {} response bytes)",
+            request.request_id, processing_duration, request_bytes, response_bytes
+        );
+        self.status.lock().stat_tracker.add_stat_event(
+            processing_duration.as_millis().try_into()?,
+            request_bytes.try_into()?,
+            response_bytes.try_into()?,
+            cold,
+            StatusColor::Green,
+        );
+
+        Ok(Response::new(Body::empty()))
+    }
+
+    // Returns how many bytes of data were relayed
+    // If the client goes away the rest of the stream is still read (and dropped), so the next call doesn't find
+    // this call's messages in the pipe
+    fn relay_events(
+        &mut self,
+        mut message: ComponentStreamMessage,
+        events: &EventSink,
+        mut client_connected: bool,
+    ) -> Result<usize, WorkerError> {
+        let mut response_bytes = 0;
+        loop {
+            if client_connected {
+                response_bytes += message.data.as_ref().map_or(0, String::len);
+                client_connected = events.send(&message);
+            }
+            if message.done {
+                return Ok(response_bytes);
+            }
 
-        ComponentStatus {
-            id: self.id.clone(),
-            component_stats,
+            self.record_access();
+            let encoded_message = self
+                .component_process_wrapper
+                .read_next_message(&mut self.log_tracker)?;
+            message = decode_stream_message(&encoded_message)?;
         }
     }
 
+    // Kept in step with the process wrapper's own `last_accessed`, which status requests can't get at mid-call
+    fn record_access(&self) {
+        self.status.lock().last_accessed = Instant::now();
+    }
+
+    // Polls the process, and keeps the result for detailed status requests
+    pub fn measure_resource_usage(&mut self) {
+        let usage = self.component_process_wrapper.resource_usage();
+        self.status.lock().resource_usage = usage;
+    }
+
     pub fn get_component_log(&mut self) -> ComponentLog {
-        let (dedup_number, log) = self.log_tracker.get_contents();
+        let (dedup_number, captured) = self.log_tracker.get_contents();
+        // `Ok(None)` means there are no logs yet, which is different from failing to get them
+        let log = captured.and_then(|captured| self.component_process_wrapper.logs(captured));
 
         match log {
             Ok(log) => ComponentLog {
@@ -344,8 +1364,13 @@ impl ComponentHandle {
         }
     }
 
+    pub fn warm(&mut self) -> Result<bool, WorkerError> {
+        self.record_access();
+        self.component_process_wrapper.warm(&mut self.log_tracker)
+    }
+
     pub fn set_color(&mut self, color: StatusColor) {
-        self.stat_tracker.set_color(color)
+        self.status.lock().stat_tracker.set_color(color)
     }
 
     // The heartbeat function is called periodically
@@ -353,3 +1378,648 @@ impl ComponentHandle {
         self.component_process_wrapper.heartbeat()
     }
 }
+
+// Components can't set headers yet, so we guess a content type to stop browsers from sniffing one
+fn default_content_type(body: &str) -> &'static str {
+    if serde_json::from_str::<serde_json::Value>(body).is_ok() {
+        JSON_CONTENT_TYPE
+    } else {
+        PLAIN_TEXT_CONTENT_TYPE
+    }
+}
+
+// Answers OPTIONS for components that don't handle it themselves
+fn options_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ALLOW, ALLOWED_METHODS)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn component_request(
+    component_method: &str,
+    http_verb: &Method,
+    additional_path_components: &[&str],
+    headers: &HeaderMap,
+    query: String,
+    body: String,
+) -> ComponentRequest {
+    let path_segments = additional_path_components
+        .iter()
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().to_string())
+        .collect();
+    let query_pairs = parse_query_pairs(&query);
+
+    ComponentRequest {
+        called_function: component_method.to_string(),
+        request_id: request_id(headers).to_string(),
+        traceparent: traceparent(headers).map(str::to_string),
+        client_ip: client_ip(headers).map(str::to_string),
+
+        http_method: http_verb.to_string(),
+        path: additional_path_components.join("/"),
+        request_arguments: query,
+        request_body: body,
+
+        path_segments,
+        query_pairs,
+    }
+}
+
+// Our communication with subprocesses has protocol calls for one percent encoded JSON per request/response
+// We handle this serialization here to keep it general
+fn encode_request(request: &ComponentRequest) -> Result<String, WorkerError> {
+    let serialized_request = serde_json::to_string(request)?;
+    Ok(utf8_percent_encode(&serialized_request, NON_ALPHANUMERIC).to_string())
+}
+
+// The intermediate forms are dropped as soon as possible, since large responses exist several times over
+fn decode_response(
+    encoded_response: String,
+    request_id: &str,
+) -> Result<ComponentResponse, WorkerError> {
+    let serialized_response = percent_decode_str(&encoded_response).decode_utf8()?.to_string();
+    drop(encoded_response);
+
+    serde_json::from_str(&serialized_response).map_err(|e| {
+        let excerpt: String = serialized_response
+            .chars()
+            .take(MAX_MALFORMED_RESPONSE_EXCERPT_CHARS)
+            .collect();
+        warn!(
+            "[{}] Component sent a malformed response ({}): {:?}",
+            request_id, e, excerpt
+        );
+        WorkerErrorKind::InvalidComponentResponse(format!("{} in {:?}", e, excerpt)).into()
+    })
+}
+
+// Control messages and their replies are percent encoded too, the reply is passed on as whatever text it decodes to
+fn send_control_message(
+    control_channel: &ControlChannel,
+    message: &ControlMessage,
+) -> Result<Option<String>, WorkerError> {
+    let serialized_message = serde_json::to_string(message)?;
+    let reply =
+        control_channel.send(&utf8_percent_encode(&serialized_message, NON_ALPHANUMERIC).to_string())?;
+    reply
+        .map(|reply| Ok(percent_decode_str(&reply).decode_utf8()?.to_string()))
+        .transpose()
+}
+
+fn decode_stream_message(encoded_message: &str) -> Result<ComponentStreamMessage, WorkerError> {
+    let serialized_message = percent_decode_str(encoded_message).decode_utf8()?;
+    Ok(serde_json::from_str(&serialized_message)?)
+}
+
+// 204 and 304 responses can't have a body, so none is sent (nor the headers describing one), whatever the
+// component put in `response_body` or `error_message`
+fn forbids_body(resp_code: u16) -> bool {
+    resp_code == StatusCode::NO_CONTENT.as_u16() || resp_code == StatusCode::NOT_MODIFIED.as_u16()
+}
+
+// Only fills in the content type if nothing else has set one
+fn set_default_content_type(resp: &mut Response<Body>, content_type: &'static str) {
+    resp.headers_mut()
+        .entry(CONTENT_TYPE)
+        .or_insert(HeaderValue::from_static(content_type));
+}
+
+// Redirects are the only responses a component can set a location on
+fn set_location(resp: &mut Response<Body>, location: Option<String>, request_id: &str) {
+    if !resp.status().is_redirection() {
+        return;
+    }
+
+    match location.map(HeaderValue::try_from) {
+        Some(Ok(location)) => {
+            resp.headers_mut().insert(LOCATION, location);
+        }
+        Some(Err(e)) => warn!(
+            "[{}] Component redirected to an invalid location: {}",
+            request_id, e
+        ),
+        None => warn!(
+            "[{}] Component returned a {} without a location",
+            request_id,
+            resp.status()
+        ),
+    }
+}
+
+// hyper always writes the code's standard reason phrase on the status line (and HTTP/2 has none at all), so a
+// component's own reason is passed along in a header instead
+fn set_status_reason(resp: &mut Response<Body>, status_reason: Option<String>, request_id: &str) {
+    match status_reason
+        .filter(|reason| !reason.is_empty())
+        .map(HeaderValue::try_from)
+    {
+        Some(Ok(reason)) => {
+            resp.headers_mut()
+                .insert(HeaderName::from_static(X_STATUS_REASON), reason);
+        }
+        Some(Err(e)) => warn!("[{}] Component sent an invalid status reason: {}", request_id, e),
+        None => {}
+    }
+}
+
+// Parses a query string the same way browsers/url libraries do (application/x-www-form-urlencoded)
+// Repeated keys are kept as separate pairs, in order
+pub fn parse_query_pairs(query: &str) -> Vec<(String, String)> {
+    let decode = |s: &str| {
+        percent_decode_str(&s.replace('+', " "))
+            .decode_utf8_lossy()
+            .to_string()
+    };
+
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut key_value = pair.splitn(2, '=');
+            let key = key_value.next().unwrap_or("");
+            let value = key_value.next().unwrap_or("");
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::fs_utils;
+
+    const STRESS_THREADS: usize = 6;
+    const STRESS_ROUNDS: usize = 50;
+    // Far longer than the stress test needs, so running out means something deadlocked
+    const DEADLOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn activate_request(executable_file: &str, repo: &str) -> ActivateRequest {
+        serde_json::from_value(json!({
+            "id": {"user": "stress", "repo": repo, "hash": "h"},
+            "executable_file": executable_file,
+            "execution_method": "python-unsafe",
+        }))
+        .unwrap()
+    }
+
+    // Each thread activates and deactivates a window of components that overlaps with its neighbours' (some in
+    // reverse order), and reads every component's logs in between
+    #[test]
+    fn overlapping_activations_and_locks_dont_deadlock() {
+        let dir = tempfile::tempdir().unwrap();
+        let executable_file = dir.path().join("component.py");
+        fs::write(&executable_file, "").unwrap();
+        let executable_file = executable_file.to_string_lossy().into_owned();
+
+        let manager = Arc::new(RwLock::new(ComponentManager::new()));
+        let repos: Vec<String> = (0..STRESS_THREADS + 2).map(|i| format!("r{}", i)).collect();
+
+        let (done_sender, done) = channel();
+        for i in 0..STRESS_THREADS {
+            let manager = manager.clone();
+            let executable_file = executable_file.clone();
+            let mut window = repos[i..i + 3].to_vec();
+            if i % 2 == 1 {
+                window.reverse();
+            }
+            let done_sender = done_sender.clone();
+
+            thread::spawn(move || {
+                for _ in 0..STRESS_ROUNDS {
+                    for repo in &window {
+                        let resp = ComponentManager::activate_shared(
+                            &manager,
+                            Ok(activate_request(&executable_file, repo)),
+                        );
+                        assert_ne!(resp.result, ActivationStatus::InvalidRequest, "{:?}", resp);
+                    }
+
+                    manager.read().logs();
+
+                    for repo in &window {
+                        manager.write().deactivate(Ok(DeactivateRequest {
+                            id: activate_request(&executable_file, repo).id,
+                        }));
+                    }
+                }
+                done_sender.send(()).unwrap();
+            });
+        }
+
+        for _ in 0..STRESS_THREADS {
+            done.recv_timeout(DEADLOCK_TIMEOUT)
+                .expect("a stress thread got stuck (or panicked)");
+        }
+
+        // Whatever order things happened in, the manager's maps have to agree with each other
+        let manager = manager.read();
+        let active: HashSet<&ComponentPath> = manager.active_components.keys().collect();
+        assert_eq!(active, manager.activations.keys().collect());
+        assert_eq!(active, manager.call_limiters.keys().collect());
+        assert_eq!(active, manager.kill_switches.keys().collect());
+        assert!(manager.reservations.is_empty());
+        assert_eq!(active, manager.statuses.keys().collect());
+    }
+
+    // A call holds its component's lock until the component answers, which can take as long as the call timeout
+    #[test]
+    fn status_doesnt_wait_for_busy_components() {
+        let dir = tempfile::tempdir().unwrap();
+        let executable_file = dir.path().join("component.py");
+        fs::write(&executable_file, "").unwrap();
+
+        let manager = Arc::new(RwLock::new(ComponentManager::new()));
+        let resp = ComponentManager::activate_shared(
+            &manager,
+            Ok(activate_request(&executable_file.to_string_lossy(), "busy")),
+        );
+        assert_eq!(resp.result, ActivationStatus::ActivationSuccessful, "{:?}", resp);
+        let path = resp.id.unwrap().path;
+
+        let locked_manager = manager.read();
+        let _call = locked_manager.active_components[&path].lock();
+
+        let (status_sender, status) = channel();
+        let status_manager = manager.clone();
+        thread::spawn(move || {
+            let manager = status_manager.read();
+            status_sender
+                .send((manager.status(false), manager.status(true)))
+                .unwrap();
+        });
+
+        let (status, detailed_status) = status
+            .recv_timeout(DEADLOCK_TIMEOUT)
+            .expect("status waited on the busy component");
+        for status in &[status, detailed_status] {
+            assert_eq!(status.active_components.len(), 1);
+            assert_eq!(status.active_components[0].id.path, path);
+        }
+    }
+
+    // Only successful GETs are cached, and a hit must not reach the process at all
+    #[test]
+    fn cached_responses_skip_the_process() {
+        // Responses are spilled into the worker's temp directory
+        fs_utils::prepare_temp_dir();
+        let dir = tempfile::tempdir().unwrap();
+        let calls_file = dir.path().join("calls");
+        let executable_file = dir.path().join("component.py");
+        fs::write(
+            &executable_file,
+            format!(
+                r#"
+import sys, json, urllib.parse
+fi = open(sys.argv[-2]); fo = open(sys.argv[-1], 'w')
+for line in fi:
+    open({:?}, 'a').write('call\n')
+    resp = {{"response_body": "hi", "http_response_code": 200, "error_message": None, "cache_ttl_seconds": 60}}
+    fo.write(urllib.parse.quote(json.dumps(resp)) + '\n'); fo.flush()
+"#,
+                calls_file
+            ),
+        )
+        .unwrap();
+
+        let manager = Arc::new(RwLock::new(ComponentManager::new()));
+        let resp = ComponentManager::activate_shared(
+            &manager,
+            Ok(activate_request(&executable_file.to_string_lossy(), "cached")),
+        );
+        assert_eq!(resp.result, ActivationStatus::ActivationSuccessful, "{:?}", resp);
+        let path = resp.id.unwrap().path;
+
+        let call = |verb: &Method, headers: &HeaderMap| {
+            let manager = manager.read();
+            let resp = manager.active_components[&path]
+                .lock()
+                .handle_component_call(
+                    "f",
+                    verb,
+                    &[],
+                    headers,
+                    String::new(),
+                    String::new(),
+                    EarlyResponse::new().0,
+                )
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            resp
+        };
+        let process_calls = || fs::read_to_string(&calls_file).unwrap().lines().count();
+
+        let no_headers = HeaderMap::new();
+        assert!(!call(&Method::GET, &no_headers).headers().contains_key("age"));
+        assert_eq!(process_calls(), 1);
+        assert!(call(&Method::GET, &no_headers).headers().contains_key("age"));
+        assert_eq!(process_calls(), 1);
+        // A POST is never served from the cache
+        call(&Method::POST, &no_headers);
+        assert_eq!(process_calls(), 2);
+        // Nor are calls with credentials, whose response might only be meant for that caller
+        let mut credentials = HeaderMap::new();
+        credentials.insert("authorization", HeaderValue::from_static("Bearer secret"));
+        call(&Method::GET, &credentials);
+        call(&Method::GET, &credentials);
+        assert_eq!(process_calls(), 4);
+
+        let stats = manager.read().raw_stats(&path, 10).unwrap();
+        assert_eq!(stats.events.len(), 4);
+    }
+
+    #[test]
+    fn activations_are_reported_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let executable_file = dir.path().join("component.py");
+        fs::write(&executable_file, "").unwrap();
+
+        let mut request = activate_request(&executable_file.to_string_lossy(), "reported");
+        request.control_pipe = true;
+        request.ping = false;
+        request.working_dir = Some(dir.path().to_string_lossy().into_owned());
+
+        let manager = Arc::new(RwLock::new(ComponentManager::new()));
+        let resp = ComponentManager::activate_shared(&manager, Ok(request.clone()));
+        assert_eq!(resp.result, ActivationStatus::ActivationSuccessful, "{:?}", resp);
+
+        let activation = manager.read().activation(&request.id.path).unwrap();
+        assert_eq!(activation.id, request.id);
+        assert!(activation.control_pipe);
+        assert!(!activation.ping);
+        assert_eq!(activation.working_dir, request.working_dir);
+    }
+
+    #[test]
+    fn query_strings_are_decoded_like_forms() {
+        assert_eq!(
+            parse_query_pairs("a=1&b=two+words&c=%26%3D%F0%9F%98%80&a=2"),
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "two words".to_string()),
+                ("c".to_string(), "&=\u{1F600}".to_string()),
+                ("a".to_string(), "2".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse_query_pairs("flag&&empty=&=value&x=a=b"),
+            vec![
+                ("flag".to_string(), String::new()),
+                ("empty".to_string(), String::new()),
+                (String::new(), "value".to_string()),
+                ("x".to_string(), "a=b".to_string()),
+            ]
+        );
+        assert!(parse_query_pairs("").is_empty());
+        // Invalid UTF-8 is replaced instead of failing the whole query
+        assert_eq!(parse_query_pairs("k=%FF")[0].1, "\u{FFFD}");
+    }
+
+    #[test]
+    fn only_204_and_304_forbid_a_body() {
+        assert!(forbids_body(204));
+        assert!(forbids_body(304));
+        for &code in &[200, 201, 205, 301, 302, 400, 404, 500] {
+            assert!(!forbids_body(code), "{}", code);
+        }
+    }
+
+    // Appends the pid of the process that answered to `pids_file`, so tests can tell if it was replaced
+    fn pid_component(dir: &Path, pids_file: &Path) -> String {
+        let executable_file = dir.join("component.py");
+        fs::write(
+            &executable_file,
+            format!(
+                r#"
+import os, sys, json, urllib.parse
+fi = open(sys.argv[-2]); fo = open(sys.argv[-1], 'w')
+for line in fi:
+    open({:?}, 'a').write(str(os.getpid()) + '\n')
+    resp = {{"response_body": "", "http_response_code": 200, "error_message": None}}
+    fo.write(urllib.parse.quote(json.dumps(resp)) + '\n'); fo.flush()
+"#,
+                pids_file
+            ),
+        )
+        .unwrap();
+        executable_file.to_string_lossy().to_string()
+    }
+
+    fn call_and_get_pid(
+        manager: &RwLock<ComponentManager>,
+        path: &ComponentPath,
+        pids_file: &Path,
+    ) -> String {
+        let resp = manager.read().active_components[path]
+            .lock()
+            .handle_component_call(
+                "f",
+                &Method::GET,
+                &[],
+                &HeaderMap::new(),
+                String::new(),
+                String::new(),
+                EarlyResponse::new().0,
+            )
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        fs::read_to_string(pids_file)
+            .unwrap()
+            .lines()
+            .last()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn reactivating_the_same_hash_keeps_the_process() {
+        fs_utils::prepare_temp_dir();
+        let dir = tempfile::tempdir().unwrap();
+        let pids_file = dir.path().join("pids");
+        let executable_file = pid_component(dir.path(), &pids_file);
+
+        let manager = Arc::new(RwLock::new(ComponentManager::new()));
+        let activation = || {
+            ComponentManager::activate_shared(&manager, Ok(activate_request(&executable_file, "same")))
+        };
+        let resp = activation();
+        assert_eq!(resp.result, ActivationStatus::ActivationSuccessful, "{:?}", resp);
+        let path = resp.id.clone().unwrap().path;
+        let pid = call_and_get_pid(&manager, &path, &pids_file);
+
+        let again = activation();
+        assert_eq!(again.result, ActivationStatus::AlreadyUpToDate, "{:?}", again);
+        assert_eq!(again.id, resp.id);
+        assert_eq!(call_and_get_pid(&manager, &path, &pids_file), pid);
+    }
+
+    #[test]
+    fn reactivating_a_new_hash_replaces_the_process() {
+        fs_utils::prepare_temp_dir();
+        let dir = tempfile::tempdir().unwrap();
+        let pids_file = dir.path().join("pids");
+        let executable_file = pid_component(dir.path(), &pids_file);
+
+        let manager = Arc::new(RwLock::new(ComponentManager::new()));
+        let resp = ComponentManager::activate_shared(
+            &manager,
+            Ok(activate_request(&executable_file, "replaced")),
+        );
+        assert_eq!(resp.result, ActivationStatus::ActivationSuccessful, "{:?}", resp);
+        let path = resp.id.unwrap().path;
+        let old_pid = call_and_get_pid(&manager, &path, &pids_file);
+
+        let mut new_hash = activate_request(&executable_file, "replaced");
+        new_hash.id.hash = "h2".to_string();
+        let resp = ComponentManager::activate_shared(&manager, Ok(new_hash.clone()));
+        assert_eq!(resp.result, ActivationStatus::ActivationSuccessful, "{:?}", resp);
+        assert_eq!(resp.id, Some(new_hash.id.clone()));
+        assert_eq!(manager.read().activations[&path].id, new_hash.id);
+
+        // The old process is stopped as it's replaced (it's gone, or a zombie until its parent reaps it)
+        let old_process_running = || {
+            fs::read_to_string(format!("/proc/{}/stat", old_pid))
+                .map_or(false, |stat| !stat.contains(") Z "))
+        };
+        let start = Instant::now();
+        while old_process_running() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!old_process_running());
+        assert_ne!(call_and_get_pid(&manager, &path, &pids_file), old_pid);
+    }
+
+    #[test]
+    fn malformed_responses_are_reported_with_an_excerpt() {
+        let malformed = format!(r#"{{"response_body": "{}"#, "x".repeat(1000));
+        let encoded = utf8_percent_encode(&malformed, NON_ALPHANUMERIC).to_string();
+
+        let e = decode_response(encoded, "r").unwrap_err();
+        match e.kind() {
+            WorkerErrorKind::InvalidComponentResponse(problem) => {
+                assert!(problem.contains(r#"{\"response_body\": \"xxx"#), "{}", problem);
+                // Only the start of the response is included
+                assert!(
+                    problem.len() < MAX_MALFORMED_RESPONSE_EXCERPT_CHARS + 200,
+                    "{}",
+                    problem
+                );
+            }
+            _ => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    // A status code outside of 200-599 is the component's mistake, so it's an error rather than a panic
+    #[test]
+    fn out_of_range_status_codes_are_rejected() {
+        fs_utils::prepare_temp_dir();
+        let dir = tempfile::tempdir().unwrap();
+        let executable_file = dir.path().join("component.py");
+        fs::write(
+            &executable_file,
+            r#"
+import sys, json, urllib.parse
+fi = open(sys.argv[-2]); fo = open(sys.argv[-1], 'w')
+for line in fi:
+    req = json.loads(urllib.parse.unquote(line.strip()))
+    code = int(dict(req["query_pairs"])["code"])
+    resp = {"response_body": "", "http_response_code": code, "error_message": None}
+    fo.write(urllib.parse.quote(json.dumps(resp)) + '\n'); fo.flush()
+"#,
+        )
+        .unwrap();
+
+        let manager = Arc::new(RwLock::new(ComponentManager::new()));
+        let resp = ComponentManager::activate_shared(
+            &manager,
+            Ok(activate_request(&executable_file.to_string_lossy(), "codes")),
+        );
+        assert_eq!(resp.result, ActivationStatus::ActivationSuccessful, "{:?}", resp);
+        let path = resp.id.unwrap().path;
+
+        let call = |code: u32| {
+            manager.read().active_components[&path]
+                .lock()
+                .handle_component_call(
+                    "f",
+                    &Method::GET,
+                    &[],
+                    &HeaderMap::new(),
+                    format!("code={}", code),
+                    String::new(),
+                    EarlyResponse::new().0,
+                )
+        };
+
+        for code in [99, 600] {
+            let e = call(code).unwrap_err();
+            assert!(
+                matches!(e.kind(), WorkerErrorKind::InvalidComponentResponse(_)),
+                "{:?}",
+                e
+            );
+        }
+        assert_eq!(call(200).unwrap().status(), StatusCode::OK);
+    }
+
+    // A browser preflights every cross-origin call, which would otherwise use up half of the limit
+    #[test]
+    fn preflights_dont_count_towards_the_rate_limit() {
+        fs_utils::prepare_temp_dir();
+        let dir = tempfile::tempdir().unwrap();
+        let executable_file = dir.path().join("component.py");
+        fs::write(
+            &executable_file,
+            r#"
+import sys, json, urllib.parse
+fi = open(sys.argv[-2]); fo = open(sys.argv[-1], 'w')
+for line in fi:
+    resp = {"response_body": "", "http_response_code": 200, "error_message": None}
+    fo.write(urllib.parse.quote(json.dumps(resp)) + '\n'); fo.flush()
+"#,
+        )
+        .unwrap();
+
+        let mut req = activate_request(&executable_file.to_string_lossy(), "preflights");
+        req.max_requests_per_second = Some(1);
+        req.cors = Some(CorsConfig {
+            origins: vec!["*".to_string()],
+            methods: vec!["GET".to_string()],
+            headers: Vec::new(),
+        });
+        let manager = Arc::new(RwLock::new(ComponentManager::new()));
+        let resp = ComponentManager::activate_shared(&manager, Ok(req));
+        assert_eq!(resp.result, ActivationStatus::ActivationSuccessful, "{:?}", resp);
+        let path = resp.id.unwrap().path;
+
+        let call = |http_verb: &Method| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ORIGIN, HeaderValue::from_static("https://example.com"));
+            manager.read().active_components[&path]
+                .lock()
+                .handle_component_call(
+                    "f",
+                    http_verb,
+                    &[],
+                    &headers,
+                    String::new(),
+                    String::new(),
+                    EarlyResponse::new().0,
+                )
+        };
+
+        for _ in 0..3 {
+            assert_eq!(call(&Method::OPTIONS).unwrap().status(), StatusCode::NO_CONTENT);
+        }
+        // With a limit of 1, this only gets through if none of the preflights used up the token
+        assert_eq!(call(&Method::GET).unwrap().status(), StatusCode::OK);
+    }
+}
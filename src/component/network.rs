@@ -0,0 +1,69 @@
+use systemstat::{Platform, System};
+
+// The network counters reported by the OS are cumulative since boot, so instead we keep the last two
+// samples (taken every heartbeat) and report the error rate over the interval between them
+#[derive(Debug, Default)]
+pub struct NetworkTracker {
+    previous_sample: Option<NetworkSample>,
+    latest_sample: Option<NetworkSample>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct NetworkSample {
+    total_packets: u64,
+    failed_packets: u64,
+}
+
+impl NetworkTracker {
+    pub fn sample(&mut self, system: &System) {
+        let packets_data = system.networks().map(|networks| {
+            networks
+                .values()
+                .flat_map(|network| {
+                    let stats = system.network_stats(&network.name);
+                    if let Err(e) = &stats {
+                        warn!(
+                            "Could not get network stats for network {}. err: {}",
+                            network.name, e
+                        )
+                    }
+                    stats.ok()
+                })
+                .fold((0, 0), |(total_packets, failed_packets), stats| {
+                    (
+                        total_packets + stats.tx_packets + stats.rx_packets,
+                        failed_packets + stats.tx_errors + stats.rx_errors,
+                    )
+                })
+        });
+
+        match packets_data {
+            Ok((total_packets, failed_packets)) => {
+                self.previous_sample = self.latest_sample.take();
+                self.latest_sample = Some(NetworkSample {
+                    total_packets,
+                    failed_packets,
+                });
+            }
+            Err(e) => warn!("Could not sample network usage {}", e),
+        }
+    }
+
+    // Returns `None` if we don't have two good samples to compare
+    pub fn error_rate(&self) -> Option<f64> {
+        let previous = self.previous_sample?;
+        let latest = self.latest_sample?;
+
+        // If an interface restarts its counters reset, so the deltas would be negative
+        // In that case we just have to wait for the next interval
+        let total_packets = latest.total_packets.checked_sub(previous.total_packets)?;
+        let failed_packets = latest.failed_packets.checked_sub(previous.failed_packets)?;
+
+        if total_packets == 0 {
+            // No packets means nothing failed
+            Some(0.0)
+        } else {
+            Some(failed_packets as f64 / total_packets as f64)
+        }
+    }
+}
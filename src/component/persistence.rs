@@ -0,0 +1,92 @@
+use std::fs::{create_dir_all, read_to_string, rename, write};
+use std::path::Path;
+
+use crate::component::fetch;
+use crate::error::WorkerError;
+use crate::model::{ActivateRequest, ExecutionMethod};
+
+// A local docker archive is deleted once it's loaded, and its image is removed when the component stops (shutting
+// down included), so there's nothing left to restore it from. Ones from a URL are just downloaded again.
+pub fn is_restorable(ar: &ActivateRequest) -> bool {
+    ar.execution_method != ExecutionMethod::DockerArchive || fetch::is_remote(&ar.executable_file)
+}
+
+// Only the activation requests are saved, processes are booted lazily after a restore like always
+pub fn save_activations(path: &Path, activations: &[&ActivateRequest]) -> Result<(), WorkerError> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+
+    // Write to a temporary file then rename, so a crash mid-write can't leave a truncated snapshot
+    let temp_path = path.with_extension("tmp");
+    write(&temp_path, serde_json::to_string(activations)?)?;
+    rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+// A missing snapshot just means there is nothing to restore (e.g. the first time the worker runs)
+pub fn load_activations(path: &Path) -> Result<Vec<ActivateRequest>, WorkerError> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    Ok(serde_json::from_str(&read_to_string(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn activate_request(execution_method: &str, executable_file: &str) -> ActivateRequest {
+        serde_json::from_value(json!({
+            "id": {"user": "u", "repo": execution_method, "hash": "h"},
+            "executable_file": executable_file,
+            "execution_method": execution_method,
+            "max_concurrent": 3,
+            "volumes": [{"host_path": "/srv/data", "container_path": "/data", "read_only": true}],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn saved_activations_load_back_the_same() {
+        let dir = tempfile::tempdir().unwrap();
+        // The parent directory doesn't exist yet
+        let path = dir.path().join("state").join("activations.json");
+        let activations = vec![
+            activate_request("python-unsafe", "/srv/component.py"),
+            activate_request("docker-build", "/srv/context"),
+        ];
+
+        save_activations(&path, &activations.iter().collect::<Vec<_>>()).unwrap();
+        assert_eq!(load_activations(&path).unwrap(), activations);
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn missing_snapshot_loads_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load_activations(&dir.path().join("activations.json"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn only_remote_docker_archives_are_restorable() {
+        assert!(is_restorable(&activate_request(
+            "python-unsafe",
+            "/srv/component.py"
+        )));
+        assert!(!is_restorable(&activate_request(
+            "docker-archive",
+            "/srv/image.tar"
+        )));
+        assert!(is_restorable(&activate_request(
+            "docker-archive",
+            "https://example.com/image.tar"
+        )));
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::Instant;
+
+// A token bucket, holding up to a second's worth of requests
+// Each request takes a token, and tokens refill continuously based on the time since the last request
+#[derive(Debug)]
+pub struct RateLimiter {
+    max_requests_per_second: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(max_requests_per_second: u32) -> Self {
+        let max_requests_per_second = f64::from(max_requests_per_second);
+
+        Self {
+            max_requests_per_second,
+            tokens: max_requests_per_second,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Returns whether the request is allowed through
+    pub fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * self.max_requests_per_second;
+        self.tokens = (self.tokens + refill).min(self.max_requests_per_second);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn allows_a_seconds_worth_of_requests_at_once() {
+        let mut limiter = RateLimiter::new(3);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire());
+        }
+        assert!(!limiter.try_acquire());
+    }
+
+    #[test]
+    fn tokens_refill_over_time_up_to_the_limit() {
+        let mut limiter = RateLimiter::new(4);
+        while limiter.try_acquire() {}
+
+        // Half a second is worth two requests
+        limiter.last_refill -= Duration::from_millis(500);
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        // But an idle limiter never holds more than a second's worth
+        limiter.last_refill -= Duration::from_secs(60);
+        for _ in 0..4 {
+            assert!(limiter.try_acquire());
+        }
+        assert!(!limiter.try_acquire());
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, AGE, AUTHORIZATION, COOKIE, SET_COOKIE};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use lazy_static::lazy_static;
+
+use crate::env_utils::env_or;
+
+// Each component gets this much room for cached response bodies, the least recently used are evicted first
+const DEFAULT_CACHE_BYTES: usize = 8 * 1024 * 1024;
+
+lazy_static! {
+    static ref CACHE_BYTES: usize = env_or("V9_RESPONSE_CACHE_BYTES", DEFAULT_CACHE_BYTES);
+}
+
+// Everything about a call that can change what the component returns (besides the body, which GETs don't have)
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct CacheKey {
+    method: String,
+    path: String,
+    query: String,
+}
+
+impl CacheKey {
+    pub fn new(method: &str, additional_path_components: &[&str], query: &str) -> Self {
+        Self {
+            method: method.to_string(),
+            path: additional_path_components.join("/"),
+            query: query.to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+
+    stored_at: Instant,
+    ttl: Duration,
+    // When this was last used, in terms of `ResponseCache::uses`
+    last_used: u64,
+}
+
+impl CachedResponse {
+    fn expired(&self) -> bool {
+        self.stored_at.elapsed() >= self.ttl
+    }
+}
+
+// Calls with credentials might get a response meant only for that caller, so they never use the cache
+pub fn is_cacheable_request(headers: &HeaderMap) -> bool {
+    !headers.contains_key(AUTHORIZATION) && !headers.contains_key(COOKIE)
+}
+
+// Responses components asked us to cache (with `cache_ttl_seconds`), so repeated calls skip the process
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    entries: HashMap<CacheKey, CachedResponse>,
+    size_bytes: usize,
+    // Counts lookups and inserts, which gives us an ordering for LRU eviction
+    uses: u64,
+}
+
+impl ResponseCache {
+    // Includes an `Age` header, so clients can tell how much longer the response is good for
+    pub fn get(&mut self, key: &CacheKey) -> Option<Response<Body>> {
+        if self.entries.get(key)?.expired() {
+            self.remove(key);
+            return None;
+        }
+
+        self.uses += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.uses;
+
+        let mut resp = Response::new(Body::from(entry.body.clone()));
+        *resp.status_mut() = entry.status;
+        *resp.headers_mut() = entry.headers.clone();
+        resp.headers_mut()
+            .insert(AGE, HeaderValue::from(entry.stored_at.elapsed().as_secs()));
+        Some(resp)
+    }
+
+    pub fn insert(
+        &mut self,
+        key: CacheKey,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        ttl: Duration,
+    ) {
+        // Something this big would evict everything else, and still might not fit
+        // A cookie is meant for one client, so serving it to others would mix up their sessions
+        if body.len() > *CACHE_BYTES || headers.contains_key(SET_COOKIE) {
+            return;
+        }
+
+        self.remove(&key);
+        self.entries.retain(|_, entry| !entry.expired());
+        self.size_bytes = self.entries.values().map(|entry| entry.body.len()).sum();
+
+        while self.size_bytes + body.len() > *CACHE_BYTES {
+            let least_recently_used = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match least_recently_used {
+                Some(lru_key) => self.remove(&lru_key),
+                None => break,
+            }
+        }
+
+        self.uses += 1;
+        self.size_bytes += body.len();
+        self.entries.insert(
+            key,
+            CachedResponse {
+                status,
+                headers,
+                body,
+                stored_at: Instant::now(),
+                ttl,
+                last_used: self.uses,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.size_bytes -= entry.body.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn key(query: &str) -> CacheKey {
+        CacheKey::new("f", &[], query)
+    }
+
+    fn insert(cache: &mut ResponseCache, query: &str, body_bytes: usize, ttl: Duration) {
+        cache.insert(
+            key(query),
+            StatusCode::OK,
+            HeaderMap::new(),
+            Bytes::from(vec![b'x'; body_bytes]),
+            ttl,
+        );
+    }
+
+    fn stored_bytes(cache: &ResponseCache) -> usize {
+        cache.entries.values().map(|entry| entry.body.len()).sum()
+    }
+
+    #[test]
+    fn hits_keep_the_status_and_headers() {
+        let mut cache = ResponseCache::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-custom", HeaderValue::from_static("yes"));
+        cache.insert(key("a"), StatusCode::CREATED, headers, Bytes::from("body"), TTL);
+
+        let resp = cache.get(&key("a")).unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers()["x-custom"], "yes");
+        assert_eq!(resp.headers()[AGE], "0");
+        assert!(cache.get(&key("b")).is_none());
+    }
+
+    #[test]
+    fn expired_responses_are_dropped() {
+        let mut cache = ResponseCache::default();
+        insert(&mut cache, "a", 10, Duration::from_secs(0));
+        insert(&mut cache, "b", 20, TTL);
+
+        assert!(cache.get(&key("a")).is_none());
+        assert!(cache.get(&key("b")).is_some());
+        assert_eq!(cache.size_bytes, 20);
+    }
+
+    #[test]
+    fn least_recently_used_responses_are_evicted_first() {
+        let mut cache = ResponseCache::default();
+        let third = *CACHE_BYTES / 3;
+        insert(&mut cache, "a", third, TTL);
+        insert(&mut cache, "b", third, TTL);
+        insert(&mut cache, "c", third, TTL);
+        // Using `a` makes `b` the least recently used
+        assert!(cache.get(&key("a")).is_some());
+
+        insert(&mut cache, "d", third, TTL);
+        assert!(cache.get(&key("b")).is_none());
+        for query in &["a", "c", "d"] {
+            assert!(cache.get(&key(query)).is_some(), "{} was evicted", query);
+        }
+        assert!(cache.size_bytes <= *CACHE_BYTES);
+    }
+
+    #[test]
+    fn size_tracks_what_is_stored() {
+        let mut cache = ResponseCache::default();
+        insert(&mut cache, "a", 100, TTL);
+        insert(&mut cache, "b", 50, TTL);
+        assert_eq!(cache.size_bytes, 150);
+
+        // Replacing a response doesn't count the old body
+        insert(&mut cache, "a", 10, TTL);
+        assert_eq!(cache.size_bytes, 60);
+
+        // Neither does an expired one, once something else is inserted
+        insert(&mut cache, "c", 5, Duration::from_secs(0));
+        insert(&mut cache, "d", 1, TTL);
+        assert_eq!(cache.size_bytes, 61);
+        assert_eq!(cache.size_bytes, stored_bytes(&cache));
+    }
+
+    #[test]
+    fn credentials_and_cookies_keep_responses_out() {
+        let with = |name| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static("secret"));
+            headers
+        };
+        assert!(is_cacheable_request(&HeaderMap::new()));
+        assert!(!is_cacheable_request(&with(AUTHORIZATION)));
+        assert!(!is_cacheable_request(&with(COOKIE)));
+
+        let mut cache = ResponseCache::default();
+        cache.insert(
+            key("a"),
+            StatusCode::OK,
+            with(SET_COOKIE),
+            Bytes::from("body"),
+            TTL,
+        );
+        assert!(cache.get(&key("a")).is_none());
+        assert_eq!(cache.size_bytes, 0);
+    }
+
+    #[test]
+    fn responses_bigger_than_the_cache_are_not_stored() {
+        let mut cache = ResponseCache::default();
+        insert(&mut cache, "a", 100, TTL);
+        insert(&mut cache, "big", *CACHE_BYTES + 1, TTL);
+
+        assert!(cache.get(&key("big")).is_none());
+        assert!(cache.get(&key("a")).is_some());
+        assert_eq!(cache.size_bytes, 100);
+    }
+}
@@ -0,0 +1,65 @@
+use std::io::{self, Seek, SeekFrom, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::Body;
+use lazy_static::lazy_static;
+use tokio::fs::File;
+use tokio::io::AsyncRead;
+use tokio::stream::Stream;
+
+use crate::env_utils::env_or;
+use crate::error::WorkerError;
+use crate::fs_utils::unnamed_temp_file;
+
+// Response bodies larger than this are moved to disk while they're sent, so slow clients downloading big
+// responses don't pin them in memory
+const DEFAULT_SPILL_THRESHOLD_BYTES: usize = 1024 * 1024;
+const SPILL_CHUNK_BYTES: usize = 64 * 1024;
+
+lazy_static! {
+    static ref SPILL_THRESHOLD_BYTES: usize =
+        env_or("V9_RESPONSE_SPILL_BYTES", DEFAULT_SPILL_THRESHOLD_BYTES);
+}
+
+// Small bodies stay in memory, since writing them out would just be slower
+pub fn response_body(body: String) -> Result<Body, WorkerError> {
+    if body.len() <= *SPILL_THRESHOLD_BYTES {
+        return Ok(Body::from(body));
+    }
+
+    debug!("Spilling {} byte response to disk", body.len());
+
+    // The file is already unlinked, so it's cleaned up as soon as the body is dropped
+    let mut spill_file = unnamed_temp_file()?;
+    spill_file.write_all(body.as_bytes())?;
+    spill_file.seek(SeekFrom::Start(0))?;
+    drop(body);
+
+    Ok(Body::wrap_stream(FileStream {
+        file: File::from_std(spill_file),
+    }))
+}
+
+// Reads a file in chunks, as a stream hyper can send
+struct FileStream {
+    file: File,
+}
+
+impl Stream for FileStream {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut chunk = vec![0; SPILL_CHUNK_BYTES];
+
+        match Pin::new(&mut self.file).poll_read(cx, &mut chunk) {
+            Poll::Ready(Ok(0)) => Poll::Ready(None),
+            Poll::Ready(Ok(read)) => {
+                chunk.truncate(read);
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
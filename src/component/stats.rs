@@ -1,13 +1,61 @@
 use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::env;
 use std::time::{Duration, Instant};
 
-use crate::model::{ComponentStats, StatusColor};
+use hyper::StatusCode;
+use lazy_static::lazy_static;
+
+use crate::error::{WorkerError, WorkerErrorKind, WORKER_ERROR_STATUS};
+use crate::model::{ComponentStats, LatencyPercentile, RawStatEvent, SizePercentile, StatusColor};
 
 const DEFAULT_STAT_WINDOW: Duration = Duration::from_secs(5 * 60);
+// Every event in the window is kept in memory, so we can't let the window grow forever
+const MAX_STAT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+const FALLBACK_PERCENTILE_MARKS: &[f64] = &[50.0, 90.0, 95.0, 99.0, 99.9];
+
+lazy_static! {
+    // Operators can change the default marks with a comma separated list, like "50,99,99.9"
+    static ref DEFAULT_PERCENTILE_MARKS: Vec<f64> = default_percentile_marks();
+}
+
+fn default_percentile_marks() -> Vec<f64> {
+    if let Ok(configured) = env::var("V9_LATENCY_PERCENTILES") {
+        let marks: Result<Vec<f64>, _> = configured.split(',').map(|mark| mark.trim().parse()).collect();
+        match marks {
+            Ok(marks) if marks.iter().all(|&mark| valid_percentile_mark(mark)) => return marks,
+            _ => warn!(
+                "Invalid value {:?} for V9_LATENCY_PERCENTILES, using default {:?}",
+                configured, FALLBACK_PERCENTILE_MARKS
+            ),
+        }
+    }
+
+    FALLBACK_PERCENTILE_MARKS.to_vec()
+}
+
+fn valid_percentile_mark(mark: f64) -> bool {
+    mark > 0.0 && mark <= 100.0
+}
+
+// Green for success (and redirects), orange for client errors, red for server errors
+pub fn response_color(status: StatusCode) -> StatusColor {
+    if status.is_success() || status.is_redirection() {
+        StatusColor::Green
+    } else if status.is_server_error() || status == WORKER_ERROR_STATUS {
+        StatusColor::Red
+    } else {
+        // Covers `status.is_client_error()`
+        StatusColor::Orange
+    }
+}
 
 #[derive(Debug)]
 pub struct StatTracker {
     stat_window: Duration,
+    // Which latency percentiles to report, e.g. 99.0 for p99
+    percentile_marks: Vec<f64>,
     current_color: StatusColor,
     // Events at the back of the queue are the newest events
     event_deque: VecDeque<StatEvent>,
@@ -17,13 +65,19 @@ pub struct StatTracker {
 struct StatEvent {
     at: Instant,
     duration_ms: u32,
+    request_bytes: u32,
     response_bytes: u32,
+    // Whether the process had to be booted for this call
+    cold: bool,
+    // See `response_color`
+    color: StatusColor,
 }
 
 impl Default for StatTracker {
     fn default() -> Self {
         Self {
             stat_window: DEFAULT_STAT_WINDOW,
+            percentile_marks: DEFAULT_PERCENTILE_MARKS.clone(),
             current_color: StatusColor::Grey,
             event_deque: VecDeque::new(),
         }
@@ -31,101 +85,244 @@ impl Default for StatTracker {
 }
 
 impl StatTracker {
-    pub fn get_component_stats(&mut self) -> ComponentStats {
-        self.pop_old_events();
+    // Uses the defaults for anything that is `None`
+    pub fn new(
+        stat_window_seconds: Option<u64>,
+        percentile_marks: Option<Vec<f64>>,
+    ) -> Result<Self, WorkerError> {
+        let stat_window = stat_window_seconds.map_or(DEFAULT_STAT_WINDOW, Duration::from_secs);
+        if stat_window == Duration::from_secs(0) || stat_window > MAX_STAT_WINDOW {
+            return Err(WorkerErrorKind::InvalidStatWindow(stat_window.as_secs()).into());
+        }
+
+        let percentile_marks = percentile_marks.unwrap_or_else(|| DEFAULT_PERCENTILE_MARKS.clone());
+        if let Some(&mark) = percentile_marks
+            .iter()
+            .find(|&&mark| !valid_percentile_mark(mark))
+        {
+            return Err(WorkerErrorKind::InvalidPercentileMark(mark).into());
+        }
+
+        Ok(Self {
+            stat_window,
+            percentile_marks,
+            ..Self::default()
+        })
+    }
+
+    // Doesn't drop expired events (that happens as new events come in), so it works through a shared reference
+    pub fn get_component_stats(&self) -> ComponentStats {
+        let events: Vec<&StatEvent> = self.events_in_window().collect();
 
         let stat_window_seconds = self.stat_window.as_secs_f64();
 
-        let hits = self.event_deque.len() as f64;
+        let hits = events.len() as f64;
+        let count_color = |color| events.iter().filter(|e| e.color == color).count() as f64;
 
-        if self.event_deque.is_empty() {
+        if events.is_empty() {
             ComponentStats {
                 color: self.current_color,
 
                 stat_window_seconds,
 
                 hits,
+                success_count: 0.0,
+                client_error_count: 0.0,
+                server_error_count: 0.0,
 
+                avg_request_bytes: 0.0,
                 avg_response_bytes: 0.0,
+                request_bytes_percentiles: vec![],
+                response_bytes_percentiles: vec![],
+
                 avg_ms_latency: 0.0,
                 ms_latency_percentiles: vec![],
+
+                cold_start_count: 0.0,
+                avg_cold_ms_latency: 0.0,
+                avg_warm_ms_latency: 0.0,
+
+                memory_bytes: None,
+                cpu_percent: None,
             }
         } else {
-            let avg_response_bytes = self
-                .event_deque
-                .iter()
-                .map(|e| f64::from(e.response_bytes))
-                .sum::<f64>()
-                / hits;
-            let avg_ms_latency = self
-                .event_deque
-                .iter()
-                .map(|e| f64::from(e.duration_ms))
-                .sum::<f64>()
-                / hits;
+            let average = |value: fn(&StatEvent) -> u32| {
+                events.iter().map(|e| f64::from(value(e))).sum::<f64>() / hits
+            };
+            let percentiles = |value: fn(&StatEvent) -> u32| {
+                calculate_percentiles(events.iter().map(|e| value(e)).collect(), &self.percentile_marks)
+            };
+
+            let cold_start_count = events.iter().filter(|e| e.cold).count() as f64;
             ComponentStats {
                 color: self.current_color,
 
                 stat_window_seconds,
 
                 hits,
+                success_count: count_color(StatusColor::Green),
+                client_error_count: count_color(StatusColor::Orange),
+                server_error_count: count_color(StatusColor::Red),
+
+                avg_request_bytes: average(|e| e.request_bytes),
+                avg_response_bytes: average(|e| e.response_bytes),
+                request_bytes_percentiles: percentiles(|e| e.request_bytes)
+                    .map(|(percentile, bytes)| SizePercentile { percentile, bytes })
+                    .collect(),
+                response_bytes_percentiles: percentiles(|e| e.response_bytes)
+                    .map(|(percentile, bytes)| SizePercentile { percentile, bytes })
+                    .collect(),
+
+                avg_ms_latency: average(|e| e.duration_ms),
+                ms_latency_percentiles: percentiles(|e| e.duration_ms)
+                    .map(|(percentile, ms_latency)| LatencyPercentile {
+                        percentile,
+                        ms_latency,
+                    })
+                    .collect(),
+
+                cold_start_count,
+                avg_cold_ms_latency: average_latency(&events, true),
+                avg_warm_ms_latency: average_latency(&events, false),
 
-                avg_response_bytes,
-                avg_ms_latency,
-                ms_latency_percentiles: calculate_latency_percentiles(&self.event_deque),
+                memory_bytes: None,
+                cpu_percent: None,
             }
         }
     }
 
-    pub fn add_stat_event(&mut self, duration_ms: u32, response_bytes: u32) {
+    pub fn add_stat_event(
+        &mut self,
+        duration_ms: u32,
+        request_bytes: u32,
+        response_bytes: u32,
+        cold: bool,
+        color: StatusColor,
+    ) {
         self.event_deque.push_back(StatEvent {
             at: Instant::now(),
             duration_ms,
+            request_bytes,
             response_bytes,
+            cold,
+            color,
         });
 
         self.pop_old_events();
     }
 
+    // The newest `limit` events in the window (oldest first), and whether any older ones were left out
+    pub fn raw_events(&self, limit: usize) -> (Vec<RawStatEvent>, bool) {
+        let now = Instant::now();
+        let skipped = self.events_in_window().count().saturating_sub(limit);
+        let events = self
+            .events_in_window()
+            .skip(skipped)
+            .map(|e| RawStatEvent {
+                age_ms: now
+                    .saturating_duration_since(e.at)
+                    .as_millis()
+                    .try_into()
+                    .unwrap_or(u64::MAX),
+                duration_ms: e.duration_ms,
+                request_bytes: e.request_bytes,
+                response_bytes: e.response_bytes,
+                cold: e.cold,
+                color: e.color,
+            })
+            .collect();
+
+        (events, skipped > 0)
+    }
+
+    // Expired events stay in the deque until the next `pop_old_events`, so readers skip them instead
+    fn events_in_window(&self) -> impl Iterator<Item = &StatEvent> {
+        let too_old = self.too_old();
+        self.event_deque
+            .iter()
+            .skip_while(move |e| too_old.map_or(false, |too_old| e.at < too_old))
+    }
+
     fn pop_old_events(&mut self) {
-        let too_old = Instant::now() - self.stat_window;
-        while self.event_deque.front().map_or(false, |e| e.at < too_old) {
-            self.event_deque.pop_front();
+        if let Some(too_old) = self.too_old() {
+            while self.event_deque.front().map_or(false, |e| e.at < too_old) {
+                self.event_deque.pop_front();
+            }
         }
     }
 
+    // If the window reaches back past the start of the clock, then no event can be too old
+    fn too_old(&self) -> Option<Instant> {
+        Instant::now().checked_sub(self.stat_window)
+    }
+
     pub fn set_color(&mut self, color: StatusColor) {
         self.current_color = color;
     }
 }
 
-const PERCENTILE_BUCKETS: usize = 10;
+// Average latency of just the cold (or just the warm) events, or 0 if there are none
+fn average_latency(entries: &[&StatEvent], cold: bool) -> f64 {
+    let latencies: Vec<f64> = entries
+        .iter()
+        .filter(|e| e.cold == cold)
+        .map(|e| f64::from(e.duration_ms))
+        .collect();
+
+    if latencies.is_empty() {
+        0.0
+    } else {
+        latencies.iter().sum::<f64>() / latencies.len() as f64
+    }
+}
+
+// Uses the nearest-rank method, so every reported value is one that was actually observed
+// Returns (percentile, value) pairs, for the marks there is enough data for
+fn calculate_percentiles(
+    mut values: Vec<u32>,
+    percentile_marks: &[f64],
+) -> impl Iterator<Item = (f64, f64)> + '_ {
+    values.sort_unstable();
 
-fn calculate_latency_percentiles(entries: &VecDeque<StatEvent>) -> Vec<f64> {
-    let mut u32_latencies: Vec<u32> = entries.iter().map(|e| e.duration_ms).collect();
-    u32_latencies.sort();
+    let count = values.len() as f64;
+    percentile_marks.iter().filter_map(move |&percentile| {
+        // The smallest value with at least `percentile`% of the values at or below it
+        let index = (0..values.len()).find(|&i| (i + 1) as f64 * 100.0 >= percentile * count)?;
 
-    let mut res = Vec::new();
+        Some((percentile, f64::from(values[index])))
+    })
+}
 
-    // Need some special logic here for dealing with a number of entries that is not a multiple of `PERCENTILE_BUCKETS`
-    // To do this, each of the first `additional_items` buckets get one extra item
-    // In order to account for this, each buckets starting index needs to be bumped up
-    // Notice, however, that this bump is exactly `min(i, additional_items)` where `i` is the bucket #
-    // https://stackoverflow.com/a/2135920/1981468
-    let rough_bucket_size = u32_latencies.len() / PERCENTILE_BUCKETS;
-    let additional_items = u32_latencies.len() % PERCENTILE_BUCKETS;
-    for i in 0..PERCENTILE_BUCKETS {
-        let starting_index = i * rough_bucket_size + i.min(additional_items);
-        let next_starting_index = (i + 1) * rough_bucket_size + (i + 1).min(additional_items);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let slice = &u32_latencies[starting_index..next_starting_index];
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let values: Vec<u32> = (1..=10).rev().collect();
+        let percentiles: Vec<(f64, f64)> =
+            calculate_percentiles(values, &[0.0, 10.0, 50.0, 55.0, 90.0, 99.9, 100.0]).collect();
 
-        // We only care about non-empty buckets
-        if !slice.is_empty() {
-            let total_latency: u32 = slice.iter().sum();
-            res.push(f64::from(total_latency) / slice.len() as f64);
-        }
+        assert_eq!(
+            percentiles,
+            vec![
+                (0.0, 1.0),
+                (10.0, 1.0),
+                (50.0, 5.0),
+                (55.0, 6.0),
+                (90.0, 9.0),
+                (99.9, 10.0),
+                (100.0, 10.0),
+            ]
+        );
     }
 
-    res
+    #[test]
+    fn percentiles_of_nothing_are_left_out() {
+        assert_eq!(calculate_percentiles(Vec::new(), &[50.0, 99.0]).count(), 0);
+        assert_eq!(
+            calculate_percentiles(vec![7], &[50.0, 99.0]).collect::<Vec<_>>(),
+            vec![(50.0, 7.0), (99.0, 7.0)]
+        );
+    }
 }
@@ -0,0 +1,52 @@
+use std::time::Instant;
+
+use crate::component::circuit_breaker::CircuitBreaker;
+use crate::component::stats::StatTracker;
+use crate::model::{ComponentId, ComponentStatus, ResourceUsage};
+
+// Everything `status` reports about a component. The component lock is held for the whole of a call, so this is
+// kept behind a lock of its own (shared by the handle and the manager), which is only held long enough to update
+// or read it. That way a slow call never holds up a status request.
+#[derive(Debug)]
+pub struct StatusSnapshot {
+    pub id: ComponentId,
+    pub activated_at: Instant,
+    // When a call (or a warm/ping) last reached the process, the process wrapper keeps its own copy for idle expiry
+    pub last_accessed: Instant,
+    pub circuit_breaker: CircuitBreaker,
+    pub stat_tracker: StatTracker,
+    // Measuring needs the process, so this is only refreshed when the component isn't busy (see `status`)
+    pub resource_usage: Option<ResourceUsage>,
+}
+
+impl StatusSnapshot {
+    pub fn new(id: ComponentId, stat_tracker: StatTracker) -> Self {
+        let now = Instant::now();
+        Self {
+            id,
+            activated_at: now,
+            last_accessed: now,
+            circuit_breaker: CircuitBreaker::new(),
+            stat_tracker,
+            resource_usage: None,
+        }
+    }
+
+    // `detailed` also reports the last resource usage measured
+    pub fn component_status(&self, detailed: bool) -> ComponentStatus {
+        let mut status = ComponentStatus {
+            id: self.id.clone(),
+            seconds_since_activation: self.activated_at.elapsed().as_secs(),
+            seconds_since_last_access: self.last_accessed.elapsed().as_secs(),
+            breaker_state: self.circuit_breaker.state(),
+            component_stats: self.stat_tracker.get_component_stats(),
+        };
+
+        if let Some(usage) = self.resource_usage.filter(|_| detailed) {
+            status.component_stats.memory_bytes = Some(usage.memory_bytes);
+            status.component_stats.cpu_percent = Some(usage.cpu_percent);
+        }
+
+        status
+    }
+}
@@ -0,0 +1,134 @@
+use std::io;
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Response};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::model::ComponentStreamMessage;
+
+const EVENT_STREAM_CONTENT_TYPE: &str = "text/event-stream";
+
+// Calls are handled on a blocking thread, which normally finishes before the response is sent. A streaming call
+// keeps going for as long as the component has events though, so it hands its response over early through this.
+#[derive(Debug)]
+pub struct EarlyResponse {
+    sender: oneshot::Sender<Response<Body>>,
+}
+
+impl EarlyResponse {
+    pub fn new() -> (Self, oneshot::Receiver<Response<Body>>) {
+        let (sender, receiver) = oneshot::channel();
+        (Self { sender }, receiver)
+    }
+
+    // Returns whether the client is still waiting for it
+    pub fn send(self, resp: Response<Body>) -> bool {
+        self.sender.send(resp).is_ok()
+    }
+}
+
+// Feeds the body of a `text/event-stream` response
+// It's unbounded, since the component can't be paused anyway (it would just block writing to its pipe, until
+// our read timed out), but events are relayed as soon as they're read, so it only grows for slow clients
+#[derive(Debug)]
+pub struct EventSink {
+    sender: mpsc::UnboundedSender<Result<Bytes, io::Error>>,
+}
+
+impl EventSink {
+    // Returns whether the client is still connected, as far as we can tell
+    // That's only known once something is sent, so messages without events assume it is
+    pub fn send(&self, message: &ComponentStreamMessage) -> bool {
+        format_event(message).map_or(true, |event| self.sender.send(Ok(Bytes::from(event))).is_ok())
+    }
+
+    // Cuts the response off, instead of ending it normally, so the client can tell it's incomplete
+    pub fn abort(self) {
+        let broken_stream = io::Error::new(io::ErrorKind::BrokenPipe, "component stream failed");
+        // If the client is already gone, there's nothing to tell
+        let _ = self.sender.send(Err(broken_stream));
+    }
+}
+
+pub fn event_stream_response() -> (Response<Body>, EventSink) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    let mut resp = Response::new(Body::wrap_stream(receiver));
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(EVENT_STREAM_CONTENT_TYPE));
+    // Proxies shouldn't hold on to events, they only make sense as they happen
+    resp.headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+
+    (resp, EventSink { sender })
+}
+
+// What a GET would get, minus the events, since answering a HEAD shouldn't start a stream
+pub fn event_stream_head_response() -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(EVENT_STREAM_CONTENT_TYPE));
+    resp
+}
+
+// Messages without data (like the final one, usually) aren't events
+// Event fields can't contain newlines, so multi-line data is sent as several `data:` lines, which clients join
+// back together
+fn format_event(message: &ComponentStreamMessage) -> Option<String> {
+    let data = message.data.as_ref()?;
+
+    let mut event = String::new();
+    if let Some(name) = &message.event {
+        event.push_str("event: ");
+        event.push_str(&name.replace(['\r', '\n'], ""));
+        event.push('\n');
+    }
+    if data.is_empty() {
+        event.push_str("data: \n");
+    }
+    for line in data.lines() {
+        event.push_str("data: ");
+        event.push_str(line);
+        event.push('\n');
+    }
+    event.push('\n');
+
+    Some(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(data: Option<&str>, event: Option<&str>) -> ComponentStreamMessage {
+        ComponentStreamMessage {
+            data: data.map(str::to_string),
+            event: event.map(str::to_string),
+            done: false,
+        }
+    }
+
+    #[test]
+    fn messages_are_formatted_as_events() {
+        assert_eq!(
+            format_event(&message(Some("hello"), None)).unwrap(),
+            "data: hello\n\n"
+        );
+        assert_eq!(
+            format_event(&message(Some("two\nlines"), Some("update"))).unwrap(),
+            "event: update\ndata: two\ndata: lines\n\n"
+        );
+        assert_eq!(format_event(&message(Some(""), None)).unwrap(), "data: \n\n");
+        // A newline in the event name would end the field early
+        assert_eq!(
+            format_event(&message(Some("x"), Some("up\r\ndate"))).unwrap(),
+            "event: update\ndata: x\n\n"
+        );
+    }
+
+    #[test]
+    fn messages_without_data_arent_events() {
+        assert_eq!(format_event(&message(None, Some("update"))), None);
+    }
+}
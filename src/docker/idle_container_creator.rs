@@ -1,98 +1,519 @@
-use std::sync::mpsc::{sync_channel, Receiver};
-use std::thread;
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use lazy_static::lazy_static;
 use parking_lot::Mutex;
 
 use crate::component::LogPolicy;
-use crate::docker::V9Container;
+use crate::docker::{ContainerOptions, V9Container};
+use crate::env_utils::env_or;
 use crate::error::WorkerError;
+use crate::model::{IdlePoolMetrics, IdlePoolStatus};
 use crate::named_pipe::NamedPipe;
 
-// We guarantee that the new idle containers have this code folder available
-pub const CODE_FOLDER: &str = "/home/sl";
+// These defaults can be overridden by the environment variables read in `IdleContainerConfig::from_env`
+const DEFAULT_CODE_FOLDER: &str = "/home/sl";
+const DEFAULT_CONTAINER_CACHE_CHANNEL_SIZE: usize = 3;
+const DEFAULT_CACHE_POPULATOR_COUNT: usize = 2;
+const DEFAULT_CONTAINER_IMAGE_TAG: &str = "python:3.7-alpine";
+const DEFAULT_MAX_IDLE_POOLS: usize = 4;
 
-// NOTE: the number of idle containers on the system is CONTAINER_CACHE_CHANNEL_SIZE + CACHE_POPULATOR_COUNT
-const CONTAINER_CACHE_CHANNEL_SIZE: usize = 3;
-const CACHE_POPULATOR_COUNT: usize = 2;
+// Generous, since the first container on a host may have to pull the image
+const CONTAINER_START_TIMEOUT: Duration = Duration::from_secs(120);
+
+// A populator that hasn't checked in for this long is considered stuck. Creating a container can legitimately
+// take up to `CONTAINER_START_TIMEOUT`, plus however long the setup commands take.
+const POPULATOR_STALL_TIMEOUT: Duration = Duration::from_secs(180);
+// How often a populator waiting for room in a full pool checks back in (and checks for shutdown)
+const FULL_POOL_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
-const CONTAINER_IMAGE_TAG: &str = "python:3.7-alpine";
 // 1000000000 seconds ~= 30 years
 const SLEEP_TIME: &str = "1000000000";
 
-fn sync_create_container() -> Result<V9Container, WorkerError> {
+#[derive(Clone, Debug)]
+pub struct IdleContainerConfig {
+    // We guarantee that the new idle containers have this code folder available
+    pub code_folder: String,
+    // NOTE: the number of idle containers on the system is cache_channel_size + cache_populator_count
+    pub cache_channel_size: usize,
+    pub cache_populator_count: usize,
+    pub image_tag: String,
+    // Unset means the host's platform
+    pub platform: Option<String>,
+    // Shell commands run (in order) in every new container, after the code folder is created
+    pub setup_commands: Vec<String>,
+}
+
+impl IdleContainerConfig {
+    fn from_env() -> Self {
+        Self {
+            code_folder: env_or("V9_IDLE_CODE_FOLDER", DEFAULT_CODE_FOLDER.to_string()),
+            cache_channel_size: env_or("V9_IDLE_CACHE_SIZE", DEFAULT_CONTAINER_CACHE_CHANNEL_SIZE),
+            cache_populator_count: env_or("V9_IDLE_POPULATOR_COUNT", DEFAULT_CACHE_POPULATOR_COUNT),
+            image_tag: env_or("V9_IDLE_IMAGE", DEFAULT_CONTAINER_IMAGE_TAG.to_string()),
+            platform: env::var("V9_IDLE_PLATFORM").ok(),
+            setup_commands: env::var("V9_IDLE_SETUP_COMMANDS")
+                .map(|commands| parse_setup_commands(&commands))
+                .unwrap_or_default(),
+        }
+    }
+
+    fn for_image(&self, image_tag: &str) -> Self {
+        Self {
+            image_tag: image_tag.to_string(),
+            ..self.clone()
+        }
+    }
+
+    // The options every pooled container is started with
+    fn container_options(&self) -> ContainerOptions {
+        ContainerOptions::with_platform(self.platform.clone())
+    }
+}
+
+// Commands are separated by ";", and empty ones are skipped
+fn parse_setup_commands(commands: &str) -> Vec<String> {
+    commands
+        .split(';')
+        .map(str::trim)
+        .filter(|command| !command.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// A failed container is removed when it's dropped, so the populators just try again with a fresh one
+fn sync_create_container(
+    config: &IdleContainerConfig,
+    options: &ContainerOptions,
+) -> Result<V9Container, WorkerError> {
     let pipe = NamedPipe::new()?;
-    let container = V9Container::start(
+    let mut container = V9Container::start(
         pipe,
-        CONTAINER_IMAGE_TAG,
+        None,
+        &config.image_tag,
         &["sleep", SLEEP_TIME],
+        options,
         &LogPolicy::new_ignore_policy(),
     )?;
+    container.wait_until_running(CONTAINER_START_TIMEOUT)?;
 
-    // Unfortunately we can't know when the container is ready, so we blindly sleep for a second
-    // Luckily this is usually done in an async context, so it's okay to sleep
-    // TODO: This can take a long time on first go, so add backoff to try twice at 1 sec and 10 sec
-    thread::sleep(Duration::from_secs(1));
-
-    container.exec_sync(&["mkdir", "-p", CODE_FOLDER])?;
+    container.exec_sync(&["mkdir", "-p", &config.code_folder])?;
+    for command in &config.setup_commands {
+        if let Err(e) = container.exec_sync(&["sh", "-c", command]) {
+            warn!(
+                "Setup command {:?} failed in container {}, discarding it",
+                command,
+                container.name()
+            );
+            return Err(e);
+        }
+    }
 
     Ok(container)
 }
 
+// These let us see whether the populator threads are keeping up with demand
+#[derive(Debug, Default)]
+struct IdlePoolCounters {
+    containers_created: AtomicU64,
+    containers_handed_out: AtomicU64,
+    cache_hits: AtomicU64,
+    synchronous_fallbacks: AtomicU64,
+    // Together with `cache_hits`, these tell us how many containers are in the pool right now
+    containers_pooled: AtomicU64,
+    containers_removed: AtomicU64,
+}
+
+// Each populator beats when it starts on a container, and regularly while it waits for room in the pool, so one
+// that hasn't beaten in a while is stuck. It also marks itself exited, which covers it panicking.
+#[derive(Debug)]
+struct PopulatorHeartbeat {
+    // Since `started`, which saves a lock around an `Instant`
+    last_beat_ms: AtomicU64,
+    started: Instant,
+    exited: AtomicBool,
+}
+
+impl PopulatorHeartbeat {
+    fn new() -> Self {
+        Self {
+            last_beat_ms: AtomicU64::new(0),
+            started: Instant::now(),
+            exited: AtomicBool::new(false),
+        }
+    }
+
+    fn beat(&self) {
+        self.last_beat_ms.store(self.elapsed_ms(), Ordering::SeqCst);
+    }
+
+    fn is_alive(&self) -> bool {
+        let since_last_beat = self
+            .elapsed_ms()
+            .saturating_sub(self.last_beat_ms.load(Ordering::SeqCst));
+        !self.exited.load(Ordering::SeqCst)
+            && Duration::from_millis(since_last_beat) < POPULATOR_STALL_TIMEOUT
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        u64::try_from(self.started.elapsed().as_millis()).unwrap_or(u64::MAX)
+    }
+}
+
+// Marks the populator exited however its thread ends
+struct PopulatorExitGuard(Arc<PopulatorHeartbeat>);
+
+impl Drop for PopulatorExitGuard {
+    fn drop(&mut self) {
+        self.0.exited.store(true, Ordering::SeqCst);
+    }
+}
+
+// Fills the pool until we shut down (or the pool goes away)
+fn populate(
+    config: &IdleContainerConfig,
+    sender: &SyncSender<V9Container>,
+    counters: &IdlePoolCounters,
+    shutting_down: &AtomicBool,
+    heartbeat: &PopulatorHeartbeat,
+) {
+    while !shutting_down.load(Ordering::SeqCst) {
+        heartbeat.beat();
+        let mut container = match sync_create_container(config, &config.container_options()) {
+            Ok(container) => container,
+            Err(e) => {
+                error!("Problem creating a container in a working thread: {}", e);
+                info!("Worker thread sleeping after erorring out...");
+                thread::sleep(Duration::from_secs(10));
+                continue;
+            }
+        };
+        counters.containers_created.fetch_add(1, Ordering::SeqCst);
+
+        // A blocking send could wait forever on a full pool, which would look just like a stuck populator
+        loop {
+            match sender.try_send(container) {
+                Ok(()) => {
+                    counters.containers_pooled.fetch_add(1, Ordering::SeqCst);
+                    break;
+                }
+                Err(TrySendError::Full(unsent)) => {
+                    // Dropping the container removes it
+                    if shutting_down.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    heartbeat.beat();
+                    thread::sleep(FULL_POOL_POLL_INTERVAL);
+                    container = unsent;
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    warn!("Idle container cache populator thread disconnected. Terminating...");
+                    return;
+                }
+            }
+        }
+    }
+}
+
 pub struct IdleContainerCreator {
+    config: IdleContainerConfig,
+    counters: Arc<IdlePoolCounters>,
     cache_channel_receiver: Mutex<Receiver<V9Container>>,
+    // Tells the populator threads to stop creating containers (see `shutdown`)
+    shutting_down: Arc<AtomicBool>,
+    populator_threads: Mutex<Vec<JoinHandle<()>>>,
+    populator_heartbeats: Vec<Arc<PopulatorHeartbeat>>,
 }
 
 impl IdleContainerCreator {
-    fn new() -> Self {
+    fn new(config: IdleContainerConfig) -> Self {
+        info!("Creating idle container pool with {:?}", config);
+
         // Create the cache channel
-        let (sender, receiver) = sync_channel(CONTAINER_CACHE_CHANNEL_SIZE);
+        let (sender, receiver) = sync_channel(config.cache_channel_size);
+        let counters = Arc::new(IdlePoolCounters::default());
 
         // Create the populator threads
-        for _ in 0..CACHE_POPULATOR_COUNT {
-            let sender = sender.clone();
-            thread::spawn(move || loop {
-                let container = sync_create_container();
-                match container {
-                    Ok(id) => {
-                        let send_res = sender.send(id);
-                        if send_res.is_err() {
-                            warn!("Idle container cache populator thread disconnected. Terminating...");
-                            return;
-                        }
-                    }
-                    Err(e) => {
-                        error!("Problem creating a container in a working thread: {}", e);
-                        info!("Worker thread sleeping after erorring out...");
-                        thread::sleep(Duration::from_secs(10));
-                    }
-                }
-            });
-        }
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let populator_heartbeats: Vec<Arc<PopulatorHeartbeat>> = (0..config.cache_populator_count)
+            .map(|_| Arc::new(PopulatorHeartbeat::new()))
+            .collect();
+        let populator_threads = populator_heartbeats
+            .iter()
+            .map(|heartbeat| {
+                let sender = sender.clone();
+                let config = config.clone();
+                let counters = counters.clone();
+                let shutting_down = shutting_down.clone();
+                let heartbeat = heartbeat.clone();
+                thread::spawn(move || {
+                    let _exit_guard = PopulatorExitGuard(heartbeat.clone());
+                    populate(&config, &sender, &counters, &shutting_down, &heartbeat);
+                    debug!("Idle container cache populator thread shut down");
+                })
+            })
+            .collect();
 
         Self {
+            config,
+            counters,
             cache_channel_receiver: Mutex::new(receiver),
+            shutting_down,
+            populator_threads: Mutex::new(populator_threads),
+            populator_heartbeats,
         }
     }
 
+    // Stops the populator threads, then removes every container left in the pool, so none are leaked
+    // NOTE: This blocks until each populator has finished the container it's currently creating
+    fn shutdown(&self) {
+        info!("Shutting down the idle container pool...");
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        // A populator may be about to add one last container, so we keep draining the cache while they stop
+        // Once every populator has exited the channel disconnects, and `recv` fails when it's empty
+        let receiver = self.cache_channel_receiver.lock();
+        let mut removed = 0;
+        while let Ok(container) = receiver.recv() {
+            // Dropping a container removes it
+            drop(container);
+            removed += 1;
+        }
+        self.counters
+            .containers_removed
+            .fetch_add(removed, Ordering::SeqCst);
+
+        for populator in self.populator_threads.lock().drain(..) {
+            if populator.join().is_err() {
+                warn!("An idle container populator thread panicked");
+            }
+        }
+
+        info!("Idle container pool shut down, removed {} containers", removed);
+    }
+
     fn get_idle_container(&self) -> Result<V9Container, WorkerError> {
         let cached_container_id = self
             .cache_channel_receiver
             .try_lock()
             .and_then(|chan| chan.try_recv().ok());
 
-        match cached_container_id {
-            Some(id) => Ok(id),
-            None => sync_create_container(),
+        let container = if let Some(id) = cached_container_id {
+            self.counters.cache_hits.fetch_add(1, Ordering::SeqCst);
+            id
+        } else {
+            self.counters.synchronous_fallbacks.fetch_add(1, Ordering::SeqCst);
+            let id = sync_create_container(&self.config, &self.config.container_options())?;
+            self.counters.containers_created.fetch_add(1, Ordering::SeqCst);
+            id
+        };
+
+        self.counters.containers_handed_out.fetch_add(1, Ordering::SeqCst);
+        Ok(container)
+    }
+
+    fn metrics(&self) -> IdlePoolMetrics {
+        IdlePoolMetrics {
+            containers_created: self.counters.containers_created.load(Ordering::SeqCst),
+            containers_handed_out: self.counters.containers_handed_out.load(Ordering::SeqCst),
+            cache_hits: self.counters.cache_hits.load(Ordering::SeqCst),
+            synchronous_fallbacks: self.counters.synchronous_fallbacks.load(Ordering::SeqCst),
+        }
+    }
+
+    fn status(&self) -> IdlePoolStatus {
+        // The counters are read one at a time, so this can be off by one for a moment
+        let warm_containers = self
+            .counters
+            .containers_pooled
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.counters.cache_hits.load(Ordering::SeqCst))
+            .saturating_sub(self.counters.containers_removed.load(Ordering::SeqCst));
+
+        IdlePoolStatus {
+            warm_containers,
+            capacity: self.config.cache_channel_size,
+            populators: self.populator_heartbeats.len(),
+            populators_alive: self
+                .populator_heartbeats
+                .iter()
+                .filter(|heartbeat| heartbeat.is_alive())
+                .count(),
+        }
+    }
+}
+
+// Containerized scripts that ask for another base image get a pool of their own, made on their first activation
+// The pool for `V9_IDLE_IMAGE` is made at startup, and every pool lasts until we shut down
+pub struct IdlePools {
+    // Every pool shares this, apart from its image
+    config: IdleContainerConfig,
+    pools: Mutex<BTreeMap<String, Arc<IdleContainerCreator>>>,
+    // Once set, no more pools are made
+    shut_down: AtomicBool,
+}
+
+impl IdlePools {
+    fn new(config: IdleContainerConfig) -> Self {
+        let mut pools = BTreeMap::new();
+        pools.insert(
+            config.image_tag.clone(),
+            Arc::new(IdleContainerCreator::new(config.clone())),
+        );
+
+        Self {
+            config,
+            pools: Mutex::new(pools),
+            shut_down: AtomicBool::new(false),
+        }
+    }
+
+    // Makes the image's pool if it doesn't have one yet, `None` means its containers have to be created synchronously
+    fn pool(&self, image_tag: &str) -> Option<Arc<IdleContainerCreator>> {
+        let mut pools = self.pools.lock();
+        if let Some(pool) = pools.get(image_tag) {
+            return Some(pool.clone());
+        }
+        if self.shut_down.load(Ordering::SeqCst) {
+            return None;
+        }
+        if pools.len() >= *MAX_IDLE_POOLS {
+            debug!(
+                "Already have {} idle pools, containers for {} won't be pooled",
+                pools.len(),
+                image_tag
+            );
+            return None;
         }
+
+        let pool = Arc::new(IdleContainerCreator::new(self.config.for_image(image_tag)));
+        pools.insert(image_tag.to_string(), pool.clone());
+        Some(pool)
+    }
+
+    fn pools(&self) -> Vec<(String, Arc<IdleContainerCreator>)> {
+        self.pools
+            .lock()
+            .iter()
+            .map(|(image_tag, pool)| (image_tag.clone(), pool.clone()))
+            .collect()
     }
 }
 
 lazy_static! {
-    pub static ref GLOBAL_IDLE_CONTAINER_CREATOR: IdleContainerCreator = { IdleContainerCreator::new() };
+    // The environment is only read here, so the config is fixed for the lifetime of the worker
+    pub static ref IDLE_POOLS: IdlePools = IdlePools::new(IdleContainerConfig::from_env());
+
+    // Including the default image's pool, every pool keeps `V9_IDLE_CACHE_SIZE` containers warm
+    static ref MAX_IDLE_POOLS: usize = env_or("V9_IDLE_MAX_POOLS", DEFAULT_MAX_IDLE_POOLS);
+}
+
+// Starts filling the image's pool, so the component's first call doesn't have to wait for a container
+pub fn warm_idle_pool(image_tag: &str) {
+    IDLE_POOLS.pool(image_tag);
+}
+
+// Options (like volumes) have to be set when the container starts, so containers that need different
+// options than the pools' can't come from a pool
+pub fn get_container(image_tag: &str, options: &ContainerOptions) -> Result<V9Container, WorkerError> {
+    let config = idle_container_config();
+
+    // Components that don't ask for a platform are happy with whatever the pools run
+    let mut options = options.clone();
+    if options.platform.is_none() {
+        options.platform.clone_from(&config.platform);
+    }
+
+    match IDLE_POOLS.pool(image_tag) {
+        Some(pool) if options == config.container_options() => pool.get_idle_container(),
+        _ => sync_create_container(&config.for_image(image_tag), &options),
+    }
+}
+
+// Later requests for containers still work, they just always create them synchronously
+pub fn shutdown_idle_pools() {
+    IDLE_POOLS.shut_down.store(true, Ordering::SeqCst);
+    for (_, pool) in IDLE_POOLS.pools() {
+        pool.shutdown();
+    }
+}
+
+// Summed over every pool, which `idle_pools_metrics` splits up by image
+pub fn idle_pool_metrics() -> IdlePoolMetrics {
+    idle_pools_metrics()
+        .values()
+        .fold(IdlePoolMetrics::default(), |total, metrics| IdlePoolMetrics {
+            containers_created: total.containers_created + metrics.containers_created,
+            containers_handed_out: total.containers_handed_out + metrics.containers_handed_out,
+            cache_hits: total.cache_hits + metrics.cache_hits,
+            synchronous_fallbacks: total.synchronous_fallbacks + metrics.synchronous_fallbacks,
+        })
 }
 
-pub fn get_idle_container() -> Result<V9Container, WorkerError> {
-    GLOBAL_IDLE_CONTAINER_CREATOR.get_idle_container()
+pub fn idle_pools_metrics() -> BTreeMap<String, IdlePoolMetrics> {
+    IDLE_POOLS
+        .pools()
+        .into_iter()
+        .map(|(image_tag, pool)| (image_tag, pool.metrics()))
+        .collect()
+}
+
+// Summed over every pool, which `idle_pools_status` splits up by image
+pub fn idle_pool_status() -> IdlePoolStatus {
+    idle_pools_status()
+        .values()
+        .fold(IdlePoolStatus::default(), |total, status| IdlePoolStatus {
+            warm_containers: total.warm_containers + status.warm_containers,
+            capacity: total.capacity + status.capacity,
+            populators: total.populators + status.populators,
+            populators_alive: total.populators_alive + status.populators_alive,
+        })
+}
+
+pub fn idle_pools_status() -> BTreeMap<String, IdlePoolStatus> {
+    IDLE_POOLS
+        .pools()
+        .into_iter()
+        .map(|(image_tag, pool)| (image_tag, pool.status()))
+        .collect()
+}
+
+// The config of the default image's pool, the other pools only differ in their image
+pub fn idle_container_config() -> &'static IdleContainerConfig {
+    &IDLE_POOLS.config
+}
+
+pub fn is_pooled_image(image_tag: &str) -> bool {
+    IDLE_POOLS.pools.lock().contains_key(image_tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One test, since the environment is shared by every test thread
+    #[test]
+    fn the_environment_sizes_the_pool() {
+        env::set_var("V9_IDLE_CACHE_SIZE", "7");
+        env::set_var("V9_IDLE_POPULATOR_COUNT", "5");
+        env::set_var("V9_IDLE_IMAGE", "python:3.9-alpine");
+        let config = IdleContainerConfig::from_env();
+        assert_eq!(config.cache_channel_size, 7);
+        assert_eq!(config.cache_populator_count, 5);
+        assert_eq!(config.image_tag, "python:3.9-alpine");
+
+        env::remove_var("V9_IDLE_CACHE_SIZE");
+        env::remove_var("V9_IDLE_POPULATOR_COUNT");
+        env::remove_var("V9_IDLE_IMAGE");
+        let config = IdleContainerConfig::from_env();
+        assert_eq!(config.cache_channel_size, DEFAULT_CONTAINER_CACHE_CHANNEL_SIZE);
+        assert_eq!(config.cache_populator_count, DEFAULT_CACHE_POPULATOR_COUNT);
+        assert_eq!(config.image_tag, DEFAULT_CONTAINER_IMAGE_TAG);
+    }
 }
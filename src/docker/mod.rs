@@ -1,35 +1,65 @@
 pub mod idle_container_creator;
 
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fmt::Debug;
-use std::fs::remove_file;
+use std::fs::{remove_file, File};
+use std::io;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
 use rand;
 use regex::Regex;
-use subprocess::{Exec, ExitStatus, Popen, Redirection};
+use subprocess::{Exec, ExitStatus, Popen, PopenError, Redirection};
 
 use crate::component::LogPolicy;
+use crate::docker::idle_container_creator::is_pooled_image;
+use crate::env_utils::env_or;
 use crate::error::{WorkerError, WorkerErrorKind};
 use crate::fs_utils::canonicalize;
+use crate::model::{ActivateRequest, ResourceUsage, VolumeMount};
 use crate::named_pipe::NamedPipe;
 
+// What the docker CLI prints when it can't talk to the daemon at all, as opposed to a command failing
+const DAEMON_UNREACHABLE_MARKERS: &[&str] =
+    &["Cannot connect to the Docker daemon", "error during connect"];
+
 fn call_docker_sync<S: AsRef<OsStr> + Debug>(
     argv: &[S],
 ) -> Result<(ExitStatus, String, String), WorkerError> {
     debug!("Calling (sync) docker {:?}", argv);
-    let docker_res = Exec::cmd("docker")
+    let docker_res = match Exec::cmd("docker")
         .args(argv)
         .stdout(Redirection::Pipe)
         .stderr(Redirection::Pipe)
-        .capture()?;
+        .capture()
+    {
+        Ok(docker_res) => docker_res,
+        // Without the CLI there's no way to reach the daemon either
+        Err(PopenError::IoError(e)) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(
+                WorkerErrorKind::DockerUnavailable(format!("docker CLI not found ({})", e)).into(),
+            );
+        }
+        Err(e) => return Err(e.into()),
+    };
     let exit_status = docker_res.exit_status;
     let stdout = String::from_utf8(docker_res.stdout)?;
     let stderr = String::from_utf8(docker_res.stderr)?;
     debug!("Finished calling (sync) docker");
 
     if !exit_status.success() {
+        if DAEMON_UNREACHABLE_MARKERS
+            .iter()
+            .any(|marker| stderr.contains(marker))
+        {
+            return Err(WorkerErrorKind::DockerUnavailable(stderr.trim().to_string()).into());
+        }
         return Err(WorkerErrorKind::Docker(exit_status, stdout, stderr).into());
     }
     Ok((exit_status, stdout, stderr))
@@ -49,27 +79,320 @@ fn call_docker_async(docker_args: &[&str], log_policy: &Arc<LogPolicy>) -> Resul
     Ok(docker_subprocess)
 }
 
+// Every containerized component is run with this
+pub const CONTAINER_RUNTIME: &str = "docker";
+
+// Bounds on the backoff between readiness checks in `V9Container::wait_until_running`
+const READINESS_POLL_MIN_DELAY: Duration = Duration::from_millis(20);
+const READINESS_POLL_MAX_DELAY: Duration = Duration::from_millis(500);
+
+// Mounting any of these (or a parent of them) into a component would let it escape isolation
+const SENSITIVE_HOST_PATHS: &[&str] = &[
+    "/boot",
+    "/dev",
+    "/etc",
+    "/proc",
+    "/run/docker.sock",
+    "/sys",
+    "/var/run/docker.sock",
+];
+
+// Per component configuration for `docker run`
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ContainerOptions {
+    // Validated "host:container[:ro]" volume specs
+    volume_specs: Vec<String>,
+    // Something like "linux/arm64", `None` means the host's platform
+    platform: Option<String>,
+    // `None` means docker's default bridge network
+    network_mode: Option<String>,
+    // `None` means whatever user the image runs as
+    user: Option<String>,
+    read_only_rootfs: bool,
+    // `None` means the daemon's default runtime
+    runtime: Option<String>,
+}
+
+impl ContainerOptions {
+    pub fn from_activate_request(ar: &ActivateRequest) -> Result<Self, WorkerError> {
+        let volume_specs = ar.volumes.iter().map(volume_spec).collect::<Result<_, _>>()?;
+
+        if let Some(platform) = &ar.platform {
+            validate_platform(platform)?;
+        }
+        if let Some(network_mode) = &ar.network_mode {
+            validate_network_mode(network_mode)?;
+        }
+        if let Some(user) = &ar.run_as_user {
+            validate_container_user(user)?;
+        }
+        if let Some(runtime) = &ar.runtime {
+            check_runtime(runtime)?;
+        }
+
+        Ok(Self {
+            volume_specs,
+            platform: ar.platform.clone(),
+            network_mode: ar.network_mode.clone(),
+            user: ar.run_as_user.clone(),
+            read_only_rootfs: ar.read_only_rootfs,
+            runtime: ar.runtime.clone(),
+        })
+    }
+
+    pub fn with_platform(platform: Option<String>) -> Self {
+        Self {
+            platform,
+            ..Self::default()
+        }
+    }
+
+    pub fn platform(&self) -> Option<&str> {
+        self.platform.as_deref()
+    }
+
+    // Whether the container runs without root or a writable root filesystem
+    pub fn is_restricted(&self) -> bool {
+        self.user.is_some() || self.read_only_rootfs
+    }
+
+    fn docker_run_args(&self) -> Vec<&str> {
+        let mut args = Vec::new();
+        for spec in &self.volume_specs {
+            args.push("-v");
+            args.push(spec.as_str());
+        }
+        if let Some(platform) = &self.platform {
+            args.push("--platform");
+            args.push(platform.as_str());
+        }
+        if let Some(network_mode) = &self.network_mode {
+            args.push("--network");
+            args.push(network_mode.as_str());
+        }
+        if let Some(user) = &self.user {
+            args.push("--user");
+            args.push(user.as_str());
+        }
+        // The pipes are bind mounts, so they stay usable with a read-only root filesystem
+        if self.read_only_rootfs {
+            args.push("--read-only");
+            args.push("--tmpfs");
+            args.push("/tmp");
+        }
+        if let Some(runtime) = &self.runtime {
+            args.push("--runtime");
+            args.push(runtime.as_str());
+        }
+        args
+    }
+}
+
+fn validate_platform(platform: &str) -> Result<(), WorkerError> {
+    // Platforms look like "os/arch[/variant]", and this also stops them from being read as a docker flag
+    let valid = platform.split('/').count() >= 2
+        && platform.split('/').all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(WorkerErrorKind::UnavailablePlatform(
+            platform.to_string(),
+            "expected a platform like \"linux/arm64\"".to_string(),
+        )
+        .into())
+    }
+}
+
+fn validate_network_mode(network_mode: &str) -> Result<(), WorkerError> {
+    let invalid = |problem| WorkerErrorKind::InvalidNetworkMode(network_mode.to_string(), problem);
+
+    // Components are untrusted, so they can't share a network namespace with anything else
+    if network_mode == "host" {
+        return Err(invalid(
+            "host networking would let the component reach every service listening on the host, \
+             including this worker's meta endpoints",
+        )
+        .into());
+    }
+    if network_mode.starts_with("container:") {
+        return Err(invalid(
+            "joining another container's network would let the component intercept that container's traffic",
+        )
+        .into());
+    }
+
+    // Docker's rules for network names, which also stops the mode from being read as a docker flag
+    let valid = network_mode.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && network_mode
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(invalid("expected \"none\", \"bridge\", or the name of a user-defined network").into())
+    }
+}
+
+fn validate_container_user(user: &str) -> Result<(), WorkerError> {
+    let invalid = |problem| WorkerErrorKind::InvalidContainerUser(user.to_string(), problem);
+
+    // Docker accepts "user", "uid", "user:group" and "uid:gid", and names follow the usual unix rules, which
+    // also stops the user from being read as a docker flag
+    let valid_part = |part: &str| {
+        part.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+    };
+    let mut parts = user.splitn(2, ':');
+    let user_part = parts.next().unwrap_or("");
+    if !valid_part(user_part) || !parts.next().map_or(true, valid_part) {
+        return Err(invalid("expected a user name or uid, optionally followed by \":group\"").into());
+    }
+
+    // The point of setting a user is to drop root
+    if user_part == "root" || user_part == "0" {
+        return Err(invalid(
+            "components can't be run as root explicitly, leave run_as_user unset instead",
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// Images are pulled when their first container starts, so all we can check here is that the tag looks like one
+pub fn validate_image_tag(tag: &str) -> Result<(), WorkerError> {
+    // Docker's references are "[registry/]name[:tag][@digest]", and a tag that starts with a letter or digit can't
+    // be read as a docker flag
+    let valid = tag.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.-/:@".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(WorkerErrorKind::InvalidImageTag(
+            tag.to_string(),
+            "expected an image reference like \"node:14-alpine\"",
+        )
+        .into())
+    }
+}
+
+// Docker only notices an unknown runtime when a container is started, so we check with the daemon up front
+// (which also means a runtime can't be read as a docker flag, since the daemon's runtimes have real names)
+fn check_runtime(runtime: &str) -> Result<(), WorkerError> {
+    let (_, stdout, _) = call_docker_sync(&["info", "--format", "{{json .Runtimes}}"])?;
+    let runtimes: HashMap<String, serde_json::Value> = serde_json::from_str(stdout.trim())?;
+
+    if runtimes.contains_key(runtime) {
+        Ok(())
+    } else {
+        let mut registered: Vec<&str> = runtimes.keys().map(String::as_str).collect();
+        registered.sort_unstable();
+        Err(WorkerErrorKind::UnavailableRuntime(
+            runtime.to_string(),
+            format!(
+                "it isn't registered with the daemon, which has {}",
+                registered.join(", ")
+            ),
+        )
+        .into())
+    }
+}
+
+// Checks that a local image can actually run on `platform`, since docker won't pull a different variant
+// of an image that was loaded from an archive
+pub fn check_image_platform(image: &str, platform: &str) -> Result<(), WorkerError> {
+    let (_, stdout, _) = call_docker_sync(&[
+        "image",
+        "inspect",
+        "--format",
+        "{{.Os}}/{{.Architecture}}{{if .Variant}}/{{.Variant}}{{end}}",
+        image,
+    ])?;
+    let image_platform = stdout.trim();
+
+    // Either side may leave off the variant (e.g. "linux/arm64" vs "linux/arm64/v8")
+    if image_platform.starts_with(platform) || platform.starts_with(image_platform) {
+        Ok(())
+    } else {
+        Err(WorkerErrorKind::UnavailablePlatform(
+            platform.to_string(),
+            format!("image {} is built for {}", image, image_platform),
+        )
+        .into())
+    }
+}
+
+fn volume_spec(volume: &VolumeMount) -> Result<String, WorkerError> {
+    let invalid = |problem| WorkerErrorKind::InvalidVolumeMount(volume.host_path.clone(), problem);
+
+    // This also checks that the host path exists
+    let host_path = canonicalize(Path::new(&volume.host_path))?;
+
+    let is_sensitive = SENSITIVE_HOST_PATHS.iter().any(|sensitive| {
+        Path::new(sensitive).starts_with(&host_path) || Path::new(&host_path).starts_with(sensitive)
+    });
+    if is_sensitive {
+        return Err(invalid("mounting this host path is not allowed").into());
+    }
+    if !volume.container_path.starts_with('/') {
+        return Err(invalid("the container path must be absolute").into());
+    }
+    // Colons are the separator in docker's volume syntax
+    if host_path.contains(':') || volume.container_path.contains(':') {
+        return Err(invalid("paths cannot contain ':'").into());
+    }
+
+    let mut spec = format!("{}:{}", host_path, volume.container_path);
+    if volume.read_only {
+        spec.push_str(":ro");
+    }
+    Ok(spec)
+}
+
 #[derive(Debug)]
 pub struct V9Container {
     named_pipe: NamedPipe,
 
     docker_container_name: String,
     docker_run_process: Popen,
+    // Set when the component failed inside the container, without the container itself exiting
+    component_failed: bool,
 }
 
 fn container_name(image: &str) -> String {
     let id: u64 = rand::random();
-    let res = format!("v9_{}_{}", image, id);
 
-    // Remove the invalid colon in the middle of the image name
-    res.replace(":", "_")
+    // Container names can only use [a-zA-Z0-9_.-], and image references can also have ":", "/" and "@" in them
+    let image: String = image
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("v9_{}_{}", image, id)
 }
 
 impl V9Container {
     pub fn start(
         pipe: NamedPipe,
+        control_pipe: Option<&NamedPipe>,
         image: &str,
         image_arguments: &[&str],
+        options: &ContainerOptions,
         log_policy: &Arc<LogPolicy>,
     ) -> Result<Self, WorkerError> {
         let name = container_name(image);
@@ -80,16 +403,25 @@ impl V9Container {
         // Call docker run, mounting the input and output pipes
         let input_mount = format!("{}:{}", c_in, c_in);
         let output_mount = format!("{}:{}", c_out, c_out);
-        let mut docker_args = vec![
-            "run",
-            "--name",
-            &name,
-            "-v",
-            &input_mount,
-            "-v",
-            &output_mount,
-            image,
-        ];
+        let mut docker_args = vec!["run", "--name", &name, "-v", &input_mount, "-v", &output_mount];
+
+        // The control pipe (if the component has one) is mounted the same way
+        let mut control_mounts = Vec::new();
+        if let Some(control_pipe) = control_pipe {
+            for fifo in &[
+                control_pipe.component_input_file(),
+                control_pipe.component_output_file(),
+            ] {
+                let fifo = canonicalize(fifo)?;
+                control_mounts.push(format!("{}:{}", fifo, fifo));
+            }
+        }
+        for mount in &control_mounts {
+            docker_args.push("-v");
+            docker_args.push(mount);
+        }
+        docker_args.extend(options.docker_run_args());
+        docker_args.push(image);
         docker_args.extend_from_slice(image_arguments);
 
         let docker_subprocess = call_docker_async(&docker_args, log_policy)?;
@@ -98,9 +430,14 @@ impl V9Container {
             named_pipe: pipe,
             docker_container_name: name,
             docker_run_process: docker_subprocess,
+            component_failed: false,
         })
     }
 
+    pub fn name(&self) -> &str {
+        &self.docker_container_name
+    }
+
     pub fn pipe(&mut self) -> &mut NamedPipe {
         &mut self.named_pipe
     }
@@ -109,6 +446,51 @@ impl V9Container {
         &mut self.docker_run_process
     }
 
+    // Polls the daemon until the container is up, backing off between checks
+    // `docker run` may have to pull the image first, so this can take a while on a fresh host
+    pub fn wait_until_running(&mut self, timeout: Duration) -> Result<(), WorkerError> {
+        let start = Instant::now();
+        let mut delay = READINESS_POLL_MIN_DELAY;
+        loop {
+            if let Some(exit_status) = self.docker_run_process.poll() {
+                return Err(WorkerErrorKind::SubprocessTerminated(exit_status, None).into());
+            }
+
+            match call_docker_sync(&[
+                "inspect",
+                "--format",
+                "{{.State.Running}}",
+                &self.docker_container_name,
+            ]) {
+                Ok((_, stdout, _)) if stdout.trim() == "true" => return Ok(()),
+                // Inspect fails until the container has been created
+                Err(e) if !matches!(e.kind(), WorkerErrorKind::Docker(..)) => return Err(e),
+                _ => {}
+            }
+
+            if start.elapsed() >= timeout {
+                return Err(WorkerErrorKind::OperationTimedOut("container start").into());
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(READINESS_POLL_MAX_DELAY);
+        }
+    }
+
+    // Only meaningful once the container has exited
+    pub fn oom_killed(&self) -> Result<bool, WorkerError> {
+        let (_, stdout, _) = call_docker_sync(&[
+            "inspect",
+            "--format",
+            "{{.State.OOMKilled}}",
+            &self.docker_container_name,
+        ])?;
+        Ok(stdout.trim() == "true")
+    }
+
+    pub fn mark_component_failed(&mut self) {
+        self.component_failed = true;
+    }
+
     pub fn exec_sync(&self, command: &[&str]) -> Result<(ExitStatus, String, String), WorkerError> {
         let mut docker_args = vec!["exec", &self.docker_container_name];
         docker_args.extend_from_slice(command);
@@ -118,9 +500,10 @@ impl V9Container {
     pub fn exec_async(
         &self,
         command: &[&str],
+        working_dir: &str,
         log_policy: &Arc<LogPolicy>,
     ) -> Result<Popen, WorkerError> {
-        let mut docker_args = vec!["exec", &self.docker_container_name];
+        let mut docker_args = vec!["exec", "--workdir", working_dir, &self.docker_container_name];
         docker_args.extend_from_slice(command);
         call_docker_async(&docker_args, log_policy)
     }
@@ -137,10 +520,91 @@ impl V9Container {
 
         Ok(())
     }
+
+    // Everything the container has written to stdout/stderr, as recorded by the docker daemon
+    // NOTE: This doesn't include the output of `docker exec`ed commands
+    pub fn logs(&self) -> Result<String, WorkerError> {
+        let (_, stdout, stderr) = call_docker_sync(&["logs", &self.docker_container_name])?;
+        Ok(stdout + &stderr)
+    }
+
+    // Note: `docker stats` takes a second or two to sample, so this is slow
+    pub fn resource_usage(&self) -> Result<ResourceUsage, WorkerError> {
+        let (_, stdout, _) = call_docker_sync(&[
+            "stats",
+            "--no-stream",
+            "--format",
+            "{{.CPUPerc}} {{.MemUsage}}",
+            &self.docker_container_name,
+        ])?;
+
+        // The output looks like "0.05% 1.32MiB / 1.944GiB"
+        let invalid_output = || {
+            WorkerErrorKind::InvalidSerialization(
+                "unexpected docker stats output",
+                stdout.clone().into_bytes(),
+            )
+        };
+        let mut fields = stdout.split_whitespace();
+
+        let cpu_percent = fields
+            .next()
+            .and_then(|cpu| cpu.trim_end_matches('%').parse::<f64>().ok())
+            .ok_or_else(invalid_output)?;
+        let memory_bytes = fields
+            .next()
+            .and_then(parse_docker_size)
+            .ok_or_else(invalid_output)?;
+
+        Ok(ResourceUsage {
+            memory_bytes,
+            cpu_percent,
+        })
+    }
+}
+
+// Parses sizes like "1.32MiB" or "512kB" into a number of bytes
+fn parse_docker_size(size: &str) -> Option<f64> {
+    let unit_start = size.find(|c: char| c.is_ascii_alphabetic())?;
+    let (number, unit) = size.split_at(unit_start);
+
+    let multiplier = match unit {
+        "B" => 1.0,
+        "kB" => 1e3,
+        "KiB" => 1024.0,
+        "MB" => 1e6,
+        "MiB" => 1024.0 * 1024.0,
+        "GB" => 1e9,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "TB" => 1e12,
+        "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+
+    number.parse::<f64>().ok().map(|n| n * multiplier)
 }
 
 impl Drop for V9Container {
     fn drop(&mut self) {
+        // `docker run` exits with the container's exit code
+        let container_failed = self
+            .docker_run_process
+            .poll()
+            .map_or(false, |status| !status.success());
+        let name = &self.docker_container_name;
+
+        if (container_failed || self.component_failed) && *KEEP_FAILED_CONTAINERS {
+            warn!(
+                "Keeping failed container {} for inspection, remove it with `{} rm -f {}` when done",
+                name, CONTAINER_RUNTIME, name
+            );
+            if let Err(e) = kill_container(name) {
+                warn!("Failed to stop container {}, err {}", name, e);
+            }
+        } else if let Err(e) = remove_container(name) {
+            warn!("Failed to remove container {}, err {}", name, e);
+        }
+
         if let Err(e) = self.docker_run_process.terminate() {
             self.docker_run_process.detach();
 
@@ -149,6 +613,59 @@ impl Drop for V9Container {
     }
 }
 
+// Asks the daemon (not just the CLI) for its version, so this fails if the daemon isn't reachable
+pub fn check_docker_daemon() -> Result<String, WorkerError> {
+    let (_, stdout, _) = call_docker_sync(&["version", "--format", "{{.Server.Version}}"])?;
+    Ok(stdout.trim().to_string())
+}
+
+// Set once the runtime is known to run linux containers (the answer can't change while the daemon is up)
+static LINUX_CONTAINERS_CONFIRMED: AtomicBool = AtomicBool::new(false);
+
+// Our containers are all linux containers, but the host doesn't have to be linux (e.g. Docker Desktop on a
+// Mac runs them in a VM), so we ask the daemon rather than checking our own OS
+pub fn check_linux_containers() -> Result<(), WorkerError> {
+    if LINUX_CONTAINERS_CONFIRMED.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let (_, stdout, _) = call_docker_sync(&["info", "--format", "{{.OSType}}"])?;
+    match stdout.trim() {
+        "linux" => {
+            LINUX_CONTAINERS_CONFIRMED.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+        os_type => Err(WorkerErrorKind::UnsupportedPlatform(format!(
+            "{} runs {:?} containers, but components need linux containers",
+            CONTAINER_RUNTIME, os_type
+        ))
+        .into()),
+    }
+}
+
+pub fn kill_container(name: &str) -> Result<(), WorkerError> {
+    call_docker_sync(&["kill", name])?;
+    Ok(())
+}
+
+// Kills the container if it's still running, then removes it entirely
+pub fn remove_container(name: &str) -> Result<(), WorkerError> {
+    call_docker_sync(&["rm", "-f", name])?;
+    Ok(())
+}
+
+lazy_static! {
+    // Stopped containers of failed components are normally removed like any other, this keeps them around so
+    // an operator can look at their logs and state
+    static ref KEEP_FAILED_CONTAINERS: bool = env_or("V9_CONTAINER_KEEP_ON_FAILURE", false);
+}
+
+lazy_static! {
+    // Several components can load archives of the same image, so we count references to each loaded tag
+    // (That way an image is only removed once the last component using it is gone)
+    static ref LOADED_IMAGE_REFERENCES: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
 pub fn load_docker_image(archive_file: &str) -> Result<String, WorkerError> {
     // we are calling docker load, with quiet mode enabled to suppress excess output
     let (load_exit_status, load_stdout, load_stderr) =
@@ -176,5 +693,204 @@ pub fn load_docker_image(archive_file: &str) -> Result<String, WorkerError> {
         Err(e) => error!("Failed to delete tar file after loading image: {}", e),
     }
 
+    retain_docker_image(tag);
+
     Ok(tag.to_string())
 }
+
+// Builds `context` (a directory, or a tar archive of one) into an image tagged `tag`
+// Docker's output goes to `log_policy`, so a failed build can be diagnosed from the component's logs
+// NOTE: Unlike `load_docker_image`, this doesn't take a reference to the image (see `retain_docker_image`)
+pub fn build_docker_image(
+    context: &str,
+    tag: &str,
+    options: &ContainerOptions,
+    log_policy: &Arc<LogPolicy>,
+) -> Result<(), WorkerError> {
+    let mut config = log_policy.get_popen_config()?;
+    config.detached = false;
+
+    let mut argv = vec!["docker", "build", "-t", tag];
+    if let Some(platform) = options.platform() {
+        argv.push("--platform");
+        argv.push(platform);
+    }
+    if Path::new(context).is_dir() {
+        argv.push(context);
+    } else {
+        // A "-" context tells docker to read a tar archive from stdin
+        argv.push("-");
+        config.stdin = Redirection::File(File::open(context)?);
+    }
+
+    debug!("Building docker image {:?}", argv);
+    let mut build_process = Popen::create(&argv, config)?;
+    let exit_status = build_process.wait()?;
+
+    if !exit_status.success() {
+        return Err(WorkerErrorKind::Docker(
+            exit_status,
+            String::new(),
+            format!(
+                "building {} failed, see the component's logs for docker's output",
+                context
+            ),
+        )
+        .into());
+    }
+    debug!("Built image (tag = {:?})", tag);
+
+    Ok(())
+}
+
+pub fn docker_image_exists(tag: &str) -> bool {
+    call_docker_sync(&["image", "inspect", tag]).is_ok()
+}
+
+// Records another user of an image, which must be paired with a call to `release_docker_image`
+pub fn retain_docker_image(tag: &str) {
+    *LOADED_IMAGE_REFERENCES.lock().entry(tag.to_string()).or_insert(0) += 1;
+}
+
+// Counterpart to `load_docker_image`/`retain_docker_image`, removes the image once nothing else is using it
+pub fn release_docker_image(tag: &str) {
+    let mut references = LOADED_IMAGE_REFERENCES.lock();
+    match references.get_mut(tag) {
+        Some(count) if *count > 1 => {
+            *count -= 1;
+            debug!("Image {} still loaded by {} other components", tag, count);
+            return;
+        }
+        Some(_) => {
+            references.remove(tag);
+        }
+        None => {
+            warn!("Attempted to release image {} which was never loaded", tag);
+            return;
+        }
+    }
+    // No need to keep the lock while calling docker
+    drop(references);
+
+    // The idle container pools depend on their base images, so we never remove those
+    if is_pooled_image(tag) {
+        debug!("Not removing shared base image {}", tag);
+        return;
+    }
+
+    if let Err(e) = remove_docker_image(tag) {
+        error!("Failed to remove docker image {}: {}", tag, e);
+    }
+}
+
+fn remove_docker_image(tag: &str) -> Result<(), WorkerError> {
+    // Only remove the image if no containers (running or stopped) still reference it
+    let (_, containers, _) =
+        call_docker_sync(&["ps", "-a", "-q", "--filter", &format!("ancestor={}", tag)])?;
+    if !containers.trim().is_empty() {
+        info!(
+            "Not removing image {}, since containers still reference it: {:?}",
+            tag, containers
+        );
+        return Ok(());
+    }
+
+    call_docker_sync(&["rmi", tag])?;
+    debug!("Removed image {}", tag);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn container_names_only_use_valid_characters() {
+        for image in &[
+            "python:3.7-alpine",
+            "bitnami/node:14",
+            "ghcr.io/org/py:3.9",
+            "localhost:5000/py@sha256:0123abcd",
+        ] {
+            let name = container_name(image);
+            assert!(name.starts_with("v9_"), "{}", name);
+            assert!(
+                name.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-'),
+                "{:?} gave the invalid container name {:?}",
+                image,
+                name
+            );
+        }
+        assert!(container_name("ghcr.io/org/py:3.9").starts_with("v9_ghcr.io_org_py_3.9_"));
+    }
+
+    #[test]
+    fn every_valid_image_tag_gives_a_valid_container_name() {
+        let image = "ghcr.io/org/py:3.9@sha256:0123abcd";
+        validate_image_tag(image).unwrap();
+        assert!(!container_name(image).contains(&['/', ':', '@'][..]));
+    }
+
+    #[test]
+    fn docker_sizes_are_parsed_into_bytes() {
+        assert_eq!(parse_docker_size("512B"), Some(512.0));
+        assert_eq!(parse_docker_size("2kB"), Some(2000.0));
+        assert_eq!(parse_docker_size("1.5KiB"), Some(1536.0));
+        assert_eq!(parse_docker_size("1.32MiB"), Some(1.32 * 1024.0 * 1024.0));
+        assert_eq!(parse_docker_size("3GB"), Some(3e9));
+        assert_eq!(parse_docker_size("1TiB"), Some(1024.0f64.powi(4)));
+
+        assert_eq!(parse_docker_size("512"), None);
+        assert_eq!(parse_docker_size("MiB"), None);
+        assert_eq!(parse_docker_size("1.3XB"), None);
+        assert_eq!(parse_docker_size("1.3 MiB"), None);
+    }
+
+    #[test]
+    fn only_isolated_network_modes_are_allowed() {
+        for valid in &["none", "bridge", "my-net_1.internal"] {
+            assert!(validate_network_mode(valid).is_ok(), "{:?}", valid);
+        }
+        for invalid in &["host", "container:abc", "", "-net", "net work", "net/work"] {
+            assert!(validate_network_mode(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn container_users_cant_be_root() {
+        for valid in &["app", "1000", "app:staff", "1000:1000", "_svc.user-1", "app:0"] {
+            assert!(validate_container_user(valid).is_ok(), "{:?}", valid);
+        }
+        for invalid in &[
+            "root",
+            "0",
+            "root:staff",
+            "0:0",
+            "",
+            ":staff",
+            "app:",
+            "-u",
+            "app:-g",
+            "a b",
+        ] {
+            assert!(validate_container_user(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn image_tags_look_like_image_references() {
+        for valid in &[
+            "node:14-alpine",
+            "python",
+            "localhost:5000/team/app:1.0",
+            "app@sha256:0123abcd",
+        ] {
+            assert!(validate_image_tag(valid).is_ok(), "{:?}", valid);
+        }
+        for invalid in &["", "--privileged", ":14", "node 14", "node:14;rm"] {
+            assert!(validate_image_tag(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,17 @@
+use std::env;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+// Reads `name` from the environment, falling back to `default` if it is unset or can't be parsed
+pub fn env_or<T: FromStr + Debug>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            warn!(
+                "Invalid value {:?} for {}, using default {:?}",
+                value, name, default
+            );
+            default
+        }),
+        Err(_) => default,
+    }
+}
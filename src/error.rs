@@ -7,16 +7,28 @@ use std::str::Utf8Error;
 use std::string::FromUtf8Error;
 
 use failure::Backtrace;
+use flexi_logger::FlexiLoggerError;
+use hyper::header::{InvalidHeaderValue, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
 use hyper::{Body, Response, StatusCode};
 use subprocess::{ExitStatus, PopenError};
 use tokio::task::JoinError;
 
+use crate::model::{
+    ComponentErrorResponse, ComponentNotFoundResponse, ComponentPath, HandlerNotFoundResponse,
+};
+
 // TODO: Add `type WorkerResult<V> = Result<V, WorkerError>`, and use that everywhere
 
+// The status of errors that don't map to a more specific one (see `Into<Response<Body>>` below), as the spec
+// demands. It's a server error, but not a standard one, so clients can tell our errors from the component's.
+pub const WORKER_ERROR_STATUS: u16 = 543;
+
 #[derive(Debug)]
 pub struct WorkerError {
     kind: WorkerErrorKind,
     backtrace: Backtrace,
+    // The component being called when this happened, if any (boxed, since errors are passed around a lot)
+    component: Option<Box<ComponentPath>>,
 }
 
 impl WorkerError {
@@ -24,7 +36,29 @@ impl WorkerError {
         Self {
             kind,
             backtrace: Backtrace::new(),
+            component: None,
+        }
+    }
+
+    // Component crashes are reported with a JSON body naming the component (see `into`)
+    pub fn in_component(mut self, path: &ComponentPath) -> Self {
+        self.component = Some(Box::new(path.clone()));
+        self
+    }
+
+    pub fn kind(&self) -> &WorkerErrorKind {
+        &self.kind
+    }
+
+    // Attaches the end of a crashed component's output, so the error explains the crash by itself
+    // (`output_tail` is only called if this actually is a termination or start script error)
+    pub fn with_output_tail(mut self, output_tail: impl FnOnce() -> Option<String>) -> Self {
+        match &mut self.kind {
+            WorkerErrorKind::StartScriptFailed(_, tail @ None)
+            | WorkerErrorKind::SubprocessTerminated(_, tail @ None) => *tail = output_tail(),
+            _ => {}
         }
+        self
     }
 }
 
@@ -38,29 +72,97 @@ impl From<WorkerErrorKind> for WorkerError {
 
 #[derive(Debug)]
 pub enum WorkerErrorKind {
+    // The URL, and what went wrong
+    ArtifactDownload(String, String),
+    // Carries how many seconds are left on the breaker's cooldown
+    CircuitOpen(u64),
+    ComponentBusy,
+    ComponentNotFound(ComponentPath),
     Docker(ExitStatus, String, String),
+    DockerUnavailable(String),
+    HandlerNotFound(ComponentPath, String),
+    // The file, the hash it should have had, and the one it has
+    HashMismatch(String, String, String),
     Hyper(hyper::error::Error),
+    IncompatibleContainerOptions(&'static str),
     Io(io::Error),
     IntegerConversion(TryFromIntError),
     InternalJsonHandling(serde_json::Error),
+    InvalidComponentResponse(String),
+    InvalidContainerUser(String, &'static str),
+    InvalidHeaderValue(InvalidHeaderValue),
+    InvalidImageTag(String, &'static str),
+    InvalidLogSpec(FlexiLoggerError),
+    InvalidNetworkMode(String, &'static str),
+    InvalidPercentileMark(f64),
     InvalidSerialization(&'static str, Vec<u8>),
+    // The setting's name, and what's wrong with it
+    InvalidSetting(String, String),
+    InvalidStatWindow(u64),
     InvalidUtf8(Utf8Error),
+    InvalidVolumeMount(String, &'static str),
+    // Carries the limit, in bytes
+    MessageTooLarge(usize),
+    MissingComponentFile(String, &'static str),
+    MissingExecutable(&'static str),
+    MissingQueryParameter(&'static str),
     Nix(nix::Error),
     OperationTimedOut(&'static str),
     OsStringConversion(OsString),
+    Overloaded,
+    Panic(String),
     PathNotFound(String),
+    PayloadTooLarge(usize),
     PipeDisconnected,
+    RateLimited,
     Regex(regex::Error),
+    // Also carries the end of the script's output, if we could get it
+    StartScriptFailed(String, Option<String>),
     SubprocessStart(PopenError),
-    SubprocessTerminated(ExitStatus),
+    // Also carries the end of the component's output, if we could get it
+    SubprocessTerminated(ExitStatus, Option<String>),
     TokioJoinError(JoinError),
-    UnsupportedPlatform(&'static str),
+    Unauthorized,
+    UnavailablePlatform(String, String),
+    // The runtime, and why it can't be used
+    UnavailableRuntime(String, String),
+    UnsupportedPlatform(String),
     WrongMethod,
 }
 
 impl Display for WorkerError {
+    // This is just one arm per error kind, so there's no point splitting it up
+    #[allow(clippy::too_many_lines)]
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         match &self.kind {
+            WorkerErrorKind::ArtifactDownload(url, problem) => {
+                write!(
+                    f,
+                    "WorkerError, could not download component from {}: {}",
+                    url, problem
+                )?;
+            }
+
+            WorkerErrorKind::CircuitOpen(retry_after) => {
+                write!(
+                    f,
+                    "WorkerError, component is failing repeatedly, calls are paused for {} more seconds",
+                    retry_after
+                )?;
+            }
+
+            WorkerErrorKind::ComponentBusy => {
+                write!(f, "WorkerError, component has too many calls in flight")?;
+            }
+
+            WorkerErrorKind::ComponentNotFound(path) => {
+                write!(
+                    f,
+                    "WorkerError, no component is active at {}/{}",
+                    path.user, path.repo
+                )?;
+            }
+
             WorkerErrorKind::Docker(exit_status, stdout, stderr) => {
                 write!(
                     f,
@@ -71,10 +173,34 @@ impl Display for WorkerError {
                 )?;
             }
 
+            WorkerErrorKind::DockerUnavailable(reason) => {
+                write!(f, "WorkerError, Docker daemon not reachable: {}", reason)?;
+            }
+
+            WorkerErrorKind::HandlerNotFound(path, handler) => {
+                write!(
+                    f,
+                    "WorkerError, component {}/{} has no handler named {:?}",
+                    path.user, path.repo, handler
+                )?;
+            }
+
+            WorkerErrorKind::HashMismatch(path, expected, actual) => {
+                write!(
+                    f,
+                    "WorkerError, component file {} has SHA-256 {}, but the component's hash is {}",
+                    path, actual, expected
+                )?;
+            }
+
             WorkerErrorKind::Hyper(e) => {
                 write!(f, "WorkerError, caused by internal hyper error: {}", e)?;
             }
 
+            WorkerErrorKind::IncompatibleContainerOptions(reason) => {
+                write!(f, "WorkerError, incompatible container options: {}", reason)?;
+            }
+
             WorkerErrorKind::Io(e) => {
                 write!(f, "WorkerError, caused by internal I/O error: {}", e)?;
             }
@@ -91,6 +217,34 @@ impl Display for WorkerError {
                 write!(f, "WorkerError, caused by internal serde_json error: {}", e)?;
             }
 
+            WorkerErrorKind::InvalidComponentResponse(problem) => {
+                write!(f, "WorkerError, component sent an invalid response: {}", problem)?;
+            }
+
+            WorkerErrorKind::InvalidContainerUser(user, problem) => {
+                write!(f, "WorkerError, invalid container user {:?}: {}", user, problem)?;
+            }
+
+            WorkerErrorKind::InvalidHeaderValue(e) => {
+                write!(f, "WorkerError, caused by invalid header value: {}", e)?;
+            }
+
+            WorkerErrorKind::InvalidImageTag(tag, problem) => {
+                write!(f, "WorkerError, invalid image tag {:?}: {}", tag, problem)?;
+            }
+
+            WorkerErrorKind::InvalidLogSpec(e) => {
+                write!(f, "WorkerError, caused by invalid log specification: {}", e)?;
+            }
+
+            WorkerErrorKind::InvalidNetworkMode(mode, problem) => {
+                write!(f, "WorkerError, invalid network mode {:?}: {}", mode, problem)?;
+            }
+
+            WorkerErrorKind::InvalidPercentileMark(mark) => {
+                write!(f, "WorkerError, percentile {} is not in the range (0, 100]", mark)?;
+            }
+
             WorkerErrorKind::InvalidSerialization(problem, l) => {
                 write!(
                     f,
@@ -99,10 +253,50 @@ impl Display for WorkerError {
                 )?;
             }
 
+            WorkerErrorKind::InvalidSetting(name, problem) => {
+                write!(f, "WorkerError, invalid setting {}: {}", name, problem)?;
+            }
+
+            WorkerErrorKind::InvalidStatWindow(seconds) => {
+                write!(
+                    f,
+                    "WorkerError, stat window of {} seconds is out of range",
+                    seconds
+                )?;
+            }
+
             WorkerErrorKind::InvalidUtf8(e) => {
                 write!(f, "WorkerError, caused by internal utf8 decode error: {}", e)?;
             }
 
+            WorkerErrorKind::InvalidVolumeMount(path, problem) => {
+                write!(f, "WorkerError, invalid volume mount {}: {}", path, problem)?;
+            }
+
+            WorkerErrorKind::MessageTooLarge(max_bytes) => {
+                write!(
+                    f,
+                    "WorkerError, component sent a message larger than the {} byte limit",
+                    max_bytes
+                )?;
+            }
+
+            WorkerErrorKind::MissingComponentFile(path, problem) => {
+                write!(f, "WorkerError, component file {} is unusable: {}", path, problem)?;
+            }
+
+            WorkerErrorKind::MissingExecutable(executable) => {
+                write!(
+                    f,
+                    "WorkerError, required executable not found on PATH: {}",
+                    executable
+                )?;
+            }
+
+            WorkerErrorKind::MissingQueryParameter(name) => {
+                write!(f, "WorkerError, missing required query parameter {:?}", name)?;
+            }
+
             WorkerErrorKind::Nix(e) => {
                 write!(f, "WorkerError, caused by internal unix error: {}", e)?;
             }
@@ -115,34 +309,80 @@ impl Display for WorkerError {
                 write!(f, "WorkerError, caused by problematic OsString ({:?})", os_string)?;
             }
 
+            WorkerErrorKind::Overloaded => {
+                write!(f, "WorkerError, too many calls in flight")?;
+            }
+
+            WorkerErrorKind::Panic(payload) => {
+                write!(f, "WorkerError, caught a panic: {}", payload)?;
+            }
+
             WorkerErrorKind::PathNotFound(path) => {
                 write!(f, "WorkerError, path not found: {}", path)?;
             }
 
+            WorkerErrorKind::PayloadTooLarge(max_bytes) => {
+                write!(
+                    f,
+                    "WorkerError, request body is larger than the {} byte limit",
+                    max_bytes
+                )?;
+            }
+
             WorkerErrorKind::PipeDisconnected => {
                 write!(f, "Worker Error, internal pipe disconnected")?;
             }
 
+            WorkerErrorKind::RateLimited => {
+                write!(f, "WorkerError, too many requests")?;
+            }
+
             WorkerErrorKind::Regex(e) => {
                 write!(f, "Worker Error, invalid regex: {}", e)?;
             }
 
+            WorkerErrorKind::StartScriptFailed(problem, output_tail) => {
+                write!(f, "WorkerError, component's start script failed: {}", problem)?;
+                if let Some(output_tail) = output_tail {
+                    write!(f, ", last output:\n{}", output_tail)?;
+                }
+            }
+
             WorkerErrorKind::SubprocessStart(e) => {
                 write!(f, "WorkerError, caused by internal subprocess error: {}", e)?;
             }
 
-            WorkerErrorKind::SubprocessTerminated(exit_status) => {
+            WorkerErrorKind::SubprocessTerminated(exit_status, output_tail) => {
                 write!(
                     f,
                     "WorkerError, caused by subprocess terminating, with code {:?}",
                     exit_status
                 )?;
+                if let Some(output_tail) = output_tail {
+                    write!(f, ", last output:\n{}", output_tail)?;
+                }
             }
 
             WorkerErrorKind::TokioJoinError(e) => {
                 write!(f, "WorkerError, caused by internal tokio join error: {}", e)?;
             }
 
+            WorkerErrorKind::Unauthorized => {
+                write!(f, "WorkerError, missing or invalid authorization")?;
+            }
+
+            WorkerErrorKind::UnavailablePlatform(platform, reason) => {
+                write!(
+                    f,
+                    "WorkerError, platform {} is not available: {}",
+                    platform, reason
+                )?;
+            }
+
+            WorkerErrorKind::UnavailableRuntime(runtime, reason) => {
+                write!(f, "WorkerError, runtime {} is not available: {}", runtime, reason)?;
+            }
+
             WorkerErrorKind::UnsupportedPlatform(plat) => {
                 write!(f, "WorkerError, unsupported platform: {}", plat)?;
             }
@@ -157,7 +397,67 @@ impl Display for WorkerError {
 
 impl Into<Response<Body>> for WorkerError {
     fn into(self) -> Response<Body> {
+        // Still a 543, but with a machine readable body, so the gateway can show users a consistent error
+        let crashed = matches!(
+            self.kind,
+            WorkerErrorKind::PipeDisconnected | WorkerErrorKind::SubprocessTerminated(..)
+        );
+        if let (true, Some(path)) = (crashed, &self.component) {
+            return Response::builder()
+                .status(WORKER_ERROR_STATUS)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_string(&ComponentErrorResponse {
+                        error: "component-crashed".to_string(),
+                        detail: self.to_string(),
+                        component: ComponentPath::clone(path),
+                    })
+                    .unwrap(),
+                ))
+                .unwrap();
+        }
+
         match &self.kind {
+            // A component with an open circuit breaker is unavailable for now, so it's a 503
+            WorkerErrorKind::CircuitOpen(retry_after) => Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(RETRY_AFTER, *retry_after)
+                .body(Body::from(self.to_string()))
+                .unwrap(),
+
+            // The component is at its concurrency limit, so it's unavailable for now
+            WorkerErrorKind::ComponentBusy => Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from(self.to_string()))
+                .unwrap(),
+
+            // Also a 404, but with a machine readable body, so callers can tell it apart from a bad route
+            WorkerErrorKind::ComponentNotFound(path) => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_string(&ComponentNotFoundResponse {
+                        error: "component-not-found".to_string(),
+                        path: path.clone(),
+                    })
+                    .unwrap(),
+                ))
+                .unwrap(),
+
+            // Like a missing component, so it gets the same kind of 404
+            WorkerErrorKind::HandlerNotFound(path, handler) => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_string(&HandlerNotFoundResponse {
+                        error: "handler-not-found".to_string(),
+                        path: path.clone(),
+                        handler: handler.clone(),
+                    })
+                    .unwrap(),
+                ))
+                .unwrap(),
+
             // Special case the "PathNotFound" error, since it maps cleanly to a 404
             // IMPORTANT: The 404 message here is part of our API
             // DO NOT CHANGE without modifying the router
@@ -166,6 +466,37 @@ impl Into<Response<Body>> for WorkerError {
                 .body(Body::from("v9: worker 404"))
                 .unwrap(),
 
+            // The "Overloaded" error maps cleanly to a 503
+            WorkerErrorKind::Overloaded => Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from(""))
+                .unwrap(),
+
+            // A panic is a bug in the worker itself, so it's a plain 500 rather than a 543
+            WorkerErrorKind::Panic(_) => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(self.to_string()))
+                .unwrap(),
+
+            // The "PayloadTooLarge" error maps cleanly to a 413
+            WorkerErrorKind::PayloadTooLarge(_) => Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(Body::from(self.to_string()))
+                .unwrap(),
+
+            // The "RateLimited" error maps cleanly to a 429
+            WorkerErrorKind::RateLimited => Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .body(Body::from(""))
+                .unwrap(),
+
+            // The "Unauthorized" error maps cleanly to a 401
+            WorkerErrorKind::Unauthorized => Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(WWW_AUTHENTICATE, "Bearer")
+                .body(Body::from(""))
+                .unwrap(),
+
             // Also special case the "WrongMethodError" error since it maps cleanly to a 405
             WorkerErrorKind::WrongMethod => Response::builder()
                 .status(StatusCode::METHOD_NOT_ALLOWED)
@@ -174,7 +505,7 @@ impl Into<Response<Body>> for WorkerError {
 
             // Otherwise a 543 response is what the spec demands
             _ => Response::builder()
-                .status(543)
+                .status(WORKER_ERROR_STATUS)
                 .body(Body::from(self.to_string()))
                 .unwrap(),
         }
@@ -205,6 +536,18 @@ impl From<serde_json::Error> for WorkerError {
     }
 }
 
+impl From<InvalidHeaderValue> for WorkerError {
+    fn from(e: InvalidHeaderValue) -> Self {
+        WorkerErrorKind::InvalidHeaderValue(e).into()
+    }
+}
+
+impl From<FlexiLoggerError> for WorkerError {
+    fn from(e: FlexiLoggerError) -> Self {
+        WorkerErrorKind::InvalidLogSpec(e).into()
+    }
+}
+
 impl From<Utf8Error> for WorkerError {
     fn from(e: Utf8Error) -> Self {
         WorkerErrorKind::InvalidUtf8(e).into()
@@ -237,6 +580,19 @@ impl From<PopenError> for WorkerError {
 
 impl From<JoinError> for WorkerError {
     fn from(e: JoinError) -> Self {
-        WorkerErrorKind::TokioJoinError(e).into()
+        if !e.is_panic() {
+            return WorkerErrorKind::TokioJoinError(e).into();
+        }
+
+        // Panic payloads are almost always the message passed to `panic!`
+        let payload = e.into_panic();
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            (*message).to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "<non-string panic payload>".to_string()
+        };
+        WorkerErrorKind::Panic(message).into()
     }
 }
@@ -0,0 +1,167 @@
+// Lifecycle events (see `LifecycleEventKind`) are POSTed as JSON to V9_EVENT_WEBHOOK, if it's set
+// They're sent by a thread of their own, in order, so a slow or unreachable webhook never holds up a call or an
+// activation. Events that don't fit in the queue are dropped instead.
+
+use std::convert::TryInto;
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(not(feature = "fetch"))]
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Method, Request, Uri};
+#[cfg(feature = "fetch")]
+use hyper_rustls::HttpsConnector;
+use lazy_static::lazy_static;
+use tokio::runtime::Builder as RuntimeBuilder;
+use tokio::time::timeout;
+
+use crate::env_utils::env_or;
+use crate::model::{ComponentId, LifecycleEvent, LifecycleEventKind};
+
+const DEFAULT_EVENT_QUEUE_SIZE: usize = 1000;
+// Covers one event's whole POST, a webhook slower than this loses the event
+const EVENT_POST_TIMEOUT: Duration = Duration::from_secs(5);
+// How long shutting down waits for the queue to empty
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+struct EventQueue {
+    sender: SyncSender<LifecycleEvent>,
+    // Events queued, or being sent
+    pending: Arc<AtomicUsize>,
+}
+
+lazy_static! {
+    // Only started if V9_EVENT_WEBHOOK is set, otherwise events are thrown away
+    static ref EVENT_QUEUE: Option<EventQueue> = env::var("V9_EVENT_WEBHOOK").ok().map(|url| {
+        let webhook = parse_webhook(&url)
+            .unwrap_or_else(|problem| panic!("Invalid V9_EVENT_WEBHOOK ({:?}): {}", url, problem));
+        let (sender, receiver) = sync_channel(env_or("V9_EVENT_QUEUE_SIZE", DEFAULT_EVENT_QUEUE_SIZE));
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let sent = pending.clone();
+        thread::spawn(move || send_events(&webhook, &receiver, &sent));
+        EventQueue { sender, pending }
+    });
+}
+
+// Checks V9_EVENT_WEBHOOK at startup, so a bad one panics right away instead of on the first event
+pub fn init() {
+    lazy_static::initialize(&EVENT_QUEUE);
+}
+
+// Lets callers skip working out an event's details when nobody is listening
+pub fn enabled() -> bool {
+    EVENT_QUEUE.is_some()
+}
+
+pub fn emit(event: LifecycleEventKind, id: &ComponentId, detail: Option<String>) {
+    if let Some(queue) = &*EVENT_QUEUE {
+        let event = LifecycleEvent {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| {
+                    since_epoch.as_millis().try_into().unwrap_or(u64::MAX)
+                }),
+            event,
+            id: id.clone(),
+            detail,
+        };
+
+        queue.pending.fetch_add(1, Ordering::SeqCst);
+        match queue.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => {
+                queue.pending.fetch_sub(1, Ordering::SeqCst);
+                warn!(
+                    "The event queue is full (the webhook can't keep up), dropping {:?}",
+                    event
+                );
+            }
+            Err(TrySendError::Disconnected(event)) => {
+                queue.pending.fetch_sub(1, Ordering::SeqCst);
+                error!("The event sender thread is gone, dropping {:?}", event);
+            }
+        }
+    }
+}
+
+// Waits (for a little while) for the queued events to be sent, so the last ones aren't lost when we shut down
+pub fn flush() {
+    if let Some(queue) = &*EVENT_QUEUE {
+        let start = Instant::now();
+        while queue.pending.load(Ordering::SeqCst) > 0 {
+            if start.elapsed() >= FLUSH_TIMEOUT {
+                warn!(
+                    "Gave up on sending the last {} events",
+                    queue.pending.load(Ordering::SeqCst)
+                );
+                return;
+            }
+            thread::sleep(FLUSH_POLL_INTERVAL);
+        }
+    }
+}
+
+fn parse_webhook(url: &str) -> Result<Uri, String> {
+    let uri: Uri = url.parse().map_err(|e| format!("invalid URL ({})", e))?;
+    match uri.scheme_str() {
+        Some("http") => Ok(uri),
+        Some("https") if cfg!(feature = "fetch") => Ok(uri),
+        Some("https") => {
+            Err("this worker was built without the \"fetch\" feature, so it can't use https".to_string())
+        }
+        _ => Err("expected an http(s) URL".to_string()),
+    }
+}
+
+// Events are sent from outside the server's runtime (like activations restored at startup), so the sender gets a
+// runtime of its own
+fn send_events(webhook: &Uri, receiver: &Receiver<LifecycleEvent>, pending: &AtomicUsize) {
+    let mut runtime = match RuntimeBuilder::new().basic_scheduler().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!(
+                "Could not start the event sender's runtime, no events will be sent: {}",
+                e
+            );
+            return;
+        }
+    };
+    #[cfg(feature = "fetch")]
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    #[cfg(not(feature = "fetch"))]
+    let client = Client::builder().build::<_, Body>(HttpConnector::new());
+
+    while let Ok(event) = receiver.recv() {
+        let sent = runtime.block_on(async {
+            let body = serde_json::to_string(&event).map_err(|e| e.to_string())?;
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(webhook.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .map_err(|e| e.to_string())?;
+
+            match timeout(EVENT_POST_TIMEOUT, client.request(req)).await {
+                Ok(Ok(resp)) if resp.status().is_success() => Ok(()),
+                Ok(Ok(resp)) => Err(format!("the webhook answered with {}", resp.status())),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err(format!(
+                    "the webhook didn't answer within {:?}",
+                    EVENT_POST_TIMEOUT
+                )),
+            }
+        });
+        if let Err(problem) = sent {
+            warn!("Could not send {:?} to the webhook: {}", event, problem);
+        }
+
+        pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -1,10 +1,284 @@
-use std::path::Path;
+use std::convert::TryFrom;
+use std::env;
+use std::fs::{create_dir_all, read, read_dir, read_link, remove_dir_all, symlink_metadata, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use lazy_static::lazy_static;
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::{getuid, Pid};
+use sha2::{Digest, Sha256};
+use tempfile::{NamedTempFile, TempDir};
 
 use crate::error::{WorkerError, WorkerErrorKind};
 
+// Every worker keeps its temporary files in its own "v9-worker-<pid>" directory under the temp root
+const WORKER_TEMP_DIR_PREFIX: &str = "v9-worker-";
+
+lazy_static! {
+    // Where pipes, captured logs and spilled responses are created
+    // Pipes are bind mounted into containers, so this has to be somewhere docker can reach
+    static ref TEMP_ROOT: PathBuf = env::var_os("V9_TEMP_ROOT").map_or_else(env::temp_dir, PathBuf::from);
+    static ref WORKER_TEMP_DIR: PathBuf =
+        TEMP_ROOT.join(format!("{}{}", WORKER_TEMP_DIR_PREFIX, process::id()));
+}
+
+// This is only called at startup, so a bad V9_TEMP_ROOT panics with an explanation, rather than every
+// activation failing later on
+// Anything left behind by workers that crashed is removed first, so it can't pile up across restarts
+pub fn prepare_temp_dir() {
+    assert!(
+        TEMP_ROOT.is_dir(),
+        "V9_TEMP_ROOT ({:?}) is not a directory",
+        *TEMP_ROOT
+    );
+
+    remove_stale_worker_temp_dirs();
+
+    if let Err(e) = create_dir_all(&*WORKER_TEMP_DIR) {
+        panic!(
+            "Could not create {:?} under V9_TEMP_ROOT: {}",
+            *WORKER_TEMP_DIR, e
+        );
+    }
+    if let Err(e) = named_temp_file() {
+        panic!("V9_TEMP_ROOT ({:?}) is not writable: {}", *TEMP_ROOT, e);
+    }
+}
+
+// Called on a clean shutdown, once every component is stopped
+pub fn remove_temp_dir() {
+    if let Err(e) = remove_dir_all(&*WORKER_TEMP_DIR) {
+        warn!("Could not remove temp directory {:?}: {}", *WORKER_TEMP_DIR, e);
+    }
+}
+
+fn remove_stale_worker_temp_dirs() {
+    let entries = match read_dir(&*TEMP_ROOT) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!(
+                "Could not look for stale temp directories in {:?}: {}",
+                *TEMP_ROOT, e
+            );
+            return;
+        }
+    };
+
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        let stale = worker_temp_dir_pid(&path).map_or(false, |pid| is_stale_worker_temp_dir(&path, pid));
+        if stale {
+            info!("Removing stale temp directory {:?}", path);
+            if let Err(e) = remove_dir_all(&path) {
+                warn!("Could not remove stale temp directory {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+// `None` if this isn't named like one of our temp directories
+fn worker_temp_dir_pid(path: &Path) -> Option<i32> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix(WORKER_TEMP_DIR_PREFIX)?
+        .parse()
+        .ok()
+}
+
+// Only real directories (not symlinks) owned by our user, and only if the worker that made it is gone
+fn is_stale_worker_temp_dir(path: &Path, pid: i32) -> bool {
+    let owned_directory = symlink_metadata(path).map_or(false, |metadata| {
+        metadata.file_type().is_dir() && metadata.uid() == getuid().as_raw()
+    });
+    if !owned_directory {
+        return false;
+    }
+
+    // Our own pid can only show up if a previous worker had it (common for pid 1 in a container), since we
+    // haven't made our directory yet
+    if u32::try_from(pid).map_or(false, |pid| pid == process::id()) {
+        return true;
+    }
+
+    // Signal 0 just checks whether the process exists
+    kill(Pid::from_raw(pid), None) == Err(nix::Error::Sys(Errno::ESRCH))
+}
+
+pub fn temp_dir() -> Result<TempDir, WorkerError> {
+    Ok(TempDir::new_in(&*WORKER_TEMP_DIR)?)
+}
+
+pub fn named_temp_file() -> Result<NamedTempFile, WorkerError> {
+    Ok(NamedTempFile::new_in(&*WORKER_TEMP_DIR)?)
+}
+
+pub fn unnamed_temp_file() -> Result<File, WorkerError> {
+    Ok(tempfile::tempfile_in(&*WORKER_TEMP_DIR)?)
+}
+
 pub fn canonicalize(p: &Path) -> Result<String, WorkerError> {
     Ok(p.canonicalize()?
         .into_os_string()
         .into_string()
         .map_err(WorkerErrorKind::OsStringConversion)?)
 }
+
+// Like `canonicalize`, but symlinks aren't resolved, so a path that's repointed later (e.g. by a deploy) is still
+// followed
+pub fn absolute_path(p: &Path) -> Result<String, WorkerError> {
+    Ok(env::current_dir()?
+        .join(p)
+        .into_os_string()
+        .into_string()
+        .map_err(WorkerErrorKind::OsStringConversion)?)
+}
+
+// What a component's `executable_file` is expected to point at
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExpectedFileType {
+    File,
+    Directory,
+    Either,
+}
+
+// Lets activation fail fast, rather than the problem only surfacing on the first call
+pub fn check_component_file(path: &str, expected: ExpectedFileType) -> Result<(), WorkerError> {
+    let missing = |problem| WorkerErrorKind::MissingComponentFile(path.to_string(), problem);
+
+    let p = Path::new(path);
+    if !p.exists() {
+        return Err(missing("it does not exist").into());
+    }
+
+    match expected {
+        ExpectedFileType::File if !p.is_file() => Err(missing("expected a file").into()),
+        ExpectedFileType::Directory if !p.is_dir() => Err(missing("expected a directory").into()),
+        _ => Ok(()),
+    }
+}
+
+// Component hashes are handed out by the control plane, and aren't always content hashes
+// Only those that look like a SHA-256 (64 hex digits) are taken to be one, the rest are opaque identifiers
+pub fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Makes sure a component file is the artifact its hash describes, so a corrupted or tampered file isn't activated
+// Only regular files are checked: a missing file is left for the controller to report, and directories have no
+// single content hash to compare against
+pub fn check_component_hash(path: &str, hash: &str) -> Result<(), WorkerError> {
+    if !is_sha256(hash) || !Path::new(path).is_file() {
+        return Ok(());
+    }
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    let actual = format!("{:x}", hasher.finalize());
+
+    if actual.eq_ignore_ascii_case(hash) {
+        Ok(())
+    } else {
+        Err(WorkerErrorKind::HashMismatch(path.to_string(), hash.to_string(), actual).into())
+    }
+}
+
+// Looks up an executable the same way the shell would, by searching each directory in $PATH
+pub fn find_in_path(executable: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(executable))
+        .find(|candidate| candidate.is_file())
+}
+
+// Feeds `bytes` into `hasher` with their length in front, so fields next to each other can't run together
+pub fn hash_field(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+// Feeds a file, or everything under a directory, into `hasher`
+// Names are included too, so renaming a file changes the hash, and symlinks are hashed as links (like docker
+// treats them in a build context) rather than followed
+pub fn hash_contents(p: &Path, hasher: &mut Sha256) -> Result<(), WorkerError> {
+    let file_type = symlink_metadata(p)?.file_type();
+
+    if file_type.is_symlink() {
+        hasher.update(b"l");
+        hash_field(hasher, read_link(p)?.as_os_str().as_bytes());
+    } else if file_type.is_dir() {
+        let mut entries = read_dir(p)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Directory listing order isn't stable, so sort it
+        entries.sort();
+
+        // The entry count marks where the directory ends, so moving a file out of it changes the hash
+        hasher.update(b"d");
+        hasher.update((entries.len() as u64).to_be_bytes());
+        for entry in entries {
+            hash_field(hasher, entry.file_name().map_or(&[], OsStrExt::as_bytes));
+            hash_contents(&entry, hasher)?;
+        }
+    } else {
+        hasher.update(b"f");
+        hash_field(hasher, &read(p)?);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir, remove_file, rename, write};
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    fn digest(p: &Path) -> String {
+        let mut hasher = Sha256::new();
+        hash_contents(p, &mut hasher).unwrap();
+        format!("{:x}", hasher.finalize())
+    }
+
+    // Image tags are derived from this, so it must not change between versions (or every image gets rebuilt)
+    #[test]
+    fn file_digests_are_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("main.py");
+        write(&file, "print('hi')\n").unwrap();
+
+        assert_eq!(
+            digest(&file),
+            "38b09197ffed6d48c520fc3c79c4f58626c2c03b968b9d9789b951e326f77ff7"
+        );
+    }
+
+    #[test]
+    fn directory_digests_cover_names_layout_and_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let context = dir.path().join("context");
+        create_dir(&context).unwrap();
+        create_dir(context.join("sub")).unwrap();
+        write(context.join("sub/a"), "a").unwrap();
+        write(context.join("b"), "b").unwrap();
+        symlink("b", context.join("link")).unwrap();
+
+        let original = digest(&context);
+        assert_eq!(digest(&context), original);
+
+        // Moving a file out of a directory, renaming it, or repointing a link all change the digest
+        rename(context.join("sub/a"), context.join("a")).unwrap();
+        let moved = digest(&context);
+        assert_ne!(moved, original);
+        rename(context.join("a"), context.join("c")).unwrap();
+        let renamed = digest(&context);
+        assert_ne!(renamed, moved);
+        remove_file(context.join("link")).unwrap();
+        symlink("c", context.join("link")).unwrap();
+        assert_ne!(digest(&context), renamed);
+    }
+}
@@ -0,0 +1,37 @@
+use std::env;
+
+use flexi_logger::{LogSpecification, Logger, ReconfigurationHandle};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+use crate::error::WorkerError;
+
+// Used when V9_LOG isn't set
+pub const DEFAULT_LOG_SPEC: &str =
+    "debug, hyper=info, mio=info, tokio_reactor=info, tokio_threadpool=info";
+
+lazy_static! {
+    // Kept around so the level can be changed at runtime (see `/meta/log-level`)
+    static ref RECONFIGURATION_HANDLE: Mutex<Option<ReconfigurationHandle>> = Mutex::new(None);
+}
+
+// Starts the logger with the spec from V9_LOG (e.g. "info, v9_worker::component=trace")
+pub fn init() {
+    let log_spec = env::var("V9_LOG").unwrap_or_else(|_| DEFAULT_LOG_SPEC.to_string());
+    let log_spec = LogSpecification::parse(&log_spec)
+        .unwrap_or_else(|e| panic!("Invalid log specification in V9_LOG ({:?}): {}", log_spec, e));
+
+    let handle = Logger::with(log_spec).start().unwrap();
+    *RECONFIGURATION_HANDLE.lock() = Some(handle);
+}
+
+// Replaces the active log spec, which takes effect immediately for every thread
+pub fn set_log_spec(log_spec: &str) -> Result<(), WorkerError> {
+    let log_spec = LogSpecification::parse(log_spec)?;
+
+    if let Some(handle) = RECONFIGURATION_HANDLE.lock().as_mut() {
+        handle.set_new_spec(log_spec);
+    }
+
+    Ok(())
+}
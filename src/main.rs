@@ -19,58 +19,122 @@ extern crate log;
 #[macro_use]
 extern crate serde;
 
+mod audit;
+mod blocking_pool;
 mod component;
 mod docker;
+mod env_utils;
 mod error;
+mod events;
 mod fs_utils;
+mod logging;
 mod model;
 mod named_pipe;
 mod request_handler;
+mod scheduler;
 mod server;
+mod settings;
+mod shutdown;
+mod telemetry;
+#[cfg(feature = "tls")]
+mod tls;
 
 use std::env;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::env_utils::env_or;
 use crate::request_handler::HttpRequestHandler;
+use crate::server::ServerConfig;
 
-const HEARTBEAT_PERIODICITY: Duration = Duration::from_secs(1);
+// How often the heartbeat logs how busy the blocking pool is
+const POOL_METRICS_PERIODICITY: Duration = Duration::from_secs(60);
+const DEFAULT_STATE_FILE: &str = "/var/lib/v9_worker/activations.json";
 
 fn main() {
-    // TODO: Graceful shutdown on control-c / API call would be good
-
     // Initialize logging
-    let log_spec = "debug, hyper=info, mio=info, tokio_reactor=info, tokio_threadpool=info";
-    flexi_logger::Logger::with_str(log_spec).start().unwrap();
+    logging::init();
     info!("worker starting... (logging initialized)");
 
+    // May replace the log spec from V9_LOG with the one in the settings file
+    settings::init();
+
     // Parse command line arguments
     let development_mode = env::args().any(|arg| arg == "--development");
     if development_mode {
         info!("running in development mode");
     }
 
+    // Everything below creates pipes, so make sure there's somewhere to put them
+    fs_utils::prepare_temp_dir();
+
+    // Only containerized execution methods need docker, so the worker still starts without it
+    match docker::check_docker_daemon() {
+        Ok(version) => info!("Docker daemon reachable (version {})", version),
+        Err(e) => error!("Containerized execution methods will fail: {}", e),
+    }
+
+    // Opened before anything is activated (restoring activations below included)
+    audit::init();
+    events::init();
+    telemetry::init();
+
     // Pre-initialize idle container creation
-    lazy_static::initialize(&docker::idle_container_creator::GLOBAL_IDLE_CONTAINER_CREATOR);
+    lazy_static::initialize(&docker::idle_container_creator::IDLE_POOLS);
 
     // Create handler to deal with HTTP requests
     let http_request_handler = Arc::new(HttpRequestHandler::new());
 
+    // Bring back whatever was active before we (re)started, before serving any traffic
+    let state_file = env_or("V9_STATE_FILE", PathBuf::from(DEFAULT_STATE_FILE));
+    http_request_handler
+        .component_manager()
+        .write()
+        .restore_activations(&state_file);
+
     // Create a heartbeat thread for the ComponentManager
     // (We want a periodic signal to check on our components, and perhaps shut them down)
+    // The heartbeat also saves the active component set, so it survives restarts
     let heartbeat_handler_ref = http_request_handler.clone();
+    let heartbeat_state_file = state_file.clone();
+    let mut last_pool_metrics = Instant::now();
     thread::spawn(move || loop {
-        heartbeat_handler_ref.component_manager().read().heartbeat();
-        thread::sleep(HEARTBEAT_PERIODICITY);
+        let component_manager = heartbeat_handler_ref.component_manager().read();
+        component_manager.heartbeat();
+        component_manager.persist_activations(&heartbeat_state_file);
+        drop(component_manager);
+
+        if last_pool_metrics.elapsed() >= POOL_METRICS_PERIODICITY {
+            let metrics = blocking_pool::blocking_pool_metrics();
+            info!(
+                "Blocking pool: {} tasks running, {} queued",
+                metrics.running_tasks, metrics.queued_tasks
+            );
+            last_pool_metrics = Instant::now();
+        }
+
+        thread::sleep(settings::heartbeat_period());
     });
 
     // Start up a server to respond to REST requests
+    // It returns once a shutdown is requested (by a signal or `POST /meta/shutdown`) and in-flight requests finish
     server::start_server(
-        development_mode,
-        http_request_handler,
+        &ServerConfig::from_env(development_mode),
+        http_request_handler.clone(),
         request_handler::global_request_entrypoint,
     );
 
     warn!("Sever loop finished, shutting down...");
+    let mut component_manager = http_request_handler.component_manager().write();
+    component_manager.persist_activations(&state_file);
+    component_manager.stop_all();
+    drop(component_manager);
+    events::flush();
+    telemetry::flush();
+
+    docker::idle_container_creator::shutdown_idle_pools();
+    fs_utils::remove_temp_dir();
+    info!("Shutdown complete");
 }
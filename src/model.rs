@@ -1,5 +1,7 @@
 // These are just nice PORO (plain old rust objects) for modeling requests and responses
 
+use std::collections::BTreeMap;
+
 #[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
 pub struct ComponentPath {
     pub user: String,
@@ -25,16 +27,35 @@ pub enum ExecutionMethod {
     ContainerizedScript,
     #[serde(rename = "docker-archive")]
     DockerArchive,
+    // `executable_file` is a build context (a directory, or a tar archive of one) containing a Dockerfile
+    #[serde(rename = "docker-build")]
+    DockerBuild,
+    #[serde(rename = "node-unsafe")]
+    NodeUnsafe,
     #[serde(rename = "python-unsafe")]
     PythonUnsafe,
 }
 
+// How messages are delimited on the pipes between the worker and a component
+#[derive(Clone, Copy, Default, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum PipeFraming {
+    // Each message is terminated by a newline, so messages cannot contain newlines
+    #[default]
+    #[serde(rename = "newline")]
+    Newline,
+    // Each message is preceded by its length as a 4 byte big-endian integer
+    #[serde(rename = "length-prefixed")]
+    LengthPrefixed,
+}
+
 #[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
 pub enum ActivationStatus {
     #[serde(rename = "activation-successful")]
     ActivationSuccessful,
     #[serde(rename = "already-running")]
     AlreadyRunning,
+    #[serde(rename = "already-up-to-date")]
+    AlreadyUpToDate,
     #[serde(rename = "failed-to-find-executable")]
     FailedToFindExecutable,
     #[serde(rename = "failed-to-start")]
@@ -44,16 +65,100 @@ pub enum ActivationStatus {
 }
 
 #[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct CorsConfig {
+    // A "*" entry allows any origin
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    pub headers: Vec<String>,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct VolumeMount {
+    pub host_path: String,
+    pub container_path: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+// These bools are independent settings in the activation API, not a state machine in disguise
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize)]
 pub struct ActivateRequest {
     pub id: ComponentId,
     pub executable_file: String,
     pub execution_method: ExecutionMethod,
+    #[serde(default)]
+    pub pipe_framing: PipeFraming,
+
+    pub cors: Option<CorsConfig>,
+    // By default OPTIONS requests are answered by the worker, set this to have them passed to the component
+    // (CORS preflights are always answered by the worker, if `cors` is set)
+    #[serde(default)]
+    pub handle_options: bool,
+    // Streaming components answer each call with a series of `ComponentStreamMessage`s, which are relayed to the
+    // client as server-sent events
+    #[serde(default)]
+    pub streaming: bool,
+    // Gives the component a second pair of pipes for control messages (see `ControlMessage`), which are sent
+    // separately from its calls. Not available for containerized scripts.
+    #[serde(default)]
+    pub control_pipe: bool,
+    // Whether the component answers `__ping__` calls (see `PING_FUNCTION`), if not, pinging it only checks that
+    // its process is still running
+    #[serde(default = "ping_default")]
+    pub ping: bool,
+    // The functions (i.e. method path segments) the component handles, calls to anything else get a 404 without
+    // reaching the component. Unset means every call is passed along.
+    pub handlers: Option<Vec<String>>,
+    // Calls beyond this rate get a 429, unset means unlimited. OPTIONS requests the worker answers itself (e.g.
+    // CORS preflights) aren't counted.
+    pub max_requests_per_second: Option<u32>,
+    // Calls beyond this many in flight at once (running or queued) get a 503, unset means unlimited
+    pub max_concurrent: Option<u32>,
+    // Defaults to 5 minutes
+    pub stat_window_seconds: Option<u64>,
+    // Which latency percentiles to report in stats, defaults to V9_LATENCY_PERCENTILES
+    pub latency_percentiles: Option<Vec<f64>>,
+    // Only used by containerized execution methods
+    #[serde(default)]
+    pub volumes: Vec<VolumeMount>,
+    // If false, the component's stdout and stderr are thrown away (docker still keeps container output though)
+    #[serde(default = "capture_logs_default")]
+    pub capture_logs: bool,
+    // Runs the container as another platform (e.g. "linux/amd64" on an arm64 host), using emulation
+    pub platform: Option<String>,
+    // Passed to `docker run --network` (e.g. "none" or a named network), unset means docker's default bridge
+    pub network_mode: Option<String>,
+    // Passed to `docker run --user` (a name or uid, optionally with ":group"), unset means the image's user
+    pub run_as_user: Option<String>,
+    // Mounts the container's root filesystem read-only, with a tmpfs at /tmp for scratch space
+    #[serde(default)]
+    pub read_only_rootfs: bool,
+    // Passed to `docker run --runtime` (e.g. "runsc" for gVisor), unset means the daemon's default runtime
+    // It has to be registered with the daemon, and can't be set for components that don't run in a container
+    pub runtime: Option<String>,
+    // The image containerized scripts run in, unset means V9_IDLE_IMAGE. Each image gets an idle pool of its own
+    // (up to V9_IDLE_MAX_POOLS of them), and can't be set for any other execution method.
+    pub base_image: Option<String>,
+    // Where the component runs, unset means the directory `executable_file` is in (or for containerized scripts,
+    // the code folder, which relative paths are resolved against too). Docker images use their own WORKDIR.
+    pub working_dir: Option<String>,
+}
+
+fn capture_logs_default() -> bool {
+    true
+}
+
+fn ping_default() -> bool {
+    true
 }
 
 #[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
 pub struct ActivateResponse {
     pub result: ActivationStatus,
     pub dbg_message: String,
+    // The component that is running at this path after the request, if any
+    pub id: Option<ComponentId>,
 }
 
 #[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
@@ -79,6 +184,178 @@ pub struct DeactivateResponse {
     pub dbg_message: String,
 }
 
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct DeactivateAllResponse {
+    pub deactivated: Vec<ComponentId>,
+    pub deactivated_count: usize,
+    pub dbg_message: String,
+}
+
+// One line of the audit log (see `V9_AUDIT_LOG`)
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct AuditEvent {
+    // Milliseconds since the unix epoch
+    pub timestamp_ms: u64,
+    pub operation: AuditOperation,
+    // These are `null` when the request couldn't be parsed
+    pub user: Option<String>,
+    pub repo: Option<String>,
+    pub hash: Option<String>,
+    pub result: AuditResult,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum AuditOperation {
+    #[serde(rename = "activate")]
+    Activate,
+    #[serde(rename = "deactivate")]
+    Deactivate,
+    // Logged once for every component that was deactivated
+    #[serde(rename = "deactivate-all")]
+    DeactivateAll,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum AuditResult {
+    Activation(ActivationStatus),
+    Deactivation(DeactivationStatus),
+}
+
+// What's POSTed to V9_EVENT_WEBHOOK
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct LifecycleEvent {
+    // Milliseconds since the unix epoch
+    pub timestamp_ms: u64,
+    pub event: LifecycleEventKind,
+    pub id: ComponentId,
+    // Why a process crashed or was terminated, `null` for the other events
+    pub detail: Option<String>,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum LifecycleEventKind {
+    #[serde(rename = "activated")]
+    Activated,
+    // Sent instead of `process-crashed` when docker says the container ran out of memory
+    #[serde(rename = "container-oom-killed")]
+    ContainerOomKilled,
+    #[serde(rename = "deactivated")]
+    Deactivated,
+    #[serde(rename = "process-booted")]
+    ProcessBooted,
+    // The process exited, or a call to it failed, so it was stopped (it's booted again on the next call)
+    #[serde(rename = "process-crashed")]
+    ProcessCrashed,
+    // The process was stopped on purpose, because it was idle or its component was stopped
+    #[serde(rename = "process-terminated")]
+    ProcessTerminated,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum ControlKind {
+    // Asks the component to give up on the call it's working on (it still has to answer it)
+    #[serde(rename = "cancel")]
+    Cancel,
+    // Asks the component to write out anything it's buffering, like logs
+    #[serde(rename = "flush")]
+    Flush,
+}
+
+// What the worker writes to a component's control pipe, framed like its other messages
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct ControlMessage {
+    pub control: ControlKind,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct ControlRequest {
+    pub id: ComponentId,
+    pub control: ControlKind,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum ControlStatus {
+    #[serde(rename = "component-not-found")]
+    ComponentNotFound,
+    #[serde(rename = "component-not-running")]
+    ComponentNotRunning,
+    #[serde(rename = "control-successful")]
+    ControlSuccessful,
+    #[serde(rename = "failed-to-deliver")]
+    FailedToDeliver,
+    #[serde(rename = "invalid-request")]
+    InvalidRequest,
+    #[serde(rename = "no-control-pipe")]
+    NoControlPipe,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct ControlResponse {
+    pub result: ControlStatus,
+    pub dbg_message: String,
+    // The message the component answered with
+    pub reply: Option<String>,
+}
+
+// The `called_function` of the calls components get when they're pinged, which should be answered right away with
+// any `ComponentResponse` (it's never passed on to a client)
+pub const PING_FUNCTION: &str = "__ping__";
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum PingStatus {
+    #[serde(rename = "component-busy")]
+    ComponentBusy,
+    #[serde(rename = "component-not-found")]
+    ComponentNotFound,
+    #[serde(rename = "component-not-running")]
+    ComponentNotRunning,
+    #[serde(rename = "ping-failed")]
+    PingFailed,
+    #[serde(rename = "ping-successful")]
+    PingSuccessful,
+    // For components activated without `ping`
+    #[serde(rename = "process-running")]
+    ProcessRunning,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct PingResponse {
+    pub result: PingStatus,
+    pub dbg_message: String,
+    // How long the component took to answer, only set if it was actually pinged
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct ShutdownResponse {
+    // Repeated requests are harmless, they just join the shutdown already in progress
+    pub already_shutting_down: bool,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum WarmStatus {
+    #[serde(rename = "component-not-found")]
+    ComponentNotFound,
+    #[serde(rename = "failed-to-start")]
+    FailedToStart,
+    #[serde(rename = "invalid-request")]
+    InvalidRequest,
+    #[serde(rename = "warm-successful")]
+    WarmSuccessful,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct WarmRequest {
+    pub id: ComponentId,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct WarmResponse {
+    pub result: WarmStatus,
+    pub dbg_message: String,
+}
+
 #[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
 pub struct ComponentLog {
     pub id: ComponentId,
@@ -113,35 +390,261 @@ pub struct ComponentStats {
     pub stat_window_seconds: f64,
 
     pub hits: f64,
+    // The hits broken down by response class (2xx and 3xx, 4xx, 5xx), matching the status colors
+    // Calls that failed without the component answering aren't hits, so they only show up in `color`
+    pub success_count: f64,
+    pub client_error_count: f64,
+    pub server_error_count: f64,
 
+    pub avg_request_bytes: f64,
     pub avg_response_bytes: f64,
+    pub request_bytes_percentiles: Vec<SizePercentile>,
+    pub response_bytes_percentiles: Vec<SizePercentile>,
+
     pub avg_ms_latency: f64,
-    pub ms_latency_percentiles: Vec<f64>,
+    pub ms_latency_percentiles: Vec<LatencyPercentile>,
+
+    // Cold starts are the calls that had to boot the component's process first
+    pub cold_start_count: f64,
+    pub avg_cold_ms_latency: f64,
+    pub avg_warm_ms_latency: f64,
+
+    // These are only filled in for detailed status requests, since they're expensive to collect
+    pub memory_bytes: Option<f64>,
+    pub cpu_percent: Option<f64>,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Serialize)]
+pub struct LatencyPercentile {
+    // e.g. 99.0 for the p99 latency
+    pub percentile: f64,
+    pub ms_latency: f64,
+}
+
+// Uses the same percentile marks as the latencies
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Serialize)]
+pub struct SizePercentile {
+    pub percentile: f64,
+    pub bytes: f64,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Serialize)]
+pub struct ResourceUsage {
+    pub memory_bytes: f64,
+    pub cpu_percent: f64,
+}
+
+// See `CircuitBreaker`
+#[derive(Clone, Copy, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum BreakerState {
+    // Calls go through normally
+    #[serde(rename = "closed")]
+    Closed,
+    // Calls are rejected with a 503
+    #[serde(rename = "open")]
+    Open,
+    // The next call is let through to see if the component has recovered
+    #[serde(rename = "half-open")]
+    HalfOpen,
+}
+
+// A single call, as recorded for the component's stats
+#[derive(Clone, Copy, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct RawStatEvent {
+    // How long before the response was built the call finished
+    pub age_ms: u64,
+    pub duration_ms: u32,
+    pub request_bytes: u32,
+    pub response_bytes: u32,
+    pub cold: bool,
+    pub color: StatusColor,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct RawStatsResponse {
+    pub id: ComponentId,
+    // Oldest first, only the newest events are included if there are too many
+    pub events: Vec<RawStatEvent>,
+    pub truncated: bool,
+}
+
+// What a component was activated with, leaving out anything about the host (like volume host paths)
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct ComponentActivationResponse {
+    pub id: ComponentId,
+    pub executable_file: String,
+    pub execution_method: ExecutionMethod,
+    pub pipe_framing: PipeFraming,
+    pub streaming: bool,
+    pub control_pipe: bool,
+    pub ping: bool,
+    pub handlers: Option<Vec<String>>,
+    pub handle_options: bool,
+    pub cors: Option<CorsConfig>,
+    pub max_requests_per_second: Option<u32>,
+    pub max_concurrent: Option<u32>,
+    pub stat_window_seconds: Option<u64>,
+    pub latency_percentiles: Option<Vec<f64>>,
+    pub capture_logs: bool,
+    // Where volumes are mounted inside the container
+    pub volume_container_paths: Vec<String>,
+    pub platform: Option<String>,
+    pub network_mode: Option<String>,
+    pub run_as_user: Option<String>,
+    pub read_only_rootfs: bool,
+    pub runtime: Option<String>,
+    pub base_image: Option<String>,
+    pub working_dir: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize)]
 pub struct ComponentStatus {
     pub id: ComponentId,
+    pub seconds_since_activation: u64,
+    // Idle components are shut down once this reaches 10 minutes (they're booted again on the next call)
+    pub seconds_since_last_access: u64,
+    pub breaker_state: BreakerState,
     #[serde(flatten)]
     pub component_stats: ComponentStats,
 }
 
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize)]
 pub struct StatusResponse {
-    pub cpu_usage: f64,
-    pub memory_usage: f64,
-    pub network_usage: f64,
+    // Lets consumers detect breaking changes to this response (missing means version 1)
+    pub version: u32,
+    // These are `None` if we couldn't measure them
+    pub cpu_usage: Option<f64>,
+    pub memory_usage: Option<f64>,
+    pub network_usage: Option<f64>,
     pub active_components: Vec<ComponentStatus>,
+    // Summed over every image's pool
+    pub idle_pool: IdlePoolStatus,
+    pub idle_pools: BTreeMap<String, IdlePoolStatus>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct IdlePoolStatus {
+    // Containers waiting in the pool right now, out of `capacity`
+    pub warm_containers: u64,
+    pub capacity: usize,
+    pub populators: usize,
+    // Populators that exited, or haven't checked in for a few minutes, aren't counted. With none alive the pool
+    // stays empty, and every containerized script has to wait for a container to be created for it.
+    pub populators_alive: usize,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct IdlePoolMetrics {
+    pub containers_created: u64,
+    pub containers_handed_out: u64,
+    pub cache_hits: u64,
+    pub synchronous_fallbacks: u64,
+}
+
+// Only counts our own blocking tasks (every request), not ones tokio runs internally
+// Requests wait in the blocking pool's queue themselves, so this is only logged by the heartbeat thread
+#[derive(Clone, Copy, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct BlockingPoolMetrics {
+    pub running_tasks: u64,
+    // Waiting for a free blocking thread
+    pub queued_tasks: u64,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct MetricsResponse {
+    // Summed over every image's pool
+    pub idle_pool: IdlePoolMetrics,
+    pub idle_pools: BTreeMap<String, IdlePoolMetrics>,
+}
+
+// Dashboards depend on these field names, so don't change them
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct VersionResponse {
+    pub version: String,
+    pub git_commit: String,
+    // Seconds since the unix epoch
+    pub build_timestamp: u64,
+    pub container_runtime: String,
+}
+
+// The spec uses flexi_logger's syntax, e.g. "info, v9_worker::component=trace"
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct LogLevelRequest {
+    pub spec: String,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct LogLevelResponse {
+    pub spec: String,
+}
+
+// The settings in effect after `/meta/reload`
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct ReloadResponse {
+    pub heartbeat_ms: u64,
+    pub idle_expiry_secs: u64,
+    pub log_spec: String,
+    pub max_body_bytes: usize,
+}
+
+// The body of the 404 for calls to a component that isn't active
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct ComponentNotFoundResponse {
+    pub error: String,
+    #[serde(flatten)]
+    pub path: ComponentPath,
+}
+
+// The body of the 543 for calls that crashed the component
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct ComponentErrorResponse {
+    pub error: String,
+    pub detail: String,
+    pub component: ComponentPath,
+}
+
+// The body of the 404 for calls to a function the component didn't declare
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct HandlerNotFoundResponse {
+    pub error: String,
+    #[serde(flatten)]
+    pub path: ComponentPath,
+    pub handler: String,
 }
 
 #[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
 pub struct ComponentRequest {
     pub called_function: String,
+    // Components should include this in their logs, so they can be matched up with ours
+    pub request_id: String,
+    // The caller's W3C trace context (e.g. "00-<trace id>-<parent span id>-01"), if it sent a valid one
+    // With V9_OTLP_ENDPOINT set, it's the worker's span for the request instead (see `telemetry`)
+    // Instrumented components can use it as the parent of their spans
+    pub traceparent: Option<String>,
+    // The caller's IP address (see V9_TRUST_FORWARDED_FOR), unknown when we're listening on a unix socket
+    pub client_ip: Option<String>,
 
     pub http_method: String,
     pub path: String,
     pub request_arguments: String,
     pub request_body: String,
+
+    // Pre-parsed (and percent decoded) versions of `path` and `request_arguments`
+    pub path_segments: Vec<String>,
+    pub query_pairs: Vec<(String, String)>,
+}
+
+// What streaming components send instead of a `ComponentResponse`, any number of times per call
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct ComponentStreamMessage {
+    // Sent as the event's `data`, messages without it aren't relayed
+    pub data: Option<String>,
+    // The event's type, unset means the default ("message")
+    pub event: Option<String>,
+    // Ends the stream (after relaying this message's data, if it has any)
+    #[serde(default)]
+    pub done: bool,
 }
 
 #[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
@@ -149,4 +652,12 @@ pub struct ComponentResponse {
     pub response_body: String,
     pub http_response_code: u32,
     pub error_message: Option<String>,
+    // Sent as the `Location` header when `http_response_code` is a redirect (3xx)
+    pub location: Option<String>,
+    // If set on a successful GET, the worker answers identical calls (same method, path and query) itself
+    // for this long. Calls with an Authorization or Cookie header are never cached.
+    pub cache_ttl_seconds: Option<u32>,
+    // A reason phrase to go with `http_response_code`, sent as the `X-Status-Reason` header (see
+    // `set_status_reason`), unset means the code's standard one
+    pub status_reason: Option<String>,
 }
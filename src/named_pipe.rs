@@ -1,46 +1,85 @@
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::fs::File;
 use std::fs::OpenOptions;
-use std::os::unix::fs::OpenOptionsExt;
+use std::fs::{set_permissions, Permissions};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::os::unix::io::AsRawFd;
-use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
+use lazy_static::lazy_static;
 use nix::errno::Errno;
-use nix::fcntl::OFlag;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::libc::c_int;
 use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::stat::Mode;
 use nix::unistd::{mkfifo, read, write};
 use tempfile::TempDir;
 
+use crate::env_utils::env_or;
 use crate::error::{WorkerError, WorkerErrorKind};
+use crate::fs_utils::temp_dir;
+use crate::model::PipeFraming;
+use crate::telemetry;
 
 #[derive(Debug)]
 pub struct NamedPipe {
-    root_folder: TempDir,
+    // Only held so the fifos are deleted along with the pipe
+    _root_folder: TempDir,
 
     component_input_fifo_path: PathBuf,
     component_output_fifo_path: PathBuf,
 
-    component_input_fifo_file: Option<File>,
-    component_output_fifo_file: Option<File>,
+    // Both are opened on first use (see `open`)
+    writer: Option<PipeWriter>,
+    reader: Option<PipeReader>,
+}
+
+// Our end of the component's input
+#[derive(Debug)]
+struct PipeWriter {
+    path: PathBuf,
+    file: File,
+}
+
+// Our end of the component's output
+#[derive(Debug)]
+struct PipeReader {
+    path: PathBuf,
+    file: File,
+    // What was read past the end of the last message, since a component can write several at once (e.g. streaming)
+    unread: Vec<u8>,
 }
 
 // This is basically our limit on startup time
 const PIPE_CREATION_TIMEOUT_MS: u64 = 10000;
-// This is basically our limit on individual call time
+// Limit on each individual read or write
 const PIPE_IO_TIMEOUT_MS: u64 = 10000;
-// This is a knob for our cpu usage during calls
+// This is a knob for our cpu usage while waiting for a component to open its pipes
 const PIPE_POLL_INTERVAL_MS: u64 = 2;
 
-// How much we should read from the component at the time
-const BUF_SIZE: usize = 512;
+// How much we should read from the component at the time, by default
+const DEFAULT_READ_CHUNK_BYTES: usize = 4096;
+
+// Size of the length prefix used by `PipeFraming::LengthPrefixed`
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+lazy_static! {
+    // Limit on a whole query, writing the request and reading the response, so a component that keeps trickling
+    // out its response can't hold on to a call forever
+    static ref QUERY_TIMEOUT: Duration = Duration::from_millis(env_or("V9_CALL_TIMEOUT_MS", 20000));
+    // Reading 0 bytes means the pipe was closed, so we always read at least 1
+    static ref READ_CHUNK_BYTES: usize = env_or("V9_PIPE_READ_CHUNK_BYTES", DEFAULT_READ_CHUNK_BYTES).max(1);
+    // Kernel buffer to ask for on each fifo, 0 keeps the kernel's default (64 KiB on Linux)
+    static ref FIFO_BUFFER_BYTES: usize = env_or("V9_PIPE_BUFFER_BYTES", 0);
+    // Largest message we'll read from a component, so a runaway one can't take all our memory with it
+    static ref MAX_MESSAGE_BYTES: usize = env_or("V9_MAX_MESSAGE_BYTES", 16 * 1024 * 1024);
+}
 
 impl NamedPipe {
     pub fn new() -> Result<Self, WorkerError> {
-        let dir = TempDir::new()?;
+        let dir = temp_dir()?;
         Ok(Self::in_dir(dir)?)
     }
 
@@ -48,15 +87,8 @@ impl NamedPipe {
         let component_input_fifo_path = dir.path().join("IN");
         let component_output_fifo_path = dir.path().join("OUT");
 
-        // These fifos are created with 777 permissions
-        mkfifo(
-            &component_input_fifo_path,
-            Mode::S_IRWXU | Mode::S_IRWXG | Mode::S_IRWXO,
-        )?;
-        mkfifo(
-            &component_output_fifo_path,
-            Mode::S_IRWXU | Mode::S_IRWXG | Mode::S_IRWXO,
-        )?;
+        make_fifo(&component_input_fifo_path)?;
+        make_fifo(&component_output_fifo_path)?;
 
         debug!(
             "Creating new pipes I = {:?}, O = {:?}",
@@ -64,118 +96,193 @@ impl NamedPipe {
         );
 
         Ok(Self {
-            root_folder: dir,
+            _root_folder: dir,
 
             component_input_fifo_path,
             component_output_fifo_path,
 
-            component_input_fifo_file: None,
-            component_output_fifo_file: None,
+            writer: None,
+            reader: None,
         })
     }
 
-    fn get_fds(&mut self) -> Result<(RawFd, RawFd), WorkerError> {
+    // The component's output has to be opened first: a component that opens its output before its input is stuck
+    // until we're reading from it, and we can't write to its input until it has opened that
+    fn open(&mut self) -> Result<(&mut PipeWriter, &mut PipeReader), WorkerError> {
         let deadline = Instant::now() + Duration::from_millis(PIPE_CREATION_TIMEOUT_MS);
 
-        while self.component_output_fifo_file.is_none() && Instant::now() < deadline {
-            let c_out_res = OpenOptions::new()
-                .read(true)
-                .custom_flags(OFlag::O_NONBLOCK.bits())
-                .open(&self.component_output_fifo_path);
+        if self.reader.is_none() {
+            self.reader = Some(PipeReader::open(&self.component_output_fifo_path, deadline)?);
+        }
+        if self.writer.is_none() {
+            self.writer = Some(PipeWriter::open(&self.component_input_fifo_path, deadline)?);
+        }
 
-            trace!("Opening component output {:?}", c_out_res);
+        trace!("Finished trying to open component pipes");
 
-            self.component_output_fifo_file = c_out_res.ok();
+        // These are safe unwraps, since we just made sure both are open
+        Ok((self.writer.as_mut().unwrap(), self.reader.as_mut().unwrap()))
+    }
 
-            sleep(Duration::from_millis(PIPE_POLL_INTERVAL_MS))
-        }
+    pub fn component_input_file(&self) -> &Path {
+        &self.component_input_fifo_path
+    }
 
-        while self.component_input_fifo_file.is_none() && Instant::now() < deadline {
-            let c_in_res = OpenOptions::new()
-                .write(true)
-                .custom_flags(OFlag::O_NONBLOCK.bits())
-                .open(&self.component_input_fifo_path);
+    // See `PipeReader::read`
+    pub fn read(
+        &mut self,
+        framing: PipeFraming,
+        query_deadline: Option<Instant>,
+    ) -> Result<Vec<u8>, WorkerError> {
+        let (_, reader) = self.open()?;
+        telemetry::in_span("pipe read", || reader.read(framing, query_deadline))
+    }
 
-            trace!("Opening component input {:?}", c_in_res);
+    pub fn component_output_file(&self) -> &Path {
+        &self.component_output_fifo_path
+    }
 
-            self.component_input_fifo_file = c_in_res.ok();
+    // `timeout` replaces V9_CALL_TIMEOUT_MS for this query, if it's set
+    pub fn query(
+        &mut self,
+        req: &str,
+        framing: PipeFraming,
+        timeout: Option<Duration>,
+    ) -> Result<String, WorkerError> {
+        let query_deadline = Instant::now() + timeout.unwrap_or(*QUERY_TIMEOUT);
+        let (writer, reader) = self.open()?;
+        telemetry::in_span("pipe write", || {
+            writer.write(req.as_bytes(), framing, Some(query_deadline))
+        })?;
+
+        let read_bytes = telemetry::in_span("pipe read", || reader.read(framing, Some(query_deadline)))?;
+        Ok(String::from_utf8(read_bytes)?)
+    }
 
-            sleep(Duration::from_millis(PIPE_POLL_INTERVAL_MS))
-        }
+    // Reads a message outside of a query, so only the per-read timeout applies
+    pub fn read_string(&mut self, framing: PipeFraming) -> Result<String, WorkerError> {
+        let read_bytes = self.read(framing, None)?;
+        Ok(String::from_utf8(read_bytes)?)
+    }
+}
 
-        trace!("Finished trying to open component pipes");
+impl PipeWriter {
+    // NOTE: Unlike reading, there's nothing to block on here. Opening the write end fails until the component opens
+    // the read end, and a component blocked in that open doesn't generate an inotify event until we succeed, so we
+    // have to retry
+    fn open(path: &Path, deadline: Instant) -> Result<Self, WorkerError> {
+        let mut options = OpenOptions::new();
+        options.write(true).custom_flags(OFlag::O_NONBLOCK.bits());
 
-        if let (Some(c_in), Some(c_out)) =
-            (&self.component_input_fifo_file, &self.component_output_fifo_file)
-        {
-            Ok((c_in.as_raw_fd(), c_out.as_raw_fd()))
-        } else {
-            Err(WorkerErrorKind::OperationTimedOut("fifo pipe opening").into())
-        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: open_fifo(path, &options, deadline)?,
+        })
     }
 
-    // Precondition: No newlines in the input string
-    pub fn write(&mut self, v: &[u8]) -> Result<(), WorkerError> {
-        // Passing in a newline violates the contract of this method
-        if v.contains(&b'\n') {
-            return Err(WorkerErrorKind::InvalidSerialization("contains newline", v.to_vec()).into());
-        }
-
-        // Push a newline at the end to terminate the input
-        let mut v = Vec::from(v);
-        v.push(b'\n');
+    // Precondition: No newlines in the input string if using `PipeFraming::Newline`
+    // Writing has to finish by `query_deadline` too, if there is one
+    fn write(
+        &mut self,
+        v: &[u8],
+        framing: PipeFraming,
+        query_deadline: Option<Instant>,
+    ) -> Result<(), WorkerError> {
+        let v = frame(v, framing)?;
 
-        let (c_in_fd, _) = self.get_fds()?;
+        let c_in_fd = self.file.as_raw_fd();
 
-        let deadline = Instant::now() + Duration::from_millis(PIPE_IO_TIMEOUT_MS);
+        let (deadline, timed_out_operation) = io_deadline("pipe writing", query_deadline);
 
         let mut write_idx = 0;
         while write_idx < v.len() && Instant::now() < deadline {
-            trace!("Polling {:?}", self.component_input_fifo_path);
+            trace!("Polling {:?}", self.path);
             // Wait until ready
-            let poll_flags = PollFlags::POLLOUT;
-            poll(
-                &mut [PollFd::new(c_in_fd, poll_flags)],
-                (deadline - Instant::now()).as_millis().try_into()?,
-            )?;
+            let mut poll_fds = [PollFd::new(c_in_fd, PollFlags::POLLOUT)];
+            let ready_fds = poll(&mut poll_fds, remaining_millis(deadline)?)?;
+            if ready_fds == 0 {
+                continue;
+            }
+
+            // Once the component closes its end nothing will ever read what we write, so give up right away
+            if peer_hung_up(poll_fds[0]) {
+                return Err(WorkerErrorKind::PipeDisconnected.into());
+            }
 
             // Then write the bytes
-            let written_bytes = write(c_in_fd, &v[write_idx..])?;
+            let written_bytes = match write(c_in_fd, &v[write_idx..]) {
+                Ok(n) => n,
+                // The component can close its end between the poll and the write
+                Err(e) if e.as_errno() == Some(Errno::EPIPE) => {
+                    return Err(WorkerErrorKind::PipeDisconnected.into())
+                }
+                // Readiness can be spurious, so just go back to waiting
+                Err(e) if e.as_errno() == Some(Errno::EAGAIN) => continue,
+                Err(e) => return Err(e.into()),
+            };
             write_idx += written_bytes;
         }
 
         // If we didn't write everything, we timed out
         if write_idx < v.len() {
-            return Err(WorkerErrorKind::OperationTimedOut("pipe writing").into());
+            return Err(WorkerErrorKind::OperationTimedOut(timed_out_operation).into());
         }
 
         Ok(())
     }
+}
 
-    pub fn component_input_file(&self) -> &Path {
-        &self.component_input_fifo_path
+impl PipeReader {
+    // Opening a fifo for reading doesn't wait for a writer (with O_NONBLOCK), so this normally succeeds right away
+    fn open(path: &Path, deadline: Instant) -> Result<Self, WorkerError> {
+        let mut options = OpenOptions::new();
+        options.read(true).custom_flags(OFlag::O_NONBLOCK.bits());
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: open_fifo(path, &options, deadline)?,
+            unread: Vec::new(),
+        })
     }
 
-    pub fn read(&mut self) -> Result<Vec<u8>, WorkerError> {
-        let (_, c_out_fd) = self.get_fds()?;
+    // Reading has to finish by `query_deadline` too, if there is one
+    fn read(
+        &mut self,
+        framing: PipeFraming,
+        query_deadline: Option<Instant>,
+    ) -> Result<Vec<u8>, WorkerError> {
+        let c_out_fd = self.file.as_raw_fd();
 
-        let deadline = Instant::now() + Duration::from_millis(PIPE_IO_TIMEOUT_MS);
+        let (deadline, timed_out_operation) = io_deadline("pipe reading", query_deadline);
 
-        // Then read the bytes
-        let mut read_buf = vec![0; BUF_SIZE];
-        let mut result = Vec::with_capacity(BUF_SIZE);
+        let mut read_buf = vec![0; *READ_CHUNK_BYTES];
         loop {
-            // Wait for data to be available
-            trace!("Polling {:?}", self.component_output_fifo_path);
-            let poll_flags = PollFlags::POLLIN;
-            poll(
-                &mut [PollFd::new(c_out_fd, poll_flags)],
-                (deadline - Instant::now()).as_millis().try_into()?,
-            )?;
+            // The message may already be here, from an earlier read
+            if incoming_message_bytes(&self.unread, framing) > *MAX_MESSAGE_BYTES {
+                return Err(WorkerErrorKind::MessageTooLarge(*MAX_MESSAGE_BYTES).into());
+            }
+            if let Some((message, framed_len)) = unframe(&self.unread, framing) {
+                self.unread.drain(..framed_len);
+                return Ok(message);
+            }
+
+            // Block until data is available (or we run out of time)
+            trace!("Polling {:?}", self.path);
+            let mut poll_fds = [PollFd::new(c_out_fd, PollFlags::POLLIN)];
+            let ready_fds = poll(&mut poll_fds, remaining_millis(deadline)?)?;
 
             // If we've timed out, then just return an error
-            if Instant::now() > deadline {
-                return Err(WorkerErrorKind::OperationTimedOut("pipe reading").into());
+            // Poll only returns zero ready fds when its timeout expires
+            if ready_fds == 0 || Instant::now() > deadline {
+                return Err(WorkerErrorKind::OperationTimedOut(timed_out_operation).into());
+            }
+
+            // Whatever the component wrote before closing its end is still readable, so only stop once that's gone
+            let readable = poll_fds[0]
+                .revents()
+                .map_or(false, |revents| revents.contains(PollFlags::POLLIN));
+            if !readable && peer_hung_up(poll_fds[0]) {
+                return Err(WorkerErrorKind::PipeDisconnected.into());
             }
 
             // Otherwise read n bytes
@@ -183,8 +290,8 @@ impl NamedPipe {
                 Ok(n) => n,
                 Err(e) => {
                     if e.as_errno() == Some(Errno::EAGAIN) {
+                        // Readiness can be spurious, so just go back to waiting
                         debug!("Trying again");
-                        sleep(Duration::from_millis(PIPE_POLL_INTERVAL_MS));
                         continue;
                     } else {
                         return Err(e.into());
@@ -197,23 +304,289 @@ impl NamedPipe {
                 return Err(WorkerErrorKind::PipeDisconnected.into());
             }
 
-            for &v in &read_buf[0..n] {
-                result.push(v);
-                if v == b'\n' {
-                    return Ok(result);
-                }
+            self.unread.extend_from_slice(&read_buf[0..n]);
+        }
+    }
+}
+
+// These fifos are created with 777 permissions, so components running as any user (see `run_as_user`) can
+// use them. mkfifo applies our umask, so the permissions have to be set again afterwards.
+fn make_fifo(path: &Path) -> Result<(), WorkerError> {
+    mkfifo(path, Mode::S_IRWXU | Mode::S_IRWXG | Mode::S_IRWXO)?;
+    set_permissions(path, Permissions::from_mode(0o777))?;
+    Ok(())
+}
+
+// Retries until `deadline`, since opening a fifo's write end fails until something opens the read end
+fn open_fifo(path: &Path, options: &OpenOptions, deadline: Instant) -> Result<File, WorkerError> {
+    loop {
+        let fifo = options.open(path);
+        trace!("Opening {:?}: {:?}", path, fifo);
+
+        if let Ok(fifo) = fifo {
+            resize_fifo(&fifo);
+            return Ok(fifo);
+        }
+        if Instant::now() >= deadline {
+            return Err(WorkerErrorKind::OperationTimedOut("fifo pipe opening").into());
+        }
+        sleep(Duration::from_millis(PIPE_POLL_INTERVAL_MS));
+    }
+}
+
+// A bigger buffer lets the component write more of a message before we have to read it, so big messages take
+// fewer poll/read cycles. The memory is held for as long as the pipe is open though, and unprivileged processes
+// can't go past /proc/sys/fs/pipe-max-size, so if resizing fails we just carry on with the default.
+fn resize_fifo(fifo: &File) {
+    if *FIFO_BUFFER_BYTES == 0 {
+        return;
+    }
+
+    let size = c_int::try_from(*FIFO_BUFFER_BYTES).unwrap_or(c_int::MAX);
+    if let Err(e) = fcntl(fifo.as_raw_fd(), FcntlArg::F_SETPIPE_SZ(size)) {
+        debug!("Could not resize fifo to {} bytes, err {}", size, e);
+    }
+}
+
+// The other end of a fifo being closed shows up as POLLHUP for readers and POLLERR for writers
+fn peer_hung_up(poll_fd: PollFd) -> bool {
+    poll_fd.revents().map_or(false, |revents| {
+        revents.intersects(PollFlags::POLLHUP | PollFlags::POLLERR)
+    })
+}
+
+// When a read or write has to finish by, and what to call it if it doesn't
+fn io_deadline(operation: &'static str, query_deadline: Option<Instant>) -> (Instant, &'static str) {
+    let deadline = Instant::now() + Duration::from_millis(PIPE_IO_TIMEOUT_MS);
+    match query_deadline {
+        Some(query_deadline) if query_deadline < deadline => (query_deadline, "component call"),
+        _ => (deadline, operation),
+    }
+}
+
+// Timeout for `poll`, which takes milliseconds as a c_int
+fn remaining_millis(deadline: Instant) -> Result<c_int, WorkerError> {
+    Ok(deadline
+        .saturating_duration_since(Instant::now())
+        .as_millis()
+        .try_into()?)
+}
+
+// Turns a message into the bytes we actually send down the pipe
+fn frame(v: &[u8], framing: PipeFraming) -> Result<Vec<u8>, WorkerError> {
+    match framing {
+        PipeFraming::Newline => {
+            // Passing in a newline violates the contract of this framing
+            if v.contains(&b'\n') {
+                return Err(
+                    WorkerErrorKind::InvalidSerialization("contains newline", v.to_vec()).into(),
+                );
             }
+
+            // Push a newline at the end to terminate the input
+            let mut framed = Vec::from(v);
+            framed.push(b'\n');
+            Ok(framed)
+        }
+        PipeFraming::LengthPrefixed => {
+            let len = u32::try_from(v.len())?;
+
+            let mut framed = Vec::with_capacity(LENGTH_PREFIX_SIZE + v.len());
+            framed.extend_from_slice(&len.to_be_bytes());
+            framed.extend_from_slice(v);
+            Ok(framed)
         }
     }
+}
 
-    pub fn component_output_file(&self) -> &Path {
-        &self.component_output_fifo_path
+// How big the first message in `buf` is, as far as we can tell yet (not counting its framing)
+// A length prefix tells us up front, so an oversized message is turned down before we read any of it
+fn incoming_message_bytes(buf: &[u8], framing: PipeFraming) -> usize {
+    match framing {
+        PipeFraming::Newline => buf.iter().position(|&b| b == b'\n').unwrap_or(buf.len()),
+        PipeFraming::LengthPrefixed => {
+            if buf.len() < LENGTH_PREFIX_SIZE {
+                return 0;
+            }
+
+            let mut prefix = [0; LENGTH_PREFIX_SIZE];
+            prefix.copy_from_slice(&buf[..LENGTH_PREFIX_SIZE]);
+            u32::from_be_bytes(prefix) as usize
+        }
     }
+}
 
-    pub fn query(&mut self, req: &str) -> Result<String, WorkerError> {
-        self.write(req.as_bytes())?;
+// Returns the first complete message in `buf` and how many bytes of `buf` it took up, or `None` if we need to
+// read more
+// NOTE: The newline framing keeps the trailing newline, since that's what callers have always received
+fn unframe(buf: &[u8], framing: PipeFraming) -> Option<(Vec<u8>, usize)> {
+    match framing {
+        PipeFraming::Newline => {
+            let end = buf.iter().position(|&b| b == b'\n')?;
+            Some((buf[..=end].to_vec(), end + 1))
+        }
+        PipeFraming::LengthPrefixed => {
+            if buf.len() < LENGTH_PREFIX_SIZE {
+                return None;
+            }
 
-        let read_bytes = self.read()?;
-        Ok(String::from_utf8(read_bytes)?)
+            let mut prefix = [0; LENGTH_PREFIX_SIZE];
+            prefix.copy_from_slice(&buf[..LENGTH_PREFIX_SIZE]);
+            let end = LENGTH_PREFIX_SIZE + u32::from_be_bytes(prefix) as usize;
+
+            if buf.len() < end {
+                return None;
+            }
+            Some((buf[LENGTH_PREFIX_SIZE..end].to_vec(), end))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::thread;
+
+    use super::*;
+
+    // Opens the component's ends of the pipe, the way a component would
+    fn connect(pipe: &mut NamedPipe) -> (File, File) {
+        // Our writer can only be opened once something has the component's input open for reading
+        let component_input = OpenOptions::new()
+            .read(true)
+            .custom_flags(OFlag::O_NONBLOCK.bits())
+            .open(pipe.component_input_file())
+            .unwrap();
+        pipe.open().unwrap();
+        let component_output = OpenOptions::new()
+            .write(true)
+            .open(pipe.component_output_file())
+            .unwrap();
+
+        (component_input, component_output)
+    }
+
+    #[test]
+    fn newline_framing() {
+        let framed = frame(b"hello", PipeFraming::Newline).unwrap();
+        assert_eq!(framed, b"hello\n");
+        assert!(frame(b"hel\nlo", PipeFraming::Newline).is_err());
+
+        assert_eq!(incoming_message_bytes(b"hel", PipeFraming::Newline), 3);
+        assert_eq!(incoming_message_bytes(b"hello\nmore", PipeFraming::Newline), 5);
+
+        assert_eq!(unframe(b"hel", PipeFraming::Newline), None);
+        assert_eq!(
+            unframe(b"hello\nmore", PipeFraming::Newline),
+            Some((b"hello\n".to_vec(), 6))
+        );
+    }
+
+    #[test]
+    fn length_prefixed_framing() {
+        let framed = frame(b"he\nllo", PipeFraming::LengthPrefixed).unwrap();
+        assert_eq!(framed, b"\0\0\0\x06he\nllo");
+
+        // The size is known as soon as the prefix is
+        assert_eq!(incoming_message_bytes(b"\0\0\0", PipeFraming::LengthPrefixed), 0);
+        assert_eq!(
+            incoming_message_bytes(&framed[..5], PipeFraming::LengthPrefixed),
+            6
+        );
+        assert_eq!(
+            incoming_message_bytes(b"\x01\0\0\0", PipeFraming::LengthPrefixed),
+            1 << 24
+        );
+
+        assert_eq!(unframe(&framed[..3], PipeFraming::LengthPrefixed), None);
+        assert_eq!(unframe(&framed[..9], PipeFraming::LengthPrefixed), None);
+        let mut with_more = framed;
+        with_more.extend_from_slice(b"more");
+        assert_eq!(
+            unframe(&with_more, PipeFraming::LengthPrefixed),
+            Some((b"he\nllo".to_vec(), 10))
+        );
+        assert_eq!(
+            unframe(b"\0\0\0\0", PipeFraming::LengthPrefixed),
+            Some((Vec::new(), 4))
+        );
+    }
+
+    // An oversized length prefix is turned down before anything else is read
+    #[test]
+    fn oversized_length_prefix_is_rejected() {
+        let mut pipe = NamedPipe::in_dir(tempfile::tempdir().unwrap()).unwrap();
+        let (_component_input, mut component_output) = connect(&mut pipe);
+
+        let too_large = u32::try_from(*MAX_MESSAGE_BYTES + 1).unwrap();
+        component_output.write_all(&too_large.to_be_bytes()).unwrap();
+
+        let e = pipe.read(PipeFraming::LengthPrefixed, None).unwrap_err();
+        assert!(
+            matches!(e.kind(), WorkerErrorKind::MessageTooLarge(max) if *max == *MAX_MESSAGE_BYTES),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn messages_written_together_are_read_separately() {
+        for &framing in &[PipeFraming::Newline, PipeFraming::LengthPrefixed] {
+            let mut pipe = NamedPipe::in_dir(tempfile::tempdir().unwrap()).unwrap();
+            let (_component_input, mut component_output) = connect(&mut pipe);
+
+            let mut both = frame(b"first", framing).unwrap();
+            both.extend(frame(b"second", framing).unwrap());
+            component_output.write_all(&both).unwrap();
+
+            let first = pipe.read(framing, None).unwrap();
+            let second = pipe.read(framing, None).unwrap();
+            match framing {
+                PipeFraming::Newline => {
+                    assert_eq!(first, b"first\n");
+                    assert_eq!(second, b"second\n");
+                }
+                PipeFraming::LengthPrefixed => {
+                    assert_eq!(first, b"first");
+                    assert_eq!(second, b"second");
+                }
+            }
+        }
+    }
+
+    // Every byte arrives well within the per-read timeout, but the whole response takes far longer than the call
+    // is allowed to, so the call has to time out anyway
+    #[test]
+    fn slow_drip_responses_time_out_with_the_call() {
+        let call_timeout = Duration::from_millis(300);
+        let drip_interval = Duration::from_millis(20);
+
+        let mut pipe = NamedPipe::in_dir(tempfile::tempdir().unwrap()).unwrap();
+        let (_component_input, mut component_output) = connect(&mut pipe);
+
+        let response = frame(&[b'x'; 100], PipeFraming::LengthPrefixed).unwrap();
+        let dripper = thread::spawn(move || {
+            for byte in response {
+                // Stops once the reader is gone
+                if component_output.write_all(&[byte]).is_err() {
+                    return;
+                }
+                thread::sleep(drip_interval);
+            }
+        });
+
+        let start = Instant::now();
+        let e = pipe
+            .query("request", PipeFraming::LengthPrefixed, Some(call_timeout))
+            .unwrap_err();
+        assert!(
+            matches!(e.kind(), WorkerErrorKind::OperationTimedOut("component call")),
+            "{}",
+            e
+        );
+        assert!(start.elapsed() < call_timeout + Duration::from_secs(1));
+
+        drop(pipe);
+        dripper.join().unwrap();
     }
 }
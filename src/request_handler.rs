@@ -1,14 +1,72 @@
-use std::str;
+use std::env;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::thread::available_parallelism;
+use std::time::Duration;
 
-use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderName, HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_TYPE, VARY,
+};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode, Uri};
 use parking_lot::RwLock;
 use tokio::stream::StreamExt;
-use tokio::task::spawn_blocking;
 
-use crate::component::ComponentManager;
+use crate::blocking_pool::spawn_tracked;
+use crate::component::{parse_query_pairs, response_color, ComponentManager, EarlyResponse};
+use crate::docker::idle_container_creator::{idle_pool_metrics, idle_pools_metrics};
+use crate::docker::CONTAINER_RUNTIME;
+use crate::env_utils::env_or;
 use crate::error::{WorkerError, WorkerErrorKind};
-use crate::model::{ComponentPath, StatusColor};
+use crate::logging::set_log_spec;
+use crate::model::{
+    ComponentPath, DeactivateRequest, LogLevelRequest, LogLevelResponse, MetricsResponse,
+    ShutdownResponse, StatusColor, VersionResponse,
+};
+use crate::scheduler::FairScheduler;
+use crate::settings;
+use crate::shutdown::request_shutdown;
+use crate::telemetry::{self, RequestSpan};
+
+// Identifies a request across our logs and the component's, clients can pick it by sending this header
+pub const X_REQUEST_ID: &str = "x-request-id";
+// Incoming IDs longer than this are replaced, so clients can't bloat every log line
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// W3C trace context, see https://www.w3.org/TR/trace-context/
+const TRACEPARENT: &str = "traceparent";
+
+// Where we keep the caller's address for the rest of the request, anything the client sent in it is replaced
+const CLIENT_IP: &str = "x-v9-client-ip";
+// Only read if V9_TRUST_FORWARDED_FOR is set, since clients can send anything in it
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+// By default we allow this many component calls per cpu to be in flight at once
+const DEFAULT_CONCURRENT_CALLS_PER_CPU: usize = 8;
+// How long a component call waits for a free slot before we give up with a 503 (0 rejects immediately)
+const DEFAULT_CONCURRENCY_QUEUE_MS: u64 = 5000;
+
+// The most events `/meta/stats/raw` returns for a component
+const MAX_RAW_STAT_EVENTS: usize = 10_000;
+
+// Responses smaller than this aren't worth the cpu time to compress
+const COMPRESSION_THRESHOLD_BYTES: usize = 1024;
+// Compressing these content types again just wastes time
+const PRECOMPRESSED_CONTENT_TYPES: &[&str] = &[
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "audio/",
+    "image/gif",
+    "image/jpeg",
+    "image/png",
+    "image/webp",
+    "video/",
+];
 
 // Warning: This method is somewhat complicated, since it needs to deal with async stuff
 // There should be no state here beyond the handler, so no need for an actual hyper service
@@ -16,59 +74,386 @@ use crate::model::{ComponentPath, StatusColor};
 pub async fn global_request_entrypoint(
     handler: Arc<HttpRequestHandler>,
     req: Request<Body>,
+    remote_addr: Option<SocketAddr>,
 ) -> Result<Response<Body>, WorkerError> {
-    debug!("{:?}", req);
-
     // Pull the verb, uri, and query stuff out of the request
     // (It's okay to do this, since it's all quite quick to execute)
     let http_verb = req.method().clone();
     let uri = req.uri().clone();
-    let query = uri.query().unwrap_or("").to_string();
+    let mut headers = req.headers().clone();
 
-    // Get a stream of Bytes representing the body of the request
-    let mut body_stream = req.into_body();
-    // Turn that stream into a concrete String
-    let mut body = String::new();
-    while let Some(chunk) = body_stream.next().await {
-        body.push_str(str::from_utf8(&chunk?)?);
+    // Everything downstream reads the ID from the headers, so we don't have to thread it through every call
+    let request_id = incoming_request_id(&headers).unwrap_or_else(generate_request_id);
+    let request_id_value = HeaderValue::from_str(&request_id)?;
+    headers.insert(HeaderName::from_static(X_REQUEST_ID), request_id_value.clone());
+    headers.remove(CLIENT_IP);
+    if let Some(ip) = resolve_client_ip(&headers, remote_addr, handler.trust_forwarded_for) {
+        headers.insert(
+            HeaderName::from_static(CLIENT_IP),
+            HeaderValue::from_str(&ip.to_string())?,
+        );
     }
 
-    debug!("body = {:?}", body);
+    // Also points the traceparent header at this span, so everything downstream continues the trace from it
+    let request_span = RequestSpan::start(&http_verb, uri.path(), &mut headers);
+
+    debug!("[{}] {:?}", request_id, req);
+
+    // Only component responses are compressed, meta responses are small and consumed internally
+    let should_compress = accepts_gzip(&headers) && uri.path().starts_with("/sl/");
+    let query = uri.query().unwrap_or("").to_string();
+
+    // The whole body is buffered in memory (it's sent to the component as part of a single JSON message), so
+    // it has to be capped, otherwise a few large uploads could exhaust the worker's memory
+    let body = match read_body(req.into_body(), &headers, settings::max_body_bytes()).await {
+        Ok(body) => body,
+        Err(e) if matches!(e.kind(), WorkerErrorKind::PayloadTooLarge(_)) => {
+            return Ok(reject_request(&request_id, request_id_value, e));
+        }
+        Err(e) => return Err(e),
+    };
+
+    debug!("[{}] body = {:?}", request_id, body);
+
+    // Component calls are limited, so a flood of them can't tie up an unbounded number of blocking threads
+    // Meta requests skip the limit, so we can still be monitored (and drained) while overloaded
+    let permit = if uri.path().starts_with("/sl/") {
+        match handler.call_scheduler.acquire(&scheduling_key(uri.path())).await {
+            Ok(permit) => Some(permit),
+            Err(e) => return Ok(reject_request(&request_id, request_id_value, e)),
+        }
+    } else {
+        None
+    };
+
+    // Streaming components send their response before the handler is done, everything else drops this unused
+    let (early_response, early_resp_receiver) = EarlyResponse::new();
 
     // We want to do the actual handling in a "spawn_blocking" closure, since many operations there can block
     // This allows us to handle a ton of requests at once, since we're not blocking the executor
-    let resp = spawn_blocking(move || {
+    let blocking_resp = spawn_tracked(move || {
+        // The permit is only released once the blocking work is done (even if the client has gone away)
+        let _permit = permit;
+        let _trace = telemetry::attach(&headers);
+
         // Delegate to the handler to actually deal with this request
-        // NOTE: We cannot handle panics here, since it could leave the handler in an inconsistent state
-        // Better to just bomb out
-        // TODO: Investigate handling panics at a lower level
-        handler.handle(http_verb, &uri, query, body)
-    })
-    .await?
-    .unwrap_or_else(|e| {
-        warn!("Forced to convert error {:?} into a http response", e);
-        e.into()
+        handler.handle(http_verb, &uri, &headers, query, body, early_response)
     });
 
+    let resp = match early_resp_receiver.await {
+        // The handler keeps going in the background, feeding the response body
+        Ok(resp) => resp,
+        Err(_) => blocking_resp
+            .await
+            // A panic in the handler shows up here as a join error, which we turn into a 500
+            // The component manager stays consistent, since parking_lot locks are released (without poisoning) as
+            // the panic unwinds, and the only component that could be left mid-update is the one this request had
+            // locked
+            .unwrap_or_else(|e| Err(e.into()))
+            .unwrap_or_else(|e| {
+                warn!(
+                    "[{}] Forced to convert error {:?} into a http response",
+                    request_id, e
+                );
+                e.into()
+            }),
+    };
+
+    let mut resp = if should_compress {
+        compress_response(resp).await?
+    } else {
+        resp
+    };
+    resp.headers_mut()
+        .insert(HeaderName::from_static(X_REQUEST_ID), request_id_value);
+
     if resp.status() == StatusCode::INTERNAL_SERVER_ERROR {
-        error!("INTERNAL SERVER ERROR -- {:?}", resp);
+        error!("[{}] INTERNAL SERVER ERROR -- {:?}", request_id, resp);
     } else {
-        debug!("{:?}", resp);
+        debug!("[{}] {:?}", request_id, resp);
     }
+    request_span.finish(resp.status());
 
     Ok(resp)
 }
 
+// Reads the component and event limit for `/meta/stats/raw` from the query
+fn raw_stats_params(query: &str) -> Result<(ComponentPath, usize), WorkerError> {
+    let query_pairs = parse_query_pairs(query);
+    let param = |name: &'static str| {
+        query_pairs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+    let path = ComponentPath::new(
+        param("user").ok_or(WorkerErrorKind::MissingQueryParameter("user"))?,
+        param("repo").ok_or(WorkerErrorKind::MissingQueryParameter("repo"))?,
+    );
+    // Busy components can have a lot of events in their window, so there is a hard cap
+    let limit = param("limit")
+        .and_then(|limit| limit.parse().ok())
+        .map_or(MAX_RAW_STAT_EVENTS, |limit: usize| limit.min(MAX_RAW_STAT_EVENTS));
+
+    Ok((path, limit))
+}
+
+// Parses "components/<user>/<repo>" meta routes
+fn component_path_from_route(route: &str) -> Result<ComponentPath, WorkerError> {
+    let segments: Vec<&str> = route.split('/').skip(1).collect();
+    match segments.as_slice() {
+        [user, repo] if !user.is_empty() && !repo.is_empty() => {
+            Ok(ComponentPath::new(user.to_string(), repo.to_string()))
+        }
+        _ => Err(WorkerErrorKind::PathNotFound("meta/".to_string() + route).into()),
+    }
+}
+
+// Calls are scheduled per component, i.e. by the "<user>/<repo>" part of "/sl/<user>/<repo>/..."
+fn scheduling_key(path: &str) -> String {
+    path.split('/').skip(2).take(2).collect::<Vec<_>>().join("/")
+}
+
+// Reads the whole body, giving up as soon as it's clear it's going to be larger than `max_bytes`
+async fn read_body(
+    mut body: Body,
+    headers: &HeaderMap,
+    max_bytes: usize,
+) -> Result<String, WorkerError> {
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.map_or(false, |length| length > max_bytes) {
+        return Err(WorkerErrorKind::PayloadTooLarge(max_bytes).into());
+    }
+
+    // Chunked bodies don't have a length up front, so we have to keep checking as they arrive
+    let mut bytes = Vec::with_capacity(content_length.unwrap_or(0));
+    while let Some(chunk) = body.next().await {
+        bytes.extend_from_slice(&chunk?);
+        if bytes.len() > max_bytes {
+            return Err(WorkerErrorKind::PayloadTooLarge(max_bytes).into());
+        }
+    }
+
+    // Decoding only once it's all here, since a multi-byte character can be split across chunks
+    Ok(String::from_utf8(bytes)?)
+}
+
+// Turns an error into a response, for requests we refuse before doing any real work
+fn reject_request(request_id: &str, request_id_value: HeaderValue, e: WorkerError) -> Response<Body> {
+    warn!("[{}] Rejecting request, {}", request_id, e);
+    let mut resp: Response<Body> = e.into();
+    resp.headers_mut()
+        .insert(HeaderName::from_static(X_REQUEST_ID), request_id_value);
+    resp
+}
+
+// The ID of a request, after `global_request_entrypoint` has made sure it has one
+pub fn request_id(headers: &HeaderMap) -> &str {
+    headers
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+}
+
+// The W3C trace context header, which is passed on to components (see `ComponentRequest::traceparent`)
+// Invalid values are ignored, as the spec requires
+pub fn traceparent(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(TRACEPARENT)?.to_str().ok()?;
+
+    // Looks like "00-<32 hex trace id>-<16 hex parent id>-<2 hex flags>", where neither id may be all zeros
+    let fields: Vec<&str> = value.split('-').collect();
+    let is_hex = |field: &str, len: usize| {
+        field.len() == len
+            && field
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    };
+    let not_zero = |field: &str| field.chars().any(|c| c != '0');
+
+    let valid = fields.len() == 4
+        && fields[0] == "00"
+        && is_hex(fields[1], 32)
+        && not_zero(fields[1])
+        && is_hex(fields[2], 16)
+        && not_zero(fields[2])
+        && is_hex(fields[3], 2);
+
+    if valid {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+// The caller's IP address, which is passed on to components (see `ComponentRequest::client_ip`)
+pub fn client_ip(headers: &HeaderMap) -> Option<&str> {
+    headers.get(CLIENT_IP)?.to_str().ok()
+}
+
+// Behind a trusted proxy, the caller is the last address the proxy appended to X-Forwarded-For (anything
+// before it came from the client, and could be made up). Otherwise it's whoever connected to us.
+fn resolve_client_ip(
+    headers: &HeaderMap,
+    remote_addr: Option<SocketAddr>,
+    trust_forwarded_for: bool,
+) -> Option<IpAddr> {
+    let forwarded_ip = if trust_forwarded_for {
+        headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .next_back()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok())
+    } else {
+        None
+    };
+
+    forwarded_ip.or_else(|| remote_addr.map(|addr| addr.ip()))
+}
+
+fn incoming_request_id(headers: &HeaderMap) -> Option<String> {
+    let id = headers.get(X_REQUEST_ID)?.to_str().ok()?;
+    if id.is_empty() || id.len() > MAX_REQUEST_ID_LEN {
+        return None;
+    }
+    Some(id.to_string())
+}
+
+fn generate_request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|encoding| {
+            // Each encoding looks like "gzip" or "gzip;q=0.5", where a q of 0 means "not acceptable"
+            let mut parts = encoding.split(';');
+            let name = parts.next().unwrap_or("").trim();
+            let rejected = parts.any(|param| {
+                let quality = param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f64>().ok());
+                matches!(quality, Some(q) if q <= 0.0)
+            });
+
+            (name == "gzip" || name == "*") && !rejected
+        })
+}
+
+async fn compress_response(resp: Response<Body>) -> Result<Response<Body>, WorkerError> {
+    // Streamed bodies (i.e. large responses spilled to disk) would have to be buffered to be compressed
+    if resp.body().size_hint().exact().is_none() {
+        return Ok(resp);
+    }
+
+    let already_encoded = resp.headers().contains_key(CONTENT_ENCODING);
+    let precompressed = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map_or(false, |content_type| {
+            PRECOMPRESSED_CONTENT_TYPES
+                .iter()
+                .any(|t| content_type.starts_with(t))
+        });
+    if already_encoded || precompressed {
+        return Ok(resp);
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let bytes = hyper::body::to_bytes(body).await?;
+    if bytes.len() < COMPRESSION_THRESHOLD_BYTES {
+        return Ok(Response::from_parts(parts, Body::from(bytes)));
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&bytes)?;
+    let compressed = encoder.finish()?;
+    debug!(
+        "Compressed response from {} to {} bytes",
+        bytes.len(),
+        compressed.len()
+    );
+
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    parts
+        .headers
+        .insert(CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+
+    Ok(Response::from_parts(parts, Body::from(compressed)))
+}
+
+// Compares in constant time (for equal length inputs), so response timing doesn't leak the token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// An empty token would let anyone in with "Authorization: Bearer ", and leaving the endpoints open is rarely what
+// someone setting the variable meant (more likely a secret didn't get filled in), so the worker won't start
+fn meta_token(value: Option<String>) -> Option<String> {
+    if let Some(token) = &value {
+        assert!(
+            !token.trim().is_empty(),
+            "V9_META_TOKEN is set but empty, unset it to leave the meta endpoints unauthenticated"
+        );
+    }
+    value
+}
+
 #[derive(Debug)]
 pub struct HttpRequestHandler {
     serverless_component_manager: RwLock<ComponentManager>,
+    // If set, meta requests must carry a matching "Authorization: Bearer <token>" header
+    meta_token: Option<String>,
+    // Limits the number of component calls in flight at once, sharing them fairly between components
+    call_scheduler: Arc<FairScheduler>,
+    // Whether X-Forwarded-For can be believed, i.e. we're only reachable through a proxy that sets it
+    trust_forwarded_for: bool,
 }
 
 #[allow(clippy::unused_self)]
 impl HttpRequestHandler {
     pub fn new() -> Self {
+        let meta_token = meta_token(env::var("V9_META_TOKEN").ok());
+        if meta_token.is_some() {
+            info!("meta endpoints require a bearer token");
+        } else {
+            warn!("V9_META_TOKEN not set, meta endpoints are unauthenticated");
+        }
+
+        let cpus = available_parallelism().map_or(1, usize::from);
+        let max_concurrent_calls =
+            env_or("V9_MAX_CONCURRENT_CALLS", cpus * DEFAULT_CONCURRENT_CALLS_PER_CPU);
+        let call_queue_timeout =
+            Duration::from_millis(env_or("V9_CONCURRENCY_QUEUE_MS", DEFAULT_CONCURRENCY_QUEUE_MS));
+        info!(
+            "allowing {} concurrent component calls, queueing for up to {:?}",
+            max_concurrent_calls, call_queue_timeout
+        );
+
         Self {
             serverless_component_manager: RwLock::new(ComponentManager::new()),
+            meta_token,
+            call_scheduler: Arc::new(FairScheduler::new(max_concurrent_calls, call_queue_timeout)),
+            trust_forwarded_for: env_or("V9_TRUST_FORWARDED_FOR", false),
         }
     }
 
@@ -77,58 +462,71 @@ impl HttpRequestHandler {
         &self,
         http_verb: Method,
         uri: &Uri,
+        headers: &HeaderMap,
         query: String,
         body: String,
+        early_response: EarlyResponse,
     ) -> Result<Response<Body>, WorkerError> {
         // Get the uri path, and then split it around slashes into components
         // Note: All URIs start with a slash, so we skip the first entry in the split (which is always just "")
-        let path_components: Vec<&str> = uri.path().split('/').skip(1).collect();
-        debug!("path = {:?}", path_components);
+        // Trailing slashes are dropped, so "/sl/user/repo/method/" is the same as "/sl/user/repo/method"
+        let path_components: Vec<&str> = uri.path().trim_end_matches('/').split('/').skip(1).collect();
+        debug!("[{}] path = {:?}", request_id(headers), path_components);
 
-        if path_components.len() == 2 && path_components[0] == "meta" {
+        if path_components.len() >= 2 && path_components[0] == "meta" {
             self.handle_meta_request(
                 &self.serverless_component_manager,
                 http_verb,
-                path_components[1],
+                &path_components[1..].join("/"),
+                headers,
+                &query,
                 &body,
             )
-        } else if path_components.len() >= 4 && path_components[0] == "sl" {
+        } else if path_components.len() >= 3 && path_components[0] == "sl" {
             let component_router = self.serverless_component_manager.read();
 
-            debug!("Starting serverless request processing...");
+            debug!(
+                "[{}] Starting serverless request processing...",
+                request_id(headers)
+            );
             let user = path_components[1].to_string();
             let repo = path_components[2].to_string();
-            let method = path_components[3];
+            // Without a method segment the component is called with an empty method, which it can treat as
+            // its index (or reject)
+            let method = path_components.get(3).copied().unwrap_or("");
+            let additional_path_components = path_components.get(4..).unwrap_or(&[]);
 
             let path = ComponentPath::new(user, repo);
             let component = component_router.lookup_component(&path);
 
             let resp = component.map_or_else(
                 || {
-                    warn!("Could not find serverless component {:?}", path);
-                    Err(WorkerErrorKind::PathNotFound(path_components.join("/")).into())
+                    warn!(
+                        "[{}] Could not find serverless component {:?}",
+                        request_id(headers),
+                        path
+                    );
+                    Err(WorkerErrorKind::ComponentNotFound(path.clone()).into())
                 },
                 |component_handle| {
+                    // Claimed before locking, so excess calls are shed instead of piling up on the lock
+                    let _call_slot = component_router.enter_call(&path)?;
+
                     let mut locked_handle = component_handle.lock();
-                    let call_resp = locked_handle.handle_component_call(
-                        method,
-                        &http_verb,
-                        &path_components[4..],
-                        query,
-                        body,
-                    );
+                    let call_resp = locked_handle
+                        .handle_component_call(
+                            method,
+                            &http_verb,
+                            additional_path_components,
+                            headers,
+                            query,
+                            body,
+                            early_response,
+                        )
+                        .map_err(|e| e.in_component(&path));
 
                     let color = match &call_resp {
-                        Ok(resp) => {
-                            if resp.status().is_success() || resp.status().is_redirection() {
-                                StatusColor::Green
-                            } else if resp.status().is_server_error() || resp.status() == 543 {
-                                StatusColor::Red
-                            } else {
-                                // Covers `resp.status().is_client_error()`
-                                StatusColor::Orange
-                            }
-                        }
+                        Ok(resp) => response_color(resp.status()),
                         Err(_) => StatusColor::Red,
                     };
                     locked_handle.set_color(color);
@@ -137,7 +535,11 @@ impl HttpRequestHandler {
                 },
             );
 
-            trace!("Finished serverless request processing... ({:?})", resp);
+            trace!(
+                "[{}] Finished serverless request processing... ({:?})",
+                request_id(headers),
+                resp
+            );
 
             resp
         } else {
@@ -146,43 +548,300 @@ impl HttpRequestHandler {
     }
 
     // TODO: Refactor to associated function
+    // This is mostly one arm per route, so there's no point splitting it up
+    #[allow(clippy::too_many_lines)]
     fn handle_meta_request(
         &self,
         component_manager: &RwLock<ComponentManager>,
         http_verb: Method,
         route: &str,
+        headers: &HeaderMap,
+        query: &str,
         body: &str,
     ) -> Result<Response<Body>, WorkerError> {
+        self.check_meta_authorization(headers)?;
+
+        let mut status = StatusCode::OK;
         let result_body = Body::from(match (route, http_verb) {
             ("activate", Method::POST) => {
-                let resp = component_manager.write().activate(serde_json::from_str(body));
+                let resp = if query.split('&').any(|param| param == "dry_run=true") {
+                    component_manager
+                        .read()
+                        .validate_activation(serde_json::from_str(body))
+                } else {
+                    ComponentManager::activate_shared(component_manager, serde_json::from_str(body))
+                };
+                serde_json::to_string(&resp)?
+            }
+            (route, Method::GET) if route.starts_with("components/") => {
+                let path = component_path_from_route(route)?;
+                let resp = component_manager.read().activation(&path)?;
+                serde_json::to_string(&resp)?
+            }
+            (route, Method::GET) if route.starts_with("logs/") => {
+                let path = component_path_from_route(route)?;
+                let resp = component_manager.read().logs_for(&path)?;
+                serde_json::to_string(&resp)?
+            }
+            (route, Method::GET) if route.starts_with("ping/") => {
+                let path = component_path_from_route(route)?;
+                let resp = component_manager.read().ping(&path, headers);
+                serde_json::to_string(&resp)?
+            }
+            ("control", Method::POST) => {
+                let resp = component_manager.read().control(serde_json::from_str(body));
                 serde_json::to_string(&resp)?
             }
             ("deactivate", Method::POST) => {
-                let resp = component_manager.write().deactivate(serde_json::from_str(body));
+                let deactivate_request: Result<DeactivateRequest, _> = serde_json::from_str(body);
+                // In-flight calls hold a read lock, so we have to interrupt them before we can get a write lock
+                if let Ok(request) = &deactivate_request {
+                    component_manager.read().interrupt(&request.id.path);
+                }
+                let resp = component_manager.write().deactivate(deactivate_request);
+                serde_json::to_string(&resp)?
+            }
+            ("deactivate-all", Method::POST) => {
+                component_manager.read().interrupt_all();
+                // The write lock is held for the whole drain, so no activations can interleave
+                let resp = component_manager.write().deactivate_all();
                 serde_json::to_string(&resp)?
             }
+            ("log-level", Method::POST) => {
+                let request: LogLevelRequest = serde_json::from_str(body)?;
+                set_log_spec(&request.spec)?;
+                info!(
+                    "[{}] Log specification changed to {:?}",
+                    request_id(headers),
+                    request.spec
+                );
+                serde_json::to_string(&LogLevelResponse { spec: request.spec })?
+            }
             ("logs", Method::GET) => {
-                let resp = component_manager.write().logs();
+                let resp = component_manager.read().logs();
+                serde_json::to_string(&resp)?
+            }
+            ("metrics", Method::GET) => {
+                let resp = MetricsResponse {
+                    idle_pool: idle_pool_metrics(),
+                    idle_pools: idle_pools_metrics(),
+                };
+                serde_json::to_string(&resp)?
+            }
+            ("reload", Method::POST) => {
+                let resp = settings::reload()?;
+                info!("[{}] Settings reloaded: {:?}", request_id(headers), resp);
+                serde_json::to_string(&resp)?
+            }
+            ("shutdown", Method::POST) => {
+                // The drain happens after this response is sent, since in-flight requests are allowed to finish
+                status = StatusCode::ACCEPTED;
+                let already_shutting_down = request_shutdown("POST /meta/shutdown");
+                serde_json::to_string(&ShutdownResponse {
+                    already_shutting_down,
+                })?
+            }
+            ("stats/raw", Method::GET) => {
+                let (path, limit) = raw_stats_params(query)?;
+                let resp = component_manager.read().raw_stats(&path, limit)?;
+                serde_json::to_string(&resp)?
+            }
+            ("version", Method::GET) => {
+                let resp = VersionResponse {
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    // These are set by our build script
+                    git_commit: env!("V9_GIT_COMMIT").to_string(),
+                    build_timestamp: env!("V9_BUILD_TIMESTAMP").parse().unwrap_or(0),
+                    container_runtime: CONTAINER_RUNTIME.to_string(),
+                };
+                serde_json::to_string(&resp)?
+            }
+            ("warm", Method::POST) => {
+                let resp = component_manager.read().warm(serde_json::from_str(body));
                 serde_json::to_string(&resp)?
             }
             ("status", Method::GET) => {
-                let resp = component_manager.read().status();
+                let detailed = query.split('&').any(|param| param == "detailed=true");
+                let resp = component_manager.read().status(detailed);
                 serde_json::to_string(&resp)?
             }
 
-            ("activate", _) | ("deactivate", _) | ("logs", _) | ("status", _) => {
+            (route, _)
+                if route.starts_with("components/")
+                    || route.starts_with("logs/")
+                    || route.starts_with("ping/") =>
+            {
                 return Err(WorkerErrorKind::WrongMethod.into())
             }
+            ("activate", _)
+            | ("control", _)
+            | ("deactivate", _)
+            | ("deactivate-all", _)
+            | ("log-level", _)
+            | ("logs", _)
+            | ("metrics", _)
+            | ("reload", _)
+            | ("shutdown", _)
+            | ("stats/raw", _)
+            | ("status", _)
+            | ("version", _)
+            | ("warm", _) => return Err(WorkerErrorKind::WrongMethod.into()),
             _ => return Err(WorkerErrorKind::PathNotFound("meta/".to_string() + route).into()),
         });
-        Ok(Response::builder()
-            .status(StatusCode::OK)
-            .body(result_body)
-            .unwrap())
+        Ok(Response::builder().status(status).body(result_body).unwrap())
+    }
+
+    fn check_meta_authorization(&self, headers: &HeaderMap) -> Result<(), WorkerError> {
+        let provided_token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        let authorized = match (&self.meta_token, provided_token) {
+            (None, _) => true,
+            (Some(expected), Some(provided)) => {
+                constant_time_eq(provided.as_bytes(), expected.as_bytes())
+            }
+            (Some(_), None) => false,
+        };
+
+        if authorized {
+            Ok(())
+        } else {
+            warn!(
+                "[{}] Rejecting meta request with missing or invalid authorization",
+                request_id(headers)
+            );
+            Err(WorkerErrorKind::Unauthorized.into())
+        }
     }
 
     pub fn component_manager(&self) -> &RwLock<ComponentManager> {
         &self.serverless_component_manager
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    #[test]
+    fn meta_tokens_are_optional() {
+        assert_eq!(meta_token(None), None);
+        assert_eq!(meta_token(Some("secret".to_string())), Some("secret".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "V9_META_TOKEN is set but empty")]
+    fn empty_meta_tokens_are_rejected() {
+        meta_token(Some(" ".to_string()));
+    }
+
+    fn headers(name: &'static str, values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn gzip_is_accepted_unless_its_quality_is_zero() {
+        let accepts = |values: &[&'static str]| accepts_gzip(&headers("accept-encoding", values));
+
+        assert!(accepts(&["gzip"]));
+        assert!(accepts(&["deflate, gzip;q=0.5"]));
+        assert!(accepts(&["deflate", "br, gzip"]));
+        assert!(accepts(&["*"]));
+
+        assert!(!accepts(&[]));
+        assert!(!accepts(&["deflate, br"]));
+        assert!(!accepts(&["gzip;q=0"]));
+        assert!(!accepts(&["gzip; q=0.0"]));
+        assert!(!accepts(&["x-gzip"]));
+    }
+
+    fn response(content_type: &'static str, body: Body) -> Response<Body> {
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn large_responses_are_compressed() {
+        let body = "hello ".repeat(COMPRESSION_THRESHOLD_BYTES);
+        let resp = compress_response(response("text/plain", Body::from(body.clone())))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(resp.headers()[VARY], "Accept-Encoding");
+        let compressed = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[tokio::test]
+    async fn some_responses_are_left_alone() {
+        let large = || Body::from(vec![b'x'; COMPRESSION_THRESHOLD_BYTES * 2]);
+
+        let small = response("text/plain", Body::from("hello"));
+        let precompressed = response("image/png", large());
+        let mut encoded = response("text/plain", large());
+        encoded
+            .headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        // Streamed, so its size isn't known up front
+        let (mut sender, streamed_body) = Body::channel();
+        sender
+            .send_data(vec![b'x'; COMPRESSION_THRESHOLD_BYTES * 2].into())
+            .await
+            .unwrap();
+        drop(sender);
+        let streamed = response("text/plain", streamed_body);
+
+        for resp in [small, precompressed, encoded, streamed] {
+            let resp = compress_response(resp).await.unwrap();
+            assert_ne!(
+                resp.headers().get(CONTENT_ENCODING),
+                Some(&HeaderValue::from_static("gzip"))
+            );
+        }
+    }
+
+    #[test]
+    fn valid_traceparents_are_passed_on() {
+        let valid = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        assert_eq!(traceparent(&headers(TRACEPARENT, &[valid])), Some(valid));
+        assert_eq!(traceparent(&HeaderMap::new()), None);
+
+        for invalid in &[
+            // Unknown version
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            // All zero ids
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            // Uppercase, or the wrong length
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-00",
+            "",
+        ] {
+            assert_eq!(
+                traceparent(&headers(TRACEPARENT, &[invalid])),
+                None,
+                "{:?}",
+                invalid
+            );
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use crate::error::{WorkerError, WorkerErrorKind};
+
+// Limits the number of component calls in flight at once, like a semaphore
+// When the worker is saturated, freed slots go round-robin to the components with calls waiting, instead of to
+// whichever call has waited longest. That way a component flooding the worker only delays its own calls, and a
+// quiet component waits for at most one slot per busy component ahead of it.
+#[derive(Debug)]
+pub struct FairScheduler {
+    state: Mutex<SchedulerState>,
+    queue_timeout: Duration,
+}
+
+#[derive(Debug)]
+struct SchedulerState {
+    free_slots: usize,
+    // Waiting calls per component, oldest first
+    queues: HashMap<String, VecDeque<oneshot::Sender<()>>>,
+    // Components with waiting calls, in the order they get the next free slots
+    rotation: VecDeque<String>,
+}
+
+impl FairScheduler {
+    pub fn new(max_concurrent_calls: usize, queue_timeout: Duration) -> Self {
+        Self {
+            state: Mutex::new(SchedulerState {
+                free_slots: max_concurrent_calls,
+                queues: HashMap::new(),
+                rotation: VecDeque::new(),
+            }),
+            queue_timeout,
+        }
+    }
+
+    // Waits (up to the queue timeout) for a free call slot, on behalf of `component`
+    pub async fn acquire(self: &Arc<Self>, component: &str) -> Result<CallPermit, WorkerError> {
+        let mut receiver = {
+            let mut state = self.state.lock();
+            // Calls only skip the queue if nobody is in it, otherwise they'd jump ahead of the rotation
+            if state.free_slots > 0 && state.rotation.is_empty() {
+                state.free_slots -= 1;
+                return Ok(self.permit());
+            }
+            if self.queue_timeout == Duration::from_secs(0) {
+                return Err(WorkerErrorKind::Overloaded.into());
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            let queue = state.queues.entry(component.to_string()).or_default();
+            queue.push_back(sender);
+            if queue.len() == 1 {
+                state.rotation.push_back(component.to_string());
+            }
+            receiver
+        };
+
+        if let Ok(Ok(())) = timeout(self.queue_timeout, &mut receiver).await {
+            return Ok(self.permit());
+        }
+
+        // A slot may have been handed over right as we gave up, in which case it's ours to use
+        receiver.close();
+        if receiver.try_recv().is_ok() {
+            Ok(self.permit())
+        } else {
+            Err(WorkerErrorKind::Overloaded.into())
+        }
+    }
+
+    fn permit(self: &Arc<Self>) -> CallPermit {
+        CallPermit {
+            scheduler: Arc::clone(self),
+        }
+    }
+
+    // Hands the slot to the next waiting call in the rotation, or frees it if there are none
+    fn release(&self) {
+        let mut state = self.state.lock();
+        while let Some(component) = state.rotation.pop_front() {
+            let (sender, still_waiting) = match state.queues.get_mut(&component) {
+                Some(queue) => (queue.pop_front(), !queue.is_empty()),
+                None => (None, false),
+            };
+            if still_waiting {
+                state.rotation.push_back(component);
+            } else {
+                state.queues.remove(&component);
+            }
+
+            // Calls that timed out are still queued, handing them the slot fails and we move on
+            if sender.map_or(false, |sender| sender.send(()).is_ok()) {
+                return;
+            }
+        }
+
+        state.free_slots += 1;
+    }
+}
+
+// Holds a call slot until it's dropped
+#[derive(Debug)]
+pub struct CallPermit {
+    scheduler: Arc<FairScheduler>,
+}
+
+impl Drop for CallPermit {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::delay_for;
+
+    use super::*;
+
+    const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+    // Long enough for a spawned call to get into the queue
+    const SETTLE: Duration = Duration::from_millis(20);
+
+    #[tokio::test]
+    async fn freed_slots_go_round_robin() {
+        let scheduler = Arc::new(FairScheduler::new(1, QUEUE_TIMEOUT));
+        let first = scheduler.acquire("busy").await.unwrap();
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut calls = Vec::new();
+        for (component, call) in &[("busy", "busy 2"), ("busy", "busy 3"), ("quiet", "quiet 1")] {
+            let scheduler = scheduler.clone();
+            let order = order.clone();
+            calls.push(tokio::spawn(async move {
+                let _permit = scheduler.acquire(component).await.unwrap();
+                order.lock().push(*call);
+                delay_for(SETTLE).await;
+            }));
+            delay_for(SETTLE).await;
+        }
+
+        drop(first);
+        for call in calls {
+            call.await.unwrap();
+        }
+        // The quiet component only waits for one of the busy component's calls
+        assert_eq!(*order.lock(), vec!["busy 2", "quiet 1", "busy 3"]);
+        assert_eq!(scheduler.state.lock().free_slots, 1);
+    }
+
+    #[tokio::test]
+    async fn calls_that_wait_too_long_are_rejected() {
+        let scheduler = Arc::new(FairScheduler::new(1, SETTLE));
+        let first = scheduler.acquire("a").await.unwrap();
+
+        let e = scheduler.acquire("b").await.unwrap_err();
+        assert!(matches!(e.kind(), WorkerErrorKind::Overloaded), "{}", e);
+
+        // The slot isn't handed to the call that gave up
+        drop(first);
+        assert_eq!(scheduler.state.lock().free_slots, 1);
+        assert!(scheduler.acquire("b").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn no_queue_timeout_rejects_right_away() {
+        let scheduler = Arc::new(FairScheduler::new(1, Duration::from_secs(0)));
+        let _first = scheduler.acquire("a").await.unwrap();
+
+        let e = scheduler.acquire("a").await.unwrap_err();
+        assert!(matches!(e.kind(), WorkerErrorKind::Overloaded), "{}", e);
+    }
+}
@@ -1,48 +1,355 @@
 use std::convert::Infallible;
+use std::env;
 use std::error::Error;
+use std::fs::{remove_file, symlink_metadata};
 use std::future::Future;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::thread::available_parallelism;
+use std::time::Duration;
 
+use hyper::server::conn::AddrStream;
+#[cfg(feature = "tls")]
+use hyper::server::conn::Http;
+use hyper::server::{accept, Builder};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
-use tokio::runtime::Runtime;
+#[cfg(feature = "tls")]
+use tokio::net::TcpListener;
+use tokio::net::UnixListener;
+use tokio::runtime::Builder as RuntimeBuilder;
 use tokio::spawn;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+
+use crate::env_utils::env_or;
+use crate::shutdown::{listen_for_signals, shutdown_requested};
+#[cfg(feature = "tls")]
+use crate::tls::load_acceptor;
 
 const PRODUCTION_PORT: u16 = 80;
 const DEVELOPMENT_PORT: u16 = 8082;
 
+// tokio's default, which is plenty since component calls are also limited by `V9_MAX_CONCURRENT_CALLS`
+const DEFAULT_MAX_BLOCKING_THREADS: usize = 512;
+
+// Everything about how we serve HTTP, read from the environment in `ServerConfig::from_env`
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub listener: Listener,
+    // By default we speak HTTP/1, and also HTTP/2 for clients that use it with prior knowledge
+    pub http2_only: bool,
+    // `None` leaves TCP keepalive off (it doesn't apply to unix sockets anyway)
+    pub tcp_keepalive: Option<Duration>,
+    // `None` means no limit
+    pub http2_max_concurrent_streams: Option<u32>,
+    // If set, we serve HTTPS instead of HTTP
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsFiles>,
+    // Threads running async work (accepting connections, reading bodies, ...), one per cpu by default
+    pub worker_threads: usize,
+    // Threads running blocking work, which includes every component call
+    pub max_blocking_threads: usize,
+}
+
+// PEM files, from `V9_TLS_CERT` and `V9_TLS_KEY`
+#[cfg(feature = "tls")]
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[cfg(feature = "tls")]
+fn tls_files_from_env(listener: &Listener) -> Option<TlsFiles> {
+    match (env::var_os("V9_TLS_CERT"), env::var_os("V9_TLS_KEY")) {
+        (None, None) => None,
+        (Some(cert), Some(key)) => {
+            if let Listener::Unix(_) = listener {
+                panic!("TLS is only supported when listening on tcp");
+            }
+            Some(TlsFiles {
+                cert: cert.into(),
+                key: key.into(),
+            })
+        }
+        _ => panic!("V9_TLS_CERT and V9_TLS_KEY must be set together"),
+    }
+}
+
+// Without the "tls" feature, asking for TLS is a mistake we shouldn't silently ignore
+#[cfg(not(feature = "tls"))]
+fn check_tls_unconfigured() {
+    assert!(
+        env::var_os("V9_TLS_CERT").is_none() && env::var_os("V9_TLS_KEY").is_none(),
+        "V9_TLS_CERT/V9_TLS_KEY are set, but this worker was built without the \"tls\" feature"
+    );
+}
+
+impl ServerConfig {
+    // The defaults match hyper's, so leaving everything unset behaves exactly like `Server::bind`
+    pub fn from_env(development_mode: bool) -> Self {
+        let tcp_keepalive_secs: u64 = env_or("V9_TCP_KEEPALIVE_SECS", 0);
+        let http2_max_concurrent_streams: u32 = env_or("V9_HTTP2_MAX_CONCURRENT_STREAMS", 0);
+        let listener = Listener::from_env(development_mode);
+        #[cfg(not(feature = "tls"))]
+        check_tls_unconfigured();
+
+        Self {
+            #[cfg(feature = "tls")]
+            tls: tls_files_from_env(&listener),
+            listener,
+            http2_only: env_or("V9_HTTP2_ONLY", false),
+            tcp_keepalive: Some(Duration::from_secs(tcp_keepalive_secs))
+                .filter(|keepalive| *keepalive > Duration::from_secs(0)),
+            http2_max_concurrent_streams: Some(http2_max_concurrent_streams).filter(|max| *max > 0),
+            worker_threads: env_or(
+                "V9_WORKER_THREADS",
+                available_parallelism().map_or(1, usize::from),
+            ),
+            max_blocking_threads: env_or("V9_MAX_BLOCKING_THREADS", DEFAULT_MAX_BLOCKING_THREADS),
+        }
+    }
+
+    fn configure<I>(&self, builder: Builder<I>) -> Builder<I> {
+        builder
+            .http2_only(self.http2_only)
+            .http2_max_concurrent_streams(self.http2_max_concurrent_streams)
+    }
+}
+
+// Where the server accepts connections from
+#[derive(Clone, Debug)]
+pub enum Listener {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Listener {
+    // `V9_LISTEN` can be "unix:/path/to.sock" or "tcp:0.0.0.0:80", and defaults to tcp on all interfaces
+    pub fn from_env(development_mode: bool) -> Self {
+        if let Ok(listen) = env::var("V9_LISTEN") {
+            Self::parse(&listen).unwrap_or_else(|| {
+                panic!(
+                    "V9_LISTEN must look like \"unix:/path/to.sock\" or \"tcp:0.0.0.0:80\", got {:?}",
+                    listen
+                )
+            })
+        } else {
+            let port = if development_mode {
+                DEVELOPMENT_PORT
+            } else {
+                PRODUCTION_PORT
+            };
+            Self::Tcp(([0, 0, 0, 0], port).into())
+        }
+    }
+
+    fn parse(listen: &str) -> Option<Self> {
+        if let Some(path) = listen.strip_prefix("unix:") {
+            Some(Self::Unix(PathBuf::from(path)))
+        } else if let Some(addr) = listen.strip_prefix("tcp:") {
+            addr.parse().ok().map(Self::Tcp)
+        } else {
+            None
+        }
+    }
+}
+
 pub fn start_server<S, E, F>(
-    development_mode: bool,
+    config: &ServerConfig,
     state: Arc<S>,
-    handler: fn(Arc<S>, Request<Body>) -> F,
+    handler: fn(Arc<S>, Request<Body>, Option<SocketAddr>) -> F,
 ) where
     S: Send + Sync + 'static,
     E: Error + Send + Sync + 'static,
     F: Future<Output = Result<Response<Body>, E>> + Send + 'static,
 {
-    Runtime::new().expect("Only should be called from main").block_on(async {
-        let port = if development_mode {
-            DEVELOPMENT_PORT
-        } else {
-            PRODUCTION_PORT
+    assert!(
+        config.worker_threads > 0 && config.max_blocking_threads > 0,
+        "V9_WORKER_THREADS and V9_MAX_BLOCKING_THREADS must be at least 1"
+    );
+
+    // tokio's thread limit covers both kinds of threads
+    RuntimeBuilder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .core_threads(config.worker_threads)
+        .max_threads(config.worker_threads + config.max_blocking_threads)
+        .build()
+        .expect("Only should be called from main")
+        .block_on(async {
+            info!("Serving with {:?}", config);
+            spawn(listen_for_signals());
+
+            match &config.listener {
+                Listener::Tcp(addr) => {
+                    #[cfg(feature = "tls")]
+                    {
+                        if let Some(tls) = &config.tls {
+                            // Loading happens before we bind, so bad certificates fail startup
+                            let acceptor = load_acceptor(&tls.cert, &tls.key, config.http2_only);
+                            info!("Spinning up HTTPS server on {:?}", addr);
+                            serve_tls(addr, acceptor, config, state, handler).await;
+                            return;
+                        }
+                    }
+
+                    info!("Spinning up server on {:?}", addr);
+
+                    let new_service = make_service_fn(move |conn: &AddrStream| {
+                        let copied_state = state.clone();
+                        let remote_addr = conn.remote_addr();
+                        async move {
+                            Ok::<_, Infallible>(service_fn(move |req| {
+                                handler(copied_state.clone(), req, Some(remote_addr))
+                            }))
+                        }
+                    });
+
+                    let builder = Server::bind(addr).tcp_keepalive(config.tcp_keepalive);
+                    let server = config
+                        .configure(builder)
+                        .serve(new_service)
+                        .with_graceful_shutdown(shutdown_requested());
+
+                    spawn(server)
+                        .await
+                        .expect("Server should be created successfully")
+                        .expect("Our service is infallible");
+                }
+                Listener::Unix(path) => {
+                    info!("Spinning up server on unix socket {:?}", path);
+
+                    remove_stale_socket(path);
+                    let mut unix_listener =
+                        UnixListener::bind(path).expect("Unix socket should be bindable");
+
+                    // Unix socket peers don't have an address
+                    let new_service = make_service_fn(move |_| {
+                        let copied_state = state.clone();
+                        async move {
+                            Ok::<_, Infallible>(service_fn(move |req| {
+                                handler(copied_state.clone(), req, None)
+                            }))
+                        }
+                    });
+
+                    // The listener is borrowed by the server, so we run it in place instead of spawning it
+                    let builder = Server::builder(accept::from_stream(unix_listener.incoming()));
+                    let server_result = config
+                        .configure(builder)
+                        .serve(new_service)
+                        .with_graceful_shutdown(shutdown_requested())
+                        .await;
+
+                    if let Err(e) = remove_file(path) {
+                        warn!("Could not clean up unix socket {:?}: {}", path, e);
+                    }
+
+                    server_result.expect("Our service is infallible");
+                }
+            }
+        });
+}
+
+// Hyper's server builder can't do TLS handshakes, so we run the accept loop ourselves
+#[cfg(feature = "tls")]
+async fn serve_tls<S, E, F>(
+    addr: &SocketAddr,
+    acceptor: TlsAcceptor,
+    config: &ServerConfig,
+    state: Arc<S>,
+    handler: fn(Arc<S>, Request<Body>, Option<SocketAddr>) -> F,
+) where
+    S: Send + Sync + 'static,
+    E: Error + Send + Sync + 'static,
+    F: Future<Output = Result<Response<Body>, E>> + Send + 'static,
+{
+    let mut tcp_listener = TcpListener::bind(addr)
+        .await
+        .expect("Tcp address should be bindable");
+
+    let mut http = Http::new();
+    http.http2_only(config.http2_only)
+        .http2_max_concurrent_streams(config.http2_max_concurrent_streams);
+
+    loop {
+        // Connections that were already accepted are left to finish on their own
+        let accepted = tokio::select! {
+            accepted = tcp_listener.accept() => accepted,
+            _ = shutdown_requested() => return,
         };
+        let (tcp_stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Could not accept tcp connection: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = tcp_stream.set_keepalive(config.tcp_keepalive) {
+            warn!("Could not set tcp keepalive for {:?}: {}", peer, e);
+        }
 
-        let addr = ([0, 0, 0, 0], port).into();
-        info!("Spinning up server on {:?}", addr);
+        let acceptor = acceptor.clone();
+        let http = http.clone();
+        let copied_state = state.clone();
+        spawn(async move {
+            // A failed handshake only affects that one connection
+            let tls_stream = match acceptor.accept(tcp_stream).await {
+                Ok(tls_stream) => tls_stream,
+                Err(e) => {
+                    debug!("TLS handshake with {:?} failed: {}", peer, e);
+                    return;
+                }
+            };
 
-        let new_service = make_service_fn(move |_| {
-            let copied_state = state.clone();
-            async move {
-                Ok::<_, Infallible>(service_fn(move |req| handler(copied_state.clone(), req)))
+            let service = service_fn(move |req| handler(copied_state.clone(), req, Some(peer)));
+            if let Err(e) = http.serve_connection(tls_stream, service).await {
+                debug!("Error serving TLS connection from {:?}: {}", peer, e);
             }
         });
+    }
+}
+
+// A previous (crashed) worker may have left its socket behind, which would make binding fail
+fn remove_stale_socket(path: &Path) {
+    if let Ok(metadata) = symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            warn!("Removing stale unix socket {:?}", path);
+            if let Err(e) = remove_file(path) {
+                warn!("Could not remove stale unix socket {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let server = Server::bind(&addr)
-            .serve(new_service);
+    #[test]
+    fn listeners_are_parsed() {
+        assert!(matches!(
+            Listener::parse("tcp:127.0.0.1:8080"),
+            Some(Listener::Tcp(addr)) if addr == ([127, 0, 0, 1], 8080).into()
+        ));
+        assert!(matches!(Listener::parse("tcp:[::1]:80"), Some(Listener::Tcp(_))));
+        assert!(matches!(
+            Listener::parse("unix:/run/v9.sock"),
+            Some(Listener::Unix(path)) if path == Path::new("/run/v9.sock")
+        ));
 
-        spawn(server)
-            .await
-            .expect("Server should be created successfully")
-            .expect("Our service is infallible");
-    });
+        for invalid in &[
+            "127.0.0.1:8080",
+            "tcp:localhost:80",
+            "tcp:127.0.0.1",
+            "udp:127.0.0.1:80",
+            "",
+        ] {
+            assert!(Listener::parse(invalid).is_none(), "{:?}", invalid);
+        }
+    }
 }
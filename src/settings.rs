@@ -0,0 +1,164 @@
+// The settings that can be changed without restarting the worker (see `POST /meta/reload`)
+// A running process's environment can't be changed from the outside, so they can also be set in the file named by
+// V9_SETTINGS_FILE, which takes precedence over the environment. Every other setting is only read at startup.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::error::{WorkerError, WorkerErrorKind};
+use crate::logging::{set_log_spec, DEFAULT_LOG_SPEC};
+use crate::model::ReloadResponse;
+
+const DEFAULT_HEARTBEAT_MS: u64 = 1000;
+// Components that haven't been called for this long are stopped (they're booted again on their next call)
+const DEFAULT_IDLE_EXPIRY_SECS: u64 = 60 * 10;
+// Request bodies larger than this get a 413
+const DEFAULT_MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
+
+const RELOADABLE_SETTINGS: &[&str] = &[
+    "V9_HEARTBEAT_MS",
+    "V9_IDLE_EXPIRY_SECS",
+    "V9_LOG",
+    "V9_MAX_BODY_BYTES",
+];
+
+static HEARTBEAT_MS: AtomicU64 = AtomicU64::new(DEFAULT_HEARTBEAT_MS);
+static IDLE_EXPIRY_SECS: AtomicU64 = AtomicU64::new(DEFAULT_IDLE_EXPIRY_SECS);
+static MAX_BODY_BYTES: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_BODY_BYTES);
+
+pub fn heartbeat_period() -> Duration {
+    Duration::from_millis(HEARTBEAT_MS.load(Ordering::SeqCst))
+}
+
+pub fn idle_expiry() -> Duration {
+    Duration::from_secs(IDLE_EXPIRY_SECS.load(Ordering::SeqCst))
+}
+
+pub fn max_body_bytes() -> usize {
+    MAX_BODY_BYTES.load(Ordering::SeqCst)
+}
+
+// Applies the settings at startup, so a bad settings file panics right away instead of on the first reload
+pub fn init() {
+    match reload() {
+        Ok(settings) => info!("Settings loaded: {:?}", settings),
+        Err(e) => panic!("Could not load settings: {}", e),
+    }
+}
+
+// Reads the settings file and the environment again, and applies what they say
+// Everything is checked before anything is applied, so one bad value leaves all the settings as they were
+pub fn reload() -> Result<ReloadResponse, WorkerError> {
+    let overrides = match env::var_os("V9_SETTINGS_FILE") {
+        Some(path) => parse_settings_file(&fs::read_to_string(path)?)?,
+        None => HashMap::new(),
+    };
+    let lookup = |name: &str| overrides.get(name).cloned().or_else(|| env::var(name).ok());
+
+    let settings = ReloadResponse {
+        heartbeat_ms: parse_setting(lookup("V9_HEARTBEAT_MS"), "V9_HEARTBEAT_MS", DEFAULT_HEARTBEAT_MS)?,
+        idle_expiry_secs: parse_setting(
+            lookup("V9_IDLE_EXPIRY_SECS"),
+            "V9_IDLE_EXPIRY_SECS",
+            DEFAULT_IDLE_EXPIRY_SECS,
+        )?,
+        log_spec: lookup("V9_LOG").unwrap_or_else(|| DEFAULT_LOG_SPEC.to_string()),
+        max_body_bytes: parse_setting(
+            lookup("V9_MAX_BODY_BYTES"),
+            "V9_MAX_BODY_BYTES",
+            DEFAULT_MAX_BODY_BYTES,
+        )?,
+    };
+    if settings.heartbeat_ms == 0 {
+        return Err(invalid_setting("V9_HEARTBEAT_MS", "must be at least 1"));
+    }
+
+    // This is the only one that can still fail, so it goes first
+    set_log_spec(&settings.log_spec)?;
+    HEARTBEAT_MS.store(settings.heartbeat_ms, Ordering::SeqCst);
+    IDLE_EXPIRY_SECS.store(settings.idle_expiry_secs, Ordering::SeqCst);
+    MAX_BODY_BYTES.store(settings.max_body_bytes, Ordering::SeqCst);
+
+    Ok(settings)
+}
+
+// One `NAME=value` per line, blank lines and lines starting with # are skipped
+fn parse_settings_file(contents: &str) -> Result<HashMap<String, String>, WorkerError> {
+    let mut settings = HashMap::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts
+            .next()
+            .ok_or_else(|| invalid_setting(name, "expected a line like NAME=value"))?;
+        if !RELOADABLE_SETTINGS.contains(&name) {
+            return Err(invalid_setting(
+                name,
+                "it can't be reloaded, so it can only be set in the environment",
+            ));
+        }
+
+        settings.insert(name.to_string(), value.trim().to_string());
+    }
+
+    Ok(settings)
+}
+
+// Unlike `env_or`, a value that doesn't parse is an error, since it would otherwise quietly undo a reload
+fn parse_setting<T: FromStr>(value: Option<String>, name: &str, default: T) -> Result<T, WorkerError> {
+    match value {
+        Some(value) => value
+            .parse()
+            .map_err(|_| invalid_setting(name, &format!("{:?} isn't a valid value", value))),
+        None => Ok(default),
+    }
+}
+
+fn invalid_setting(name: &str, problem: &str) -> WorkerError {
+    WorkerErrorKind::InvalidSetting(name.to_string(), problem.to_string()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_files_are_parsed() {
+        let settings = parse_settings_file(
+            "# Slower heartbeats\n\n  V9_HEARTBEAT_MS = 2000  \nV9_LOG=info,v9_worker=debug\nV9_MAX_BODY_BYTES=\n",
+        )
+        .unwrap();
+
+        assert_eq!(settings.len(), 3);
+        assert_eq!(settings["V9_HEARTBEAT_MS"], "2000");
+        // Only the first `=` separates the name from the value
+        assert_eq!(settings["V9_LOG"], "info,v9_worker=debug");
+        assert_eq!(settings["V9_MAX_BODY_BYTES"], "");
+        assert!(parse_settings_file("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_settings_files_are_rejected() {
+        for contents in &[
+            "V9_HEARTBEAT_MS",
+            "V9_LISTEN=tcp:0.0.0.0:80",
+            "V9_HEARTBEAT_MS=1\nunknown=1",
+        ] {
+            let e = parse_settings_file(contents).unwrap_err();
+            assert!(
+                matches!(e.kind(), WorkerErrorKind::InvalidSetting(_, _)),
+                "{:?}: {}",
+                contents,
+                e
+            );
+        }
+    }
+}
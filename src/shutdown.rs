@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::lazy_static;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+// Signals and `POST /meta/shutdown` both end up here, so there is only one way the worker shuts down
+lazy_static! {
+    static ref SHUTDOWN_CHANNEL: (watch::Sender<bool>, watch::Receiver<bool>) = watch::channel(false);
+}
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Returns whether a shutdown was already in progress
+pub fn request_shutdown(reason: &str) -> bool {
+    if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+        debug!("Shutdown requested ({}), but already shutting down", reason);
+        return true;
+    }
+
+    warn!("Shutdown requested ({}), draining...", reason);
+    if SHUTDOWN_CHANNEL.0.broadcast(true).is_err() {
+        // The channel holds its own receiver, so this can't happen
+        error!("Could not broadcast shutdown");
+    }
+    false
+}
+
+// Resolves once a shutdown has been requested
+pub async fn shutdown_requested() {
+    let mut receiver = SHUTDOWN_CHANNEL.1.clone();
+    while let Some(requested) = receiver.recv().await {
+        if requested {
+            return;
+        }
+    }
+}
+
+// SIGTERM is what docker and systemd send to stop us, and SIGINT is control-c
+pub async fn listen_for_signals() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Should be able to listen for SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Should be able to listen for SIGINT");
+
+    tokio::select! {
+        _ = sigterm.recv() => request_shutdown("SIGTERM"),
+        _ = sigint.recv() => request_shutdown("SIGINT"),
+    };
+}
@@ -0,0 +1,267 @@
+// Traces of requests are exported (over OTLP/HTTP) to V9_OTLP_ENDPOINT, if it's set. This needs the "otlp" feature.
+// Every request gets a span, which continues the caller's trace if it sent a traceparent header. Within a component
+// call, booting the process and each pipe write and read get spans of their own. The request's traceparent header
+// is then pointed at the worker's span, so components (see `ComponentRequest::traceparent`) continue from it.
+// Spans are exported in batches by a thread of their own, so a slow or unreachable collector never holds up a call.
+
+use std::env;
+
+#[cfg(feature = "otlp")]
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, Method, StatusCode};
+#[cfg(feature = "otlp")]
+use lazy_static::lazy_static;
+#[cfg(feature = "otlp")]
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+#[cfg(feature = "otlp")]
+use opentelemetry::trace::{Span, SpanKind, Status, TraceContextExt, Tracer, TracerProvider};
+#[cfg(feature = "otlp")]
+use opentelemetry::{Context, ContextGuard, KeyValue};
+#[cfg(feature = "otlp")]
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider, Span as SdkSpan};
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::Resource;
+
+use crate::error::WorkerError;
+#[cfg(feature = "otlp")]
+use crate::request_handler::request_id;
+
+#[cfg(feature = "otlp")]
+const SERVICE_NAME: &str = "v9_worker";
+
+#[cfg(feature = "otlp")]
+struct Telemetry {
+    provider: SdkTracerProvider,
+    tracer: SdkTracer,
+}
+
+#[cfg(feature = "otlp")]
+lazy_static! {
+    // Only started if V9_OTLP_ENDPOINT is set, otherwise no spans are made
+    static ref TELEMETRY: Option<Telemetry> = env::var("V9_OTLP_ENDPOINT").ok().map(|endpoint| {
+        let url = traces_url(&endpoint)
+            .unwrap_or_else(|problem| panic!("Invalid V9_OTLP_ENDPOINT ({:?}): {}", endpoint, problem));
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(url)
+            .build()
+            .unwrap_or_else(|e| panic!("Could not create the OTLP exporter: {}", e));
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+            .build();
+
+        let tracer = provider.tracer(SERVICE_NAME);
+        Telemetry { provider, tracer }
+    });
+}
+
+// Checks V9_OTLP_ENDPOINT at startup, so a bad one panics right away instead of on the first request
+pub fn init() {
+    #[cfg(feature = "otlp")]
+    lazy_static::initialize(&TELEMETRY);
+
+    #[cfg(not(feature = "otlp"))]
+    assert!(
+        env::var_os("V9_OTLP_ENDPOINT").is_none(),
+        "V9_OTLP_ENDPOINT is set, but this worker was built without the \"otlp\" feature"
+    );
+}
+
+// Exports the spans that haven't been yet, so the last ones aren't lost when we shut down
+pub fn flush() {
+    #[cfg(feature = "otlp")]
+    if let Some(telemetry) = &*TELEMETRY {
+        if let Err(e) = telemetry.provider.shutdown() {
+            warn!("Could not export the last spans: {}", e);
+        }
+    }
+}
+
+// The span of a whole request, which ends when this is finished (or dropped, e.g. if the request was rejected)
+pub struct RequestSpan {
+    #[cfg(feature = "otlp")]
+    span: Option<SdkSpan>,
+}
+
+impl RequestSpan {
+    // Also points the traceparent header at the new span (see the top of this file)
+    #[cfg_attr(not(feature = "otlp"), allow(unused_variables))]
+    pub fn start(http_verb: &Method, path: &str, headers: &mut HeaderMap) -> Self {
+        Self {
+            #[cfg(feature = "otlp")]
+            span: TELEMETRY
+                .as_ref()
+                .map(|telemetry| start_request_span(&telemetry.tracer, http_verb, path, headers)),
+        }
+    }
+
+    #[cfg_attr(not(feature = "otlp"), allow(unused_variables, clippy::unused_self))]
+    pub fn finish(self, status: StatusCode) {
+        #[cfg(feature = "otlp")]
+        if let Some(mut span) = self.span {
+            span.set_attribute(KeyValue::new(
+                "http.response.status_code",
+                i64::from(status.as_u16()),
+            ));
+            if status.is_server_error() {
+                span.set_status(Status::error(status.to_string()));
+            }
+        }
+    }
+}
+
+// Makes the request's span the parent of the spans `in_span` starts on this thread, until this is dropped
+pub struct TraceGuard {
+    #[cfg(feature = "otlp")]
+    _guard: Option<ContextGuard>,
+}
+
+// The headers are the ones `RequestSpan::start` pointed at the request's span
+#[cfg_attr(not(feature = "otlp"), allow(unused_variables))]
+pub fn attach(headers: &HeaderMap) -> TraceGuard {
+    TraceGuard {
+        #[cfg(feature = "otlp")]
+        _guard: TELEMETRY.as_ref().map(|_| {
+            TraceContextPropagator::new()
+                .extract(&HeaderExtractor(headers))
+                .attach()
+        }),
+    }
+}
+
+// Runs `f` in a span of its own, which records the error if `f` fails
+#[cfg_attr(not(feature = "otlp"), allow(unused_variables))]
+pub fn in_span<T>(
+    name: &'static str,
+    f: impl FnOnce() -> Result<T, WorkerError>,
+) -> Result<T, WorkerError> {
+    #[cfg(feature = "otlp")]
+    if let Some(telemetry) = &*TELEMETRY {
+        let mut span = telemetry.tracer.start(name);
+        let result = f();
+        if let Err(e) = &result {
+            span.set_status(Status::error(e.to_string()));
+        }
+        return result;
+    }
+
+    f()
+}
+
+// Like OTEL_EXPORTER_OTLP_ENDPOINT, the endpoint is the collector's base URL
+#[cfg(feature = "otlp")]
+fn traces_url(endpoint: &str) -> Result<String, String> {
+    let uri: hyper::Uri = endpoint.parse().map_err(|e| format!("invalid URL ({})", e))?;
+    match uri.scheme_str() {
+        Some("http" | "https") => Ok(format!("{}/v1/traces", endpoint.trim_end_matches('/'))),
+        _ => Err("expected an http(s) URL".to_string()),
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn start_request_span(
+    tracer: &SdkTracer,
+    http_verb: &Method,
+    path: &str,
+    headers: &mut HeaderMap,
+) -> SdkSpan {
+    let propagator = TraceContextPropagator::new();
+    let parent = propagator.extract(&HeaderExtractor(headers));
+
+    let span = tracer
+        .span_builder(http_verb.to_string())
+        .with_kind(SpanKind::Server)
+        .with_attributes(vec![
+            KeyValue::new("http.request.method", http_verb.to_string()),
+            KeyValue::new("url.path", path.to_string()),
+            KeyValue::new("v9.request_id", request_id(headers).to_string()),
+        ])
+        .start_with_context(tracer, &parent);
+
+    let current = Context::new().with_remote_span_context(span.span_context().clone());
+    propagator.inject_context(&current, &mut HeaderInjector(headers));
+
+    span
+}
+
+#[cfg(feature = "otlp")]
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+#[cfg(feature = "otlp")]
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+#[cfg(feature = "otlp")]
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+#[cfg(feature = "otlp")]
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "otlp"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_traces_path_is_added_to_the_endpoint() {
+        assert_eq!(
+            traces_url("http://collector:4318").unwrap(),
+            "http://collector:4318/v1/traces"
+        );
+        assert_eq!(
+            traces_url("https://collector.example.com/").unwrap(),
+            "https://collector.example.com/v1/traces"
+        );
+
+        assert!(traces_url("collector:4318").is_err());
+        assert!(traces_url("ftp://collector").is_err());
+        assert!(traces_url("not a url").is_err());
+    }
+
+    #[test]
+    fn request_spans_continue_the_callers_trace() {
+        let tracer = SdkTracerProvider::builder().build().tracer(SERVICE_NAME);
+        let caller = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", HeaderValue::from_static(caller));
+
+        let span = start_request_span(&tracer, &Method::GET, "/sl/u/r/f", &mut headers);
+
+        let span_id = span.span_context().span_id().to_string();
+        let expected = format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", span_id);
+        assert_ne!(span_id, "00f067aa0ba902b7");
+        assert_eq!(headers["traceparent"], expected.as_str());
+    }
+
+    #[test]
+    fn requests_without_a_traceparent_start_a_trace() {
+        let tracer = SdkTracerProvider::builder().build().tracer(SERVICE_NAME);
+        let mut headers = HeaderMap::new();
+
+        let span = start_request_span(&tracer, &Method::GET, "/sl/u/r/f", &mut headers);
+
+        let context = span.span_context();
+        let expected = format!("00-{}-{}-01", context.trace_id(), context.span_id());
+        assert_eq!(headers["traceparent"], expected.as_str());
+    }
+}
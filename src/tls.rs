@@ -0,0 +1,52 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{NoClientAuth, PrivateKey, ServerConfig as RustlsConfig};
+use tokio_rustls::TlsAcceptor;
+
+// Loads a PEM certificate chain and private key (either PKCS#8 or RSA)
+// This is only called at startup, so any problem panics with an explanation of what is wrong
+pub fn load_acceptor(cert_path: &Path, key_path: &Path, http2_only: bool) -> TlsAcceptor {
+    let open = |path: &Path| {
+        File::open(path).map_or_else(
+            |e| panic!("Could not open TLS file {:?}: {}", path, e),
+            BufReader::new,
+        )
+    };
+
+    let cert_chain = certs(&mut open(cert_path))
+        .ok()
+        .filter(|chain| !chain.is_empty())
+        .unwrap_or_else(|| panic!("No PEM certificates found in V9_TLS_CERT ({:?})", cert_path));
+    let key = load_private_key(key_path, open)
+        .unwrap_or_else(|| panic!("No PEM private key found in V9_TLS_KEY ({:?})", key_path));
+
+    let mut config = RustlsConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(cert_chain, key)
+        .unwrap_or_else(|e| panic!("V9_TLS_CERT and V9_TLS_KEY don't make a valid pair: {}", e));
+
+    // Advertise HTTP/2 through ALPN, since TLS clients won't use it with prior knowledge
+    if http2_only {
+        config.set_protocols(&[b"h2".to_vec()]);
+    } else {
+        config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    }
+
+    TlsAcceptor::from(Arc::new(config))
+}
+
+fn load_private_key(key_path: &Path, open: impl Fn(&Path) -> BufReader<File>) -> Option<PrivateKey> {
+    let pkcs8_key = pkcs8_private_keys(&mut open(key_path))
+        .ok()
+        .and_then(|keys| keys.into_iter().next());
+
+    pkcs8_key.or_else(|| {
+        rsa_private_keys(&mut open(key_path))
+            .ok()
+            .and_then(|keys| keys.into_iter().next())
+    })
+}